@@ -8,6 +8,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         "proto/post.proto",
         "proto/media.proto",
         "proto/chat.proto",
+        "proto/page.proto",
     ];
 
     let out_dir = PathBuf::from(env::var("OUT_DIR")?);
@@ -0,0 +1,50 @@
+#!/bin/bash
+# Generates a throwaway CA plus server and client certificates for trying TLS and mTLS
+# locally. Nothing here is fit for production.
+#
+#   ./scripts/dev-certs.sh [out-dir]
+#
+# Gateway:   TLS__ENABLED=true TLS__CERT_PATH=certs/gateway.crt TLS__KEY_PATH=certs/gateway.key
+#            TLS__CLIENT_CA_PATH=certs/ca.crt TLS__REQUIRE_CLIENT_CERT=true
+# Upstreams: SERVICE_DISCOVERY__TLS__<SERVICE>__CA_PATH=certs/ca.crt
+#            SERVICE_DISCOVERY__TLS__<SERVICE>__CERT_PATH=certs/gateway-client.crt
+#            SERVICE_DISCOVERY__TLS__<SERVICE>__KEY_PATH=certs/gateway-client.key
+# Client:    grpcurl -cacert certs/ca.crt -cert certs/client.crt -key certs/client.key \
+#              localhost:8080 list
+set -euo pipefail
+
+OUT=${1:-certs}
+DAYS=30
+mkdir -p "$OUT"
+
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes -days "$DAYS" \
+  -subj "/CN=selfie-dev-ca" -keyout "$OUT/ca.key" -out "$OUT/ca.crt"
+
+# issue <name> <subject CN> <extendedKeyUsage> [subjectAltName]
+issue() {
+  local name=$1 cn=$2 usage=$3 san=${4:-}
+  local ext
+  ext=$(mktemp)
+  echo "extendedKeyUsage=$usage" > "$ext"
+  if [ -n "$san" ]; then
+    echo "subjectAltName=$san" >> "$ext"
+  fi
+
+  openssl req -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes \
+    -subj "/CN=$cn" -keyout "$OUT/$name.key" -out "$OUT/$name.csr"
+  openssl x509 -req -in "$OUT/$name.csr" -CA "$OUT/ca.crt" -CAkey "$OUT/ca.key" \
+    -CAcreateserial -days "$DAYS" -extfile "$ext" -out "$OUT/$name.crt"
+  rm -f "$OUT/$name.csr" "$ext"
+}
+
+# The gateway's listeners
+issue gateway localhost serverAuth "DNS:localhost,IP:127.0.0.1"
+# A caller of the gateway
+issue client selfie-dev-client clientAuth
+# The gateway's identity towards upstream services
+issue gateway-client selfie-gateway clientAuth
+# One certificate for every upstream service when they run locally
+issue upstream localhost serverAuth \
+  "DNS:localhost,DNS:auth-service,DNS:user-service,DNS:post-service,DNS:media-service,DNS:chat-service"
+
+echo "Certificates written to $OUT/"
@@ -0,0 +1,316 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use metrics::counter;
+use prost::Message;
+use tokio::sync::OnceCell;
+use tonic::{Request, Response, Status};
+
+use crate::config::CacheConfig;
+use crate::error::GatewayError;
+
+type Fetch = Arc<OnceCell<Result<Bytes, Status>>>;
+
+struct Entry {
+    value: Bytes,
+    expires: Instant,
+    // Last stored or served, for evicting the least recently used when full
+    used: Instant,
+    tags: Vec<String>,
+}
+
+#[derive(Default)]
+struct Entries {
+    entries: HashMap<Vec<u8>, Entry>,
+    by_tag: HashMap<String, HashSet<Vec<u8>>>,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            for tag in entry.tags {
+                if let Some(keys) = self.by_tag.get_mut(&tag) {
+                    keys.remove(key);
+                    if keys.is_empty() {
+                        self.by_tag.remove(&tag);
+                    }
+                }
+            }
+        }
+    }
+
+    fn purge_expired(&mut self, now: Instant) {
+        let expired: Vec<Vec<u8>> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expires <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+    }
+
+    // Drops expired entries, then the least recently used ones if that wasn't enough, so a
+    // full cache costs one scan per tenth of `max_entries` new entries
+    fn make_room(&mut self, max_entries: usize, now: Instant) {
+        self.purge_expired(now);
+
+        let keep = max_entries * 9 / 10;
+        if self.entries.len() > keep {
+            let excess = self.entries.len() - keep;
+            let mut by_use: Vec<(Instant, Vec<u8>)> =
+                self.entries.iter().map(|(key, entry)| (entry.used, key.clone())).collect();
+            by_use.select_nth_unstable_by_key(excess - 1, |(used, _)| *used);
+            for (_, key) in by_use.drain(..excess) {
+                self.remove(&key);
+            }
+            counter!("gateway_cache_evictions_total", excess as u64);
+        }
+    }
+}
+
+/// Short-lived cache of read RPC responses, with concurrent identical requests coalesced into
+/// a single upstream call.
+///
+/// Entries are keyed by RPC and encoded request, so RPCs whose response depends on the viewer
+/// must carry the caller in the request (`viewer_id`, bound by `caller_bound!`). Each entry is
+/// tagged with the objects it describes, e.g. `post:<id>`, and write RPCs invalidate by tag.
+pub struct ResponseCache {
+    settings: RwLock<Settings>,
+    entries: Mutex<Entries>,
+    // In-flight fetches with the generation they started in
+    inflight: Mutex<HashMap<Vec<u8>, (u64, Fetch)>>,
+    // Bumped on every invalidation so a fetch that raced with one doesn't store a stale value
+    generation: AtomicU64,
+}
+
+struct Settings {
+    enabled: bool,
+    max_entries: usize,
+    // Keyed by lowercased RPC name; RPCs without a TTL aren't cached
+    ttls: HashMap<String, Duration>,
+}
+
+impl Settings {
+    fn new(config: &CacheConfig) -> Self {
+        Self {
+            enabled: config.enabled,
+            max_entries: config.max_entries,
+            ttls: config
+                .ttl_ms
+                .iter()
+                .map(|(rpc, ttl)| (rpc.to_ascii_lowercase(), Duration::from_millis(*ttl)))
+                .collect(),
+        }
+    }
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            settings: RwLock::new(Settings::new(config)),
+            entries: Mutex::new(Entries::default()),
+            inflight: Mutex::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Applies new TTLs and limits to responses cached from now on. Disabling the cache drops
+    /// what it holds, since invalidations are skipped while it is off.
+    pub fn reload(&self, config: &CacheConfig) {
+        let settings = Settings::new(config);
+        let enabled = settings.enabled;
+        *self.settings.write().expect("cache settings poisoned") = settings;
+
+        if !enabled {
+            self.generation.fetch_add(1, Ordering::AcqRel);
+            self.inflight.lock().expect("cache inflight poisoned").clear();
+            *self.entries.lock().expect("cache entries poisoned") = Entries::default();
+        }
+    }
+
+    /// Serves `rpc` from the cache, joins an identical call already in flight, or runs `fetch`
+    /// and caches its response under `tags`.
+    pub async fn get_or_fetch<Req, Resp, F, Fut>(
+        &self,
+        rpc: &'static str,
+        tags: Vec<String>,
+        request: Request<Req>,
+        fetch: F,
+    ) -> Result<Response<Resp>, Status>
+    where
+        Req: Message,
+        Resp: Message + Default,
+        F: FnOnce(Request<Req>) -> Fut,
+        Fut: Future<Output = Result<Response<Resp>, Status>>,
+    {
+        let ttl = {
+            let settings = self.settings.read().expect("cache settings poisoned");
+            settings.ttls.get(&rpc.to_ascii_lowercase()).copied().filter(|_| settings.enabled)
+        };
+        let Some(ttl) = ttl else {
+            return fetch(request).await;
+        };
+
+        let mut key = rpc.as_bytes().to_vec();
+        key.push(0);
+        key.extend(request.get_ref().encode_to_vec());
+
+        if let Some(value) = self.lookup(&key) {
+            counter!("gateway_cache_hits_total", 1, "rpc" => rpc);
+            return Ok(decode(value)?);
+        }
+        counter!("gateway_cache_misses_total", 1, "rpc" => rpc);
+
+        let (generation, fetch_cell) = self
+            .inflight
+            .lock()
+            .expect("cache inflight poisoned")
+            .entry(key.clone())
+            .or_insert_with(|| (self.generation.load(Ordering::Acquire), Fetch::default()))
+            .clone();
+
+        // The first caller runs the upstream call; the others wait for its result. If that
+        // caller goes away, a waiting one takes over.
+        let result = fetch_cell
+            .get_or_init(|| async move {
+                fetch(request)
+                    .await
+                    .map(|response| Bytes::from(response.into_inner().encode_to_vec()))
+            })
+            .await
+            .clone();
+
+        {
+            let mut inflight = self.inflight.lock().expect("cache inflight poisoned");
+            if inflight.get(&key).is_some_and(|(_, current)| Arc::ptr_eq(current, &fetch_cell)) {
+                inflight.remove(&key);
+            }
+        }
+
+        let value = result?;
+        if self.generation.load(Ordering::Acquire) == generation {
+            self.store(key, value.clone(), ttl, tags);
+        } else {
+            counter!("gateway_cache_stores_skipped_total", 1, "rpc" => rpc);
+        }
+        Ok(decode(value)?)
+    }
+
+    /// Drops every entry tagged with one of `tags`.
+    pub fn invalidate(&self, tags: &[String]) {
+        if !self.settings.read().expect("cache settings poisoned").enabled {
+            return;
+        }
+
+        // Calls from now on must not join a fetch that may have read the old state
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        self.inflight.lock().expect("cache inflight poisoned").clear();
+
+        let mut entries = self.entries.lock().expect("cache entries poisoned");
+        for tag in tags {
+            if let Some(keys) = entries.by_tag.remove(tag) {
+                for key in keys {
+                    entries.remove(&key);
+                }
+            }
+        }
+    }
+
+    fn lookup(&self, key: &[u8]) -> Option<Bytes> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("cache entries poisoned");
+        match entries.entries.get_mut(key) {
+            Some(entry) if entry.expires > now => {
+                entry.used = now;
+                Some(entry.value.clone())
+            }
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn store(&self, key: Vec<u8>, value: Bytes, ttl: Duration, tags: Vec<String>) {
+        let now = Instant::now();
+        let max_entries = self.settings.read().expect("cache settings poisoned").max_entries;
+        let mut entries = self.entries.lock().expect("cache entries poisoned");
+
+        entries.remove(&key);
+        if entries.entries.len() >= max_entries {
+            entries.make_room(max_entries, now);
+        }
+
+        for tag in &tags {
+            entries.by_tag.entry(tag.clone()).or_default().insert(key.clone());
+        }
+        entries.entries.insert(key, Entry { value, expires: now + ttl, used: now, tags });
+    }
+}
+
+fn decode<Resp: Message + Default>(value: Bytes) -> Result<Response<Resp>, GatewayError> {
+    Resp::decode(value)
+        .map(Response::new)
+        .map_err(|err| GatewayError::Internal(format!("invalid cached response: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(max_entries: usize) -> ResponseCache {
+        ResponseCache::new(&CacheConfig {
+            enabled: true,
+            max_entries,
+            ttl_ms: HashMap::new(),
+        })
+    }
+
+    fn store(cache: &ResponseCache, key: &str, ttl: Duration) {
+        let tags = vec![format!("post:{}", key)];
+        cache.store(key.as_bytes().to_vec(), Bytes::from(key.to_string()), ttl, tags);
+    }
+
+    fn cached(cache: &ResponseCache, key: &str) -> bool {
+        cache.lookup(key.as_bytes()).is_some()
+    }
+
+    #[test]
+    fn full_cache_evicts_least_recently_used_to_store_new_keys() {
+        let cache = cache(3);
+        let ttl = Duration::from_secs(60);
+        for key in ["a", "b", "c"] {
+            store(&cache, key, ttl);
+        }
+        assert!(cached(&cache, "a"));
+
+        store(&cache, "d", ttl);
+
+        for key in ["a", "c", "d"] {
+            assert!(cached(&cache, key), "{} was evicted", key);
+        }
+        assert!(!cached(&cache, "b"));
+        assert!(!cache.entries.lock().unwrap().by_tag.contains_key("post:b"));
+    }
+
+    #[test]
+    fn full_cache_drops_expired_entries_first() {
+        let cache = cache(3);
+        store(&cache, "a", Duration::ZERO);
+        store(&cache, "b", Duration::from_secs(60));
+        store(&cache, "c", Duration::from_secs(60));
+
+        store(&cache, "d", Duration::from_secs(60));
+
+        for key in ["b", "c", "d"] {
+            assert!(cached(&cache, key), "{} was evicted", key);
+        }
+        assert!(!cached(&cache, "a"));
+    }
+}
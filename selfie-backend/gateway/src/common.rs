@@ -0,0 +1,5 @@
+// Shared selfie.common.v1 messages. Code generated for the other packages refers to them
+// by relative path, which resolves to `crate::common::v1`.
+pub mod v1 {
+    tonic::include_proto!("selfie.common.v1");
+}
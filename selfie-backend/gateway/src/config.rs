@@ -1,5 +1,15 @@
-use serde::Deserialize;
-use anyhow::Result;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use serde::{Deserialize, Deserializer};
+use anyhow::{bail, Result};
+use tonic::codegen::http::{HeaderValue, Uri};
+
+use crate::proxy::Upstream;
+
+/// Environment variable naming an optional TOML or YAML config file. Environment variables
+/// override what the file sets.
+pub const CONFIG_PATH_ENV: &str = "GATEWAY_CONFIG";
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
@@ -7,22 +17,781 @@ pub struct Config {
     pub port: u16,
     pub metrics_addr: String,
     pub service_discovery: ServiceDiscoveryConfig,
+    pub auth: AuthConfig,
+    /// Browser origins allowed to call the gateway over gRPC-Web and REST
+    #[serde(default = "default_cors_allowed_origins")]
+    pub cors_allowed_origins: Vec<String>,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub upstream: UpstreamConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub media: MediaConfig,
+    #[serde(default)]
+    pub websocket: WebSocketConfig,
+    #[serde(default)]
+    pub graphql: GraphQLConfig,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
 }
 
+/// Upstream endpoints; each service may list several URLs to balance across.
 #[derive(Debug, Deserialize)]
 pub struct ServiceDiscoveryConfig {
-    pub auth_service: String,
-    pub user_service: String,
-    pub post_service: String,
-    pub media_service: String,
-    pub chat_service: String,
-    // Add other services as needed
+    pub auth_service: Vec<String>,
+    pub user_service: Vec<String>,
+    pub post_service: Vec<String>,
+    pub media_service: Vec<String>,
+    pub chat_service: Vec<String>,
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    #[serde(default = "default_keepalive_interval_secs")]
+    pub keepalive_interval_secs: u64,
+    /// mTLS to upstreams keyed by service (`auth`, `user`, `post`, `media`, `chat`); applies
+    /// to that service's https:// endpoints
+    #[serde(default)]
+    pub tls: HashMap<String, UpstreamTlsConfig>,
+}
+
+/// How the gateway authenticates an upstream service and identifies itself to it.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct UpstreamTlsConfig {
+    /// CA the service's certificate must chain to; no other roots are trusted
+    pub ca_path: String,
+    /// Client certificate and key the gateway presents
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    /// Name expected in the service's certificate, when it isn't the endpoint's host
+    pub domain_name: Option<String>,
+}
+
+impl ServiceDiscoveryConfig {
+    pub fn endpoints(&self, upstream: Upstream) -> &[String] {
+        match upstream {
+            Upstream::Auth => &self.auth_service,
+            Upstream::User => &self.user_service,
+            Upstream::Post => &self.post_service,
+            Upstream::Media => &self.media_service,
+            Upstream::Chat => &self.chat_service,
+        }
+    }
+
+    pub fn tls(&self, upstream: Upstream) -> Option<&UpstreamTlsConfig> {
+        self.tls
+            .iter()
+            .find(|(service, _)| Upstream::parse(service) == Some(upstream))
+            .map(|(_, tls)| tls)
+    }
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_keepalive_interval_secs() -> u64 {
+    30
+}
+
+/// Timeouts, retries and circuit breaking for calls to upstream services.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct UpstreamConfig {
+    /// Longest the gateway waits on one attempt, and the deadline for calls whose client
+    /// didn't send `grpc-timeout`
+    pub default_timeout_ms: u64,
+    /// Taken off the client's deadline before it is passed on, for the gateway's own work
+    pub deadline_margin_ms: u64,
+    /// Total attempts for idempotent RPCs; everything else is sent once
+    pub max_attempts: u32,
+    pub retry_backoff_ms: u64,
+    pub retry_max_backoff_ms: u64,
+    /// Consecutive failures before a service's circuit opens
+    pub breaker_failure_threshold: u32,
+    pub breaker_open_secs: u64,
+    /// Per-RPC replacement for `default_timeout_ms`, keyed by method name (case-insensitive)
+    pub rpc_timeout_ms: HashMap<String, u64>,
+    pub concurrency: ConcurrencyConfig,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            default_timeout_ms: 10_000,
+            deadline_margin_ms: 20,
+            max_attempts: 3,
+            retry_backoff_ms: 50,
+            retry_max_backoff_ms: 1000,
+            breaker_failure_threshold: 5,
+            breaker_open_secs: 30,
+            rpc_timeout_ms: HashMap::new(),
+            concurrency: ConcurrencyConfig::default(),
+        }
+    }
+}
+
+/// Adaptive limit on calls in flight to each upstream service. The limit follows the
+/// service's latency: it grows while calls are as fast as usual and shrinks as they slow
+/// down or time out. Calls over the limit are rejected with UNAVAILABLE without being sent.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ConcurrencyConfig {
+    pub enabled: bool,
+    pub initial_limit: u32,
+    pub min_limit: u32,
+    pub max_limit: u32,
+    /// How much slower than its usual latency a service may get before its limit shrinks
+    pub rtt_tolerance: f64,
+    /// RPCs shed only once everything else is, by method name (case-insensitive)
+    pub critical_rpcs: Vec<String>,
+    /// RPCs shed first, by method name (case-insensitive)
+    pub sheddable_rpcs: Vec<String>,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            initial_limit: 50,
+            min_limit: 10,
+            max_limit: 1000,
+            rtt_tolerance: 1.5,
+            critical_rpcs: vec!["Login".to_string(), "SendMessage".to_string()],
+            sheddable_rpcs: vec!["SearchUsers".to_string(), "GetLikes".to_string()],
+        }
+    }
+}
+
+/// Background health probing of upstream services.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    pub probe_interval_secs: u64,
+    pub probe_timeout_ms: u64,
+    /// Upstreams (`auth`, `user`, `post`, `media`, `chat`) without which the gateway reports
+    /// itself NOT_SERVING
+    pub critical_services: Vec<String>,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            probe_interval_secs: 10,
+            probe_timeout_ms: 1000,
+            critical_services: vec!["auth".to_string()],
+        }
+    }
+}
+
+/// Response cache for hot read RPCs.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub max_entries: usize,
+    /// Per-RPC time to live keyed by method name (case-insensitive); other RPCs aren't cached
+    pub ttl_ms: HashMap<String, u64>,
 }
 
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_entries: 50_000,
+            ttl_ms: HashMap::from([
+                ("getuser".to_string(), 30_000),
+                ("getprofile".to_string(), 10_000),
+                ("getpost".to_string(), 10_000),
+                ("getmedia".to_string(), 60_000),
+            ]),
+        }
+    }
+}
+
+/// Limits on media uploads passing through the gateway.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct MediaConfig {
+    /// Largest upload accepted per media type (`image`, `video`, `audio`), in bytes
+    pub max_upload_bytes: HashMap<String, u64>,
+    /// How long a streamed upload may take when the client sets no deadline
+    pub upload_timeout_secs: u64,
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        Self {
+            max_upload_bytes: HashMap::from([
+                ("image".to_string(), 20 * 1024 * 1024),
+                ("video".to_string(), 500 * 1024 * 1024),
+                ("audio".to_string(), 100 * 1024 * 1024),
+            ]),
+            upload_timeout_secs: 600,
+        }
+    }
+}
+
+/// WebSocket bridge to chat streaming for browser and mobile clients.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    pub enabled: bool,
+    pub addr: String,
+    /// How often the gateway pings each connection
+    pub heartbeat_interval_secs: u64,
+    /// Connections that send nothing, pongs included, for this long are closed
+    pub idle_timeout_secs: u64,
+    pub max_frame_bytes: usize,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            addr: "0.0.0.0:8081".to_string(),
+            heartbeat_interval_secs: 20,
+            idle_timeout_secs: 60,
+            max_frame_bytes: 64 * 1024,
+        }
+    }
+}
+
+/// GraphQL endpoint over the gateway's services.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct GraphQLConfig {
+    pub enabled: bool,
+    /// Deepest selection accepted, e.g. post → author → profile is 3
+    pub max_depth: usize,
+    pub max_complexity: usize,
+}
+
+impl Default for GraphQLConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_depth: 10,
+            max_complexity: 500,
+        }
+    }
+}
+
+/// TLS termination on the gRPC and WebSocket listeners. Certificate files are watched and
+/// reloaded when they change.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    /// PEM certificate chain and private key
+    pub cert_path: String,
+    pub key_path: String,
+    /// CA for client certificates; clients aren't asked for one without it
+    pub client_ca_path: Option<String>,
+    /// Refuse clients that present no certificate
+    pub require_client_cert: bool,
+}
+
+/// What happens between SIGTERM and exit.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// How long health reports NOT_SERVING before the listeners close, so load balancers
+    /// stop sending new connections first
+    pub drain_delay_secs: u64,
+    /// How long calls in flight get to finish once the listeners are closed
+    pub grace_period_secs: u64,
+    /// How long the metrics endpoint stays up after draining so the last counts are scraped
+    pub metrics_flush_secs: u64,
+    /// Time the orchestrator allows between SIGTERM and SIGKILL, e.g. Kubernetes'
+    /// `terminationGracePeriodSeconds`; the steps above have to fit in it
+    pub termination_grace_period_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_delay_secs: 5,
+            grace_period_secs: 20,
+            metrics_flush_secs: 15,
+            termination_grace_period_secs: 45,
+        }
+    }
+}
+
+/// Logging and OpenTelemetry tracing.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// OTLP/gRPC collector, e.g. `http://otel-collector:4317`; spans aren't exported when unset
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+    /// Fraction of new traces sampled; traces started by the caller follow its decision
+    pub sample_ratio: f64,
+    pub json_logs: bool,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: "selfie-gateway".to_string(),
+            sample_ratio: 1.0,
+            json_logs: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthConfig {
+    /// PEM file or directory of `<kid>.pem` files with auth-service's Ed25519 public keys
+    pub public_key_path: Option<String>,
+    /// auth-service's `/.well-known/jwks.json`, which lists every key it signs or will sign
+    /// with, plus retired keys for as long as their tokens stay valid
+    pub jwks_url: Option<String>,
+    /// How often the JWKS is fetched again; well under auth-service's publish delay, so new
+    /// keys are known before they sign anything
+    #[serde(default = "default_jwks_refresh_secs")]
+    pub jwks_refresh_secs: u64,
+    #[serde(default = "default_leeway_secs")]
+    pub leeway_secs: u64,
+    /// Full gRPC method paths reachable without an access token
+    #[serde(default = "default_public_methods")]
+    pub public_methods: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitStoreKind {
+    /// Buckets live in the gateway process; limits apply per instance
+    #[default]
+    Memory,
+    /// Buckets are shared by every gateway instance through Redis
+    Redis,
+}
+
+/// Token bucket: refills at `per_second` up to `burst` requests.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub store: RateLimitStoreKind,
+    pub redis_url: Option<String>,
+    /// Limit shared by every RPC without an entry in `methods`
+    #[serde(default = "default_rate_limit")]
+    pub default: RateLimit,
+    /// Per-RPC limits keyed by method name (case-insensitive), e.g. `login`. Entries replace
+    /// the built-in limit for their method and leave the others in place.
+    #[serde(default = "default_method_rate_limits", deserialize_with = "merge_method_rate_limits")]
+    pub methods: HashMap<String, RateLimit>,
+    /// Proxies in front of the gateway that append to X-Forwarded-For; the client address is
+    /// the entry this many from the right. 0 ignores the header and uses the peer address.
+    #[serde(default)]
+    pub trusted_proxy_hops: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            store: RateLimitStoreKind::default(),
+            redis_url: None,
+            default: default_rate_limit(),
+            methods: default_method_rate_limits(),
+            trusted_proxy_hops: 0,
+        }
+    }
+}
+
+fn default_rate_limit() -> RateLimit {
+    RateLimit {
+        per_second: 20.0,
+        burst: 40,
+    }
+}
+
+// Credential and account endpoints are the usual brute-force and abuse targets
+fn default_method_rate_limits() -> HashMap<String, RateLimit> {
+    HashMap::from([
+        ("login".to_string(), RateLimit { per_second: 0.2, burst: 5 }),
+        ("register".to_string(), RateLimit { per_second: 0.05, burst: 3 }),
+        ("resetpassword".to_string(), RateLimit { per_second: 0.02, burst: 3 }),
+        ("sendmessage".to_string(), RateLimit { per_second: 5.0, burst: 10 }),
+    ])
+}
+
+fn merge_method_rate_limits<'de, D>(deserializer: D) -> std::result::Result<HashMap<String, RateLimit>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut methods = default_method_rate_limits();
+    for (method, limit) in HashMap::<String, RateLimit>::deserialize(deserializer)? {
+        methods.insert(method.to_ascii_lowercase(), limit);
+    }
+    Ok(methods)
+}
+
+fn default_cors_allowed_origins() -> Vec<String> {
+    vec!["https://selfie.app".to_string()]
+}
+
+fn default_leeway_secs() -> u64 {
+    30
+}
+
+fn default_jwks_refresh_secs() -> u64 {
+    60
+}
+
+fn default_public_methods() -> Vec<String> {
+    [
+        "/selfie.auth.v1.AuthService/Register",
+        "/selfie.auth.v1.AuthService/Login",
+        "/selfie.auth.v1.AuthService/Refresh",
+        "/selfie.auth.v1.AuthService/Verify2FA",
+        "/selfie.auth.v1.AuthService/ValidateToken",
+        "/selfie.auth.v1.AuthService/ResetPassword",
+        "/selfie.auth.v1.AuthService/VerifyEmail",
+        "/grpc.health.v1.Health/Check",
+        "/grpc.health.v1.Health/Watch",
+        "/grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo",
+    ]
+    .iter()
+    .map(|method| method.to_string())
+    .collect()
+}
+
+/// Path of the config file named by `GATEWAY_CONFIG`, if any.
+pub fn config_path() -> Option<PathBuf> {
+    std::env::var_os(CONFIG_PATH_ENV).map(PathBuf::from)
+}
+
+/// Loads the config file, when one is set, overlaid with environment variables, and rejects
+/// configurations that fail `Config::validate`.
 pub fn load_config() -> Result<Config> {
-    let config = config::Config::builder()
-        .add_source(config::Environment::default())
+    let mut builder = config::Config::builder();
+    // The format follows the extension: .toml, .yaml/.yml or .json
+    if let Some(path) = config_path() {
+        builder = builder.add_source(config::File::from(path));
+    }
+
+    let config = builder
+        .add_source(
+            config::Environment::default()
+                .separator("__")
+                .try_parsing(true)
+                .list_separator(",")
+                .with_list_parse_key("service_discovery.auth_service")
+                .with_list_parse_key("service_discovery.user_service")
+                .with_list_parse_key("service_discovery.post_service")
+                .with_list_parse_key("service_discovery.media_service")
+                .with_list_parse_key("service_discovery.chat_service")
+                .with_list_parse_key("cors_allowed_origins")
+                .with_list_parse_key("health.critical_services")
+                .with_list_parse_key("auth.public_methods")
+                .with_list_parse_key("upstream.concurrency.critical_rpcs")
+                .with_list_parse_key("upstream.concurrency.sheddable_rpcs"),
+        )
         .build()?;
 
-    Ok(config.try_deserialize()?)
-}
\ No newline at end of file
+    let config: Config = config.try_deserialize()?;
+    config.validate()?;
+    Ok(config)
+}
+
+impl Config {
+    /// Key and certificate files the running configuration reads, so changes to them can be
+    /// picked up like changes to the config file.
+    pub fn watched_files(&self) -> Vec<PathBuf> {
+        // A key directory's own mtime moves when a `<kid>.pem` is added or removed
+        let mut files = Vec::new();
+        if let Some(key_path) = &self.auth.public_key_path {
+            let key_path = PathBuf::from(key_path);
+            let mut keys: Vec<PathBuf> = std::fs::read_dir(&key_path)
+                .map(|entries| {
+                    entries
+                        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("pem"))
+                        .collect()
+                })
+                .unwrap_or_default();
+            keys.sort();
+            files.push(key_path);
+            files.extend(keys);
+        }
+
+        if self.tls.enabled {
+            files.push(PathBuf::from(&self.tls.cert_path));
+            files.push(PathBuf::from(&self.tls.key_path));
+            files.extend(self.tls.client_ca_path.iter().map(PathBuf::from));
+        }
+        for tls in self.service_discovery.tls.values() {
+            files.push(PathBuf::from(&tls.ca_path));
+            files.extend(tls.cert_path.iter().chain(&tls.key_path).map(PathBuf::from));
+        }
+        files
+    }
+
+    /// Checks what deserialization can't, reporting every problem at once.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: String| {
+            if !ok {
+                problems.push(problem);
+            }
+        };
+
+        check(
+            format!("{}:{}", self.host, self.port).parse::<SocketAddr>().is_ok(),
+            format!("host/port: {}:{} is not a socket address", self.host, self.port),
+        );
+        check(
+            self.metrics_addr.parse::<SocketAddr>().is_ok(),
+            format!("metrics_addr: {:?} is not a socket address", self.metrics_addr),
+        );
+        for origin in &self.cors_allowed_origins {
+            check(
+                HeaderValue::from_str(origin).is_ok(),
+                format!("cors_allowed_origins: {:?} is not a valid origin", origin),
+            );
+        }
+
+        let discovery = &self.service_discovery;
+        for upstream in Upstream::ALL {
+            let service = upstream.as_str();
+            let urls = discovery.endpoints(upstream);
+            check(!urls.is_empty(), format!("service_discovery.{}_service: no endpoints configured", service));
+            for url in urls {
+                let scheme = url.parse::<Uri>().ok().and_then(|uri| uri.scheme_str().map(str::to_string));
+                check(
+                    matches!(scheme.as_deref(), Some("http" | "https")),
+                    format!("service_discovery.{}_service: {:?} is not an http(s) URL", service, url),
+                );
+            }
+        }
+        check(
+            discovery.connect_timeout_ms > 0,
+            "service_discovery.connect_timeout_ms: must be positive".to_string(),
+        );
+        for (service, tls) in &discovery.tls {
+            let Some(upstream) = Upstream::parse(service) else {
+                check(false, format!("service_discovery.tls: unknown service {:?}", service));
+                continue;
+            };
+            check(
+                !tls.ca_path.is_empty(),
+                format!("service_discovery.tls.{}.ca_path: must be set", service),
+            );
+            check(
+                tls.cert_path.is_some() == tls.key_path.is_some(),
+                format!("service_discovery.tls.{}: cert_path and key_path go together", service),
+            );
+            for url in discovery.endpoints(upstream) {
+                check(
+                    url.starts_with("https://"),
+                    format!("service_discovery.{}_service: {:?} must be https:// to use TLS", service, url),
+                );
+            }
+        }
+
+        if self.tls.enabled {
+            check(
+                !self.tls.cert_path.is_empty() && !self.tls.key_path.is_empty(),
+                "tls.cert_path and tls.key_path: required when TLS is enabled".to_string(),
+            );
+        }
+        check(
+            !self.tls.require_client_cert || self.tls.client_ca_path.is_some(),
+            "tls.require_client_cert: needs tls.client_ca_path".to_string(),
+        );
+
+        let auth = &self.auth;
+        check(
+            auth.public_key_path.as_deref().is_some_and(|path| !path.is_empty()) || auth.jwks_url.is_some(),
+            "auth: public_key_path or jwks_url must be set".to_string(),
+        );
+        if let Some(url) = &auth.jwks_url {
+            let scheme = url.parse::<Uri>().ok().and_then(|uri| uri.scheme_str().map(str::to_string));
+            check(
+                matches!(scheme.as_deref(), Some("http" | "https")),
+                format!("auth.jwks_url: {:?} is not an http(s) URL", url),
+            );
+        }
+        check(auth.jwks_refresh_secs > 0, "auth.jwks_refresh_secs: must be positive".to_string());
+        for method in &self.auth.public_methods {
+            // `/package.Service/Method`
+            let well_formed = method
+                .strip_prefix('/')
+                .and_then(|path| path.split_once('/'))
+                .is_some_and(|(service, rpc)| !service.is_empty() && !rpc.is_empty() && !rpc.contains('/'));
+            check(
+                well_formed,
+                format!("auth.public_methods: {:?} is not a /package.Service/Method path", method),
+            );
+        }
+
+        let rate_limit = &self.rate_limit;
+        check(
+            !matches!(rate_limit.store, RateLimitStoreKind::Redis) || rate_limit.redis_url.is_some(),
+            "rate_limit.redis_url: required for the redis store".to_string(),
+        );
+        let limits = std::iter::once(("default".to_string(), &rate_limit.default))
+            .chain(rate_limit.methods.iter().map(|(rpc, limit)| (format!("methods.{}", rpc), limit)));
+        for (name, limit) in limits {
+            check(
+                limit.per_second.is_finite() && limit.per_second > 0.0 && limit.burst > 0,
+                format!("rate_limit.{}: per_second and burst must be positive", name),
+            );
+        }
+
+        let upstream = &self.upstream;
+        check(upstream.default_timeout_ms > 0, "upstream.default_timeout_ms: must be positive".to_string());
+        check(upstream.max_attempts > 0, "upstream.max_attempts: must be at least 1".to_string());
+        check(
+            upstream.retry_backoff_ms <= upstream.retry_max_backoff_ms,
+            "upstream.retry_backoff_ms: must not exceed retry_max_backoff_ms".to_string(),
+        );
+        check(
+            upstream.breaker_failure_threshold > 0,
+            "upstream.breaker_failure_threshold: must be at least 1".to_string(),
+        );
+        for (rpc, timeout) in &upstream.rpc_timeout_ms {
+            check(*timeout > 0, format!("upstream.rpc_timeout_ms.{}: must be positive", rpc));
+        }
+
+        let concurrency = &upstream.concurrency;
+        check(
+            concurrency.min_limit > 0
+                && concurrency.min_limit <= concurrency.initial_limit
+                && concurrency.initial_limit <= concurrency.max_limit,
+            "upstream.concurrency: expected 0 < min_limit <= initial_limit <= max_limit".to_string(),
+        );
+        check(
+            concurrency.rtt_tolerance >= 1.0,
+            format!("upstream.concurrency.rtt_tolerance: {} is below 1.0", concurrency.rtt_tolerance),
+        );
+        for rpc in &concurrency.critical_rpcs {
+            check(
+                !concurrency.sheddable_rpcs.iter().any(|other| other.eq_ignore_ascii_case(rpc)),
+                format!("upstream.concurrency: {} is both critical and sheddable", rpc),
+            );
+        }
+
+        check(
+            self.health.probe_interval_secs > 0,
+            "health.probe_interval_secs: must be positive".to_string(),
+        );
+        check(
+            self.health.probe_timeout_ms > 0,
+            "health.probe_timeout_ms: must be positive".to_string(),
+        );
+        for service in &self.health.critical_services {
+            check(
+                Upstream::parse(service).is_some(),
+                format!("health.critical_services: unknown service {:?}", service),
+            );
+        }
+
+        check(
+            (0.0..=1.0).contains(&self.telemetry.sample_ratio),
+            "telemetry.sample_ratio: must be between 0 and 1".to_string(),
+        );
+
+        check(
+            !self.cache.enabled || self.cache.max_entries > 0,
+            "cache.max_entries: must be positive when the cache is enabled".to_string(),
+        );
+
+        for kind in self.media.max_upload_bytes.keys() {
+            check(
+                ["image", "video", "audio"].contains(&kind.to_ascii_lowercase().as_str()),
+                format!("media.max_upload_bytes: unknown media type {:?}", kind),
+            );
+        }
+
+        if self.websocket.enabled {
+            check(
+                self.websocket.addr.parse::<SocketAddr>().is_ok(),
+                format!("websocket.addr: {:?} is not a socket address", self.websocket.addr),
+            );
+            check(
+                self.websocket.heartbeat_interval_secs > 0
+                    && self.websocket.heartbeat_interval_secs < self.websocket.idle_timeout_secs,
+                "websocket.heartbeat_interval_secs: must be positive and below idle_timeout_secs".to_string(),
+            );
+        }
+
+        let shutdown = &self.shutdown;
+        check(
+            shutdown.grace_period_secs > 0,
+            "shutdown.grace_period_secs: must be positive".to_string(),
+        );
+        let shutdown_secs = shutdown.drain_delay_secs + shutdown.grace_period_secs + shutdown.metrics_flush_secs;
+        check(
+            shutdown_secs <= shutdown.termination_grace_period_secs,
+            format!(
+                "shutdown: drain_delay_secs + grace_period_secs + metrics_flush_secs ({}s) exceeds termination_grace_period_secs ({}s)",
+                shutdown_secs, shutdown.termination_grace_period_secs
+            ),
+        );
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        bail!("invalid gateway configuration:\n  - {}", problems.join("\n  - "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate_limit_config(toml: &str) -> RateLimitConfig {
+        config::Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    #[test]
+    fn method_rate_limits_override_only_their_own_defaults() {
+        let config = rate_limit_config(
+            r#"
+            [methods.Login]
+            per_second = 1.0
+            burst = 10
+
+            [methods.GetFeed]
+            per_second = 2.0
+            burst = 4
+            "#,
+        );
+
+        let defaults = default_method_rate_limits();
+        assert_eq!(config.methods.len(), defaults.len() + 1);
+        assert_eq!(config.methods["login"], RateLimit { per_second: 1.0, burst: 10 });
+        assert_eq!(config.methods["getfeed"], RateLimit { per_second: 2.0, burst: 4 });
+        for method in ["register", "resetpassword", "sendmessage"] {
+            assert_eq!(config.methods[method], defaults[method]);
+        }
+    }
+
+    #[test]
+    fn method_rate_limits_default_when_unset() {
+        let config = rate_limit_config("trusted_proxy_hops = 1");
+
+        assert_eq!(config.methods, default_method_rate_limits());
+    }
+}
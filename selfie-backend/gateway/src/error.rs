@@ -1,5 +1,13 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use prost::Message;
 use thiserror::Error;
-use tonic::Status;
+use tonic::{Code, Status};
+
+use crate::common::v1 as common;
+
+/// Metadata key carrying the seconds a rate-limited client should wait before retrying.
+pub const RETRY_AFTER: &str = "retry-after";
 
 #[derive(Error, Debug)]
 pub enum GatewayError {
@@ -14,15 +22,98 @@ pub enum GatewayError {
     
     #[error("Authentication failed: {0}")]
     AuthenticationFailed(String),
+
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("Deadline exceeded: {0}")]
+    DeadlineExceeded(String),
+
+    #[error("Rate limit exceeded, retry after {0:?}")]
+    RateLimited(Duration),
+
+    #[error("Overloaded: {0}")]
+    Overloaded(String),
+
+    #[error("Gateway is shutting down")]
+    ShuttingDown,
+}
+
+impl GatewayError {
+    /// Machine-readable code carried in the `selfie.common.v1.Error` status details.
+    pub fn code(&self) -> &'static str {
+        match self {
+            GatewayError::Internal(_) => "INTERNAL",
+            GatewayError::ServiceUnavailable(_) => "SERVICE_UNAVAILABLE",
+            GatewayError::InvalidRequest(_) => "INVALID_REQUEST",
+            GatewayError::AuthenticationFailed(_) => "AUTHENTICATION_FAILED",
+            GatewayError::PermissionDenied(_) => "PERMISSION_DENIED",
+            GatewayError::DeadlineExceeded(_) => "DEADLINE_EXCEEDED",
+            GatewayError::RateLimited(_) => "RATE_LIMITED",
+            GatewayError::Overloaded(_) => "OVERLOADED",
+            GatewayError::ShuttingDown => "SHUTTING_DOWN",
+        }
+    }
+
+    fn grpc_code(&self) -> Code {
+        match self {
+            GatewayError::Internal(_) => Code::Internal,
+            GatewayError::ServiceUnavailable(_) => Code::Unavailable,
+            GatewayError::InvalidRequest(_) => Code::InvalidArgument,
+            GatewayError::AuthenticationFailed(_) => Code::Unauthenticated,
+            GatewayError::PermissionDenied(_) => Code::PermissionDenied,
+            GatewayError::DeadlineExceeded(_) => Code::DeadlineExceeded,
+            GatewayError::RateLimited(_) => Code::ResourceExhausted,
+            // Retryable, so clients back off or reconnect to another instance
+            GatewayError::Overloaded(_) | GatewayError::ShuttingDown => Code::Unavailable,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            GatewayError::Internal(msg)
+            | GatewayError::ServiceUnavailable(msg)
+            | GatewayError::InvalidRequest(msg)
+            | GatewayError::AuthenticationFailed(msg)
+            | GatewayError::PermissionDenied(msg)
+            | GatewayError::DeadlineExceeded(msg)
+            | GatewayError::Overloaded(msg) => msg.clone(),
+            GatewayError::RateLimited(_) => "Rate limit exceeded".to_string(),
+            GatewayError::ShuttingDown => "Gateway is shutting down, reconnect".to_string(),
+        }
+    }
+
+    // Structured context for clients, e.g. which limit or upstream was involved
+    fn metadata(&self) -> HashMap<String, String> {
+        match self {
+            GatewayError::RateLimited(retry_after) => HashMap::from([(
+                "retry_after_ms".to_string(),
+                retry_after.as_millis().to_string(),
+            )]),
+            _ => HashMap::new(),
+        }
+    }
 }
 
 impl From<GatewayError> for Status {
     fn from(err: GatewayError) -> Self {
-        match err {
-            GatewayError::Internal(msg) => Status::internal(msg),
-            GatewayError::ServiceUnavailable(msg) => Status::unavailable(msg),
-            GatewayError::InvalidRequest(msg) => Status::invalid_argument(msg),
-            GatewayError::AuthenticationFailed(msg) => Status::unauthenticated(msg),
+        let message = err.message();
+        let details = common::Error {
+            code: err.code().to_string(),
+            message: message.clone(),
+            metadata: err.metadata(),
+        };
+
+        let mut status = Status::with_details(err.grpc_code(), message, details.encode_to_vec().into());
+
+        // Whole seconds, rounded up, so the value can be copied into an HTTP Retry-After header
+        if let GatewayError::RateLimited(retry_after) = &err {
+            let secs = retry_after.as_millis().div_ceil(1000).max(1);
+            if let Ok(value) = secs.to_string().parse() {
+                status.metadata_mut().insert(RETRY_AFTER, value);
+            }
         }
+
+        status
     }
-}
\ No newline at end of file
+}
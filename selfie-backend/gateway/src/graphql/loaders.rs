@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use async_graphql::dataloader::{DataLoader, Loader};
+use async_graphql::Data;
+
+use crate::services::context::{fetch_all, CallContext};
+use crate::services::media::media_service_server::MediaService;
+use crate::services::media::{self, GetMediaRequest};
+use crate::services::post::post_service_server::PostService;
+use crate::services::post::{self, GetPostRequest};
+use crate::services::user::user_service_server::UserService;
+use crate::services::user::{self, GetProfileRequest, GetUserRequest};
+use crate::services::GatewayServer;
+
+// None of the upstreams has a batch read, so a batch is a concurrent set of single reads
+// through the gateway's handlers, which keeps the response cache and resilience policy in play.
+// Ids that can't be loaded resolve to null rather than failing the query.
+
+pub struct UserLoader {
+    gateway: GatewayServer,
+    context: CallContext,
+}
+
+impl Loader<String> for UserLoader {
+    type Value = user::User;
+    type Error = Infallible;
+
+    async fn load(&self, keys: &[String]) -> Result<HashMap<String, user::User>, Infallible> {
+        let (users, _) = fetch_all(keys.iter().cloned(), |user_id| async move {
+            self.gateway
+                .get_user(self.context.request(GetUserRequest { user_id }))
+                .await
+                .map(|response| response.into_inner().user)
+        })
+        .await;
+        Ok(users)
+    }
+}
+
+pub struct ProfileLoader {
+    gateway: GatewayServer,
+    context: CallContext,
+}
+
+impl Loader<String> for ProfileLoader {
+    type Value = user::Profile;
+    type Error = Infallible;
+
+    async fn load(&self, keys: &[String]) -> Result<HashMap<String, user::Profile>, Infallible> {
+        let (profiles, _) = fetch_all(keys.iter().cloned(), |user_id| async move {
+            let request = GetProfileRequest {
+                user_id,
+                viewer_id: String::new(),
+            };
+            self.gateway
+                .get_profile(self.context.request(request))
+                .await
+                .map(|response| response.into_inner().profile)
+        })
+        .await;
+        Ok(profiles)
+    }
+}
+
+pub struct PostLoader {
+    gateway: GatewayServer,
+    context: CallContext,
+}
+
+impl Loader<String> for PostLoader {
+    type Value = post::Post;
+    type Error = Infallible;
+
+    async fn load(&self, keys: &[String]) -> Result<HashMap<String, post::Post>, Infallible> {
+        let (posts, _) = fetch_all(keys.iter().cloned(), |post_id| async move {
+            let request = GetPostRequest {
+                post_id,
+                viewer_id: String::new(),
+            };
+            self.gateway
+                .get_post(self.context.request(request))
+                .await
+                .map(|response| response.into_inner().post)
+        })
+        .await;
+        Ok(posts)
+    }
+}
+
+pub struct MediaLoader {
+    gateway: GatewayServer,
+    context: CallContext,
+}
+
+impl Loader<String> for MediaLoader {
+    type Value = media::Media;
+    type Error = Infallible;
+
+    async fn load(&self, keys: &[String]) -> Result<HashMap<String, media::Media>, Infallible> {
+        let (media, _) = fetch_all(keys.iter().cloned(), |media_id| async move {
+            self.gateway
+                .get_media(self.context.request(GetMediaRequest { media_id }))
+                .await
+                .map(|response| response.into_inner().media)
+        })
+        .await;
+        Ok(media)
+    }
+}
+
+/// Adds the caller's context and a fresh set of loaders to the data of one request or
+/// subscription connection. Loaders batch but don't cache, so nothing outlives the request.
+pub fn insert(data: &mut Data, gateway: &GatewayServer, context: CallContext) {
+    data.insert(DataLoader::new(
+        UserLoader { gateway: gateway.clone(), context: context.clone() },
+        tokio::spawn,
+    ));
+    data.insert(DataLoader::new(
+        ProfileLoader { gateway: gateway.clone(), context: context.clone() },
+        tokio::spawn,
+    ));
+    data.insert(DataLoader::new(
+        PostLoader { gateway: gateway.clone(), context: context.clone() },
+        tokio::spawn,
+    ));
+    data.insert(DataLoader::new(
+        MediaLoader { gateway: gateway.clone(), context: context.clone() },
+        tokio::spawn,
+    ));
+    data.insert(context);
+}
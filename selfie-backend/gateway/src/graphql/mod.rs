@@ -0,0 +1,280 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use async_graphql::http::{WebSocket as GraphQLWebSocket, WebSocketProtocols as Protocols, WsMessage};
+use async_graphql::{Context, Data, EmptyMutation, ErrorExtensions, Schema};
+use futures::{future, SinkExt, StreamExt};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tonic::body::BoxBody;
+use tonic::codegen::http::{HeaderMap, Method, Request, Response, StatusCode};
+use tonic::metadata::MetadataMap;
+use tonic::transport::Body;
+use tonic::Status;
+use tower::{Layer, Service};
+use tracing::debug;
+
+use crate::common::v1 as common;
+use crate::config::GraphQLConfig;
+use crate::error::GatewayError;
+use crate::middleware::metrics::code_label;
+use crate::middleware::{AuthenticatedUser, TokenVerifier};
+use crate::ratelimit::{Decision, RateLimiter};
+use crate::rest;
+use crate::services::context::CallContext;
+use crate::services::GatewayServer;
+use crate::shutdown::Shutdown;
+use crate::tls::ServerStream;
+
+mod loaders;
+mod schema;
+mod types;
+
+pub use schema::{Query, Subscription};
+
+/// Queries over HTTP POST; subscriptions are served by the WebSocket bridge.
+pub const GRAPHQL_PATH: &str = "/graphql";
+pub const SUBSCRIPTIONS_PATH: &str = "/graphql/ws";
+
+pub type GatewaySchema = Schema<Query, EmptyMutation, Subscription>;
+
+/// GraphQL view of the user, post, media and chat services. Resolvers call the gateway's own
+/// handlers, so identity binding, caching and the upstream policy apply as they do for gRPC;
+/// nested objects are loaded in batches per request.
+pub fn build_schema(config: &GraphQLConfig) -> GatewaySchema {
+    Schema::build(Query, EmptyMutation, Subscription)
+        .limit_depth(config.max_depth)
+        .limit_complexity(config.max_complexity)
+        .finish()
+}
+
+/// Data a request or subscription connection runs with: the gateway, the caller's context
+/// and its loaders.
+pub fn request_data(gateway: &GatewayServer, context: CallContext) -> Data {
+    let mut data = Data::default();
+    loaders::insert(&mut data, gateway, context);
+    data.insert(gateway.clone());
+    data
+}
+
+fn gateway<'a>(ctx: &Context<'a>) -> &'a GatewayServer {
+    ctx.data_unchecked::<GatewayServer>()
+}
+
+fn call_context<'a>(ctx: &Context<'a>) -> &'a CallContext {
+    ctx.data_unchecked::<CallContext>()
+}
+
+fn pagination(page: Option<i32>, per_page: Option<i32>) -> Option<common::PaginationRequest> {
+    if page.is_none() && per_page.is_none() {
+        return None;
+    }
+    Some(common::PaginationRequest {
+        page: page.unwrap_or(1),
+        per_page: per_page.unwrap_or_default(),
+    })
+}
+
+// Errors carry the gRPC status name in `extensions.code`, e.g. `PERMISSION_DENIED`
+fn status_error(status: Status) -> async_graphql::Error {
+    let code = code_label(status.code());
+    async_graphql::Error::new(status.message()).extend_with(|_, extensions| extensions.set("code", code))
+}
+
+/// Serves `POST /graphql` in front of the REST layer. Callers need a bearer token, and each
+/// request takes a token from the caller's default rate limit bucket.
+#[derive(Clone)]
+pub struct GraphQLLayer {
+    handler: Arc<Handler>,
+}
+
+impl GraphQLLayer {
+    pub fn new(
+        schema: GatewaySchema,
+        gateway: GatewayServer,
+        verifier: Arc<TokenVerifier>,
+        rate_limiter: Arc<RateLimiter>,
+    ) -> Self {
+        Self {
+            handler: Arc::new(Handler {
+                schema,
+                gateway,
+                verifier,
+                rate_limiter,
+            }),
+        }
+    }
+}
+
+impl<S> Layer<S> for GraphQLLayer {
+    type Service = GraphQLService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        GraphQLService {
+            inner: service,
+            handler: self.handler.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct GraphQLService<S> {
+    inner: S,
+    handler: Arc<Handler>,
+}
+
+impl<S> Service<Request<Body>> for GraphQLService<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        if req.uri().path() != GRAPHQL_PATH {
+            return Box::pin(self.inner.call(req));
+        }
+
+        let handler = self.handler.clone();
+        Box::pin(async move {
+            match handler.execute(req).await {
+                Ok(response) => Ok(response),
+                Err(status) => Ok(rest::error_response(&status)),
+            }
+        })
+    }
+}
+
+struct Handler {
+    schema: GatewaySchema,
+    gateway: GatewayServer,
+    verifier: Arc<TokenVerifier>,
+    rate_limiter: Arc<RateLimiter>,
+}
+
+impl Handler {
+    async fn execute(&self, req: Request<Body>) -> Result<Response<BoxBody>, Status> {
+        if req.method() != Method::POST {
+            return Err(GatewayError::InvalidRequest("GraphQL requests must use POST".to_string()).into());
+        }
+        let (parts, body) = req.into_parts();
+
+        let user_id = parts
+            .headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| GatewayError::AuthenticationFailed("Missing or invalid authentication token".to_string()))
+            .and_then(|token| self.verifier.verify(token))?;
+
+        if let Decision::Limited { retry_after } = self.rate_limiter.check(GRAPHQL_PATH, &format!("user:{}", user_id)).await {
+            return Err(GatewayError::RateLimited(retry_after).into());
+        }
+
+        let body = rest::collect_body(body).await?;
+        let mut request: async_graphql::Request = serde_json::from_slice(&body)
+            .map_err(|err| GatewayError::InvalidRequest(format!("invalid GraphQL request: {}", err)))?;
+
+        let context = CallContext::new(forwarded_metadata(&parts.headers), Some(AuthenticatedUser { user_id }));
+        request.data = request_data(&self.gateway, context);
+
+        let response = self.schema.execute(request).await;
+        let body = serde_json::to_vec(&response).map_err(|err| Status::internal(err.to_string()))?;
+        Ok(rest::json_response(StatusCode::OK, body))
+    }
+}
+
+// Caller headers (request ids, trace context, deadline) passed on to the calls a query makes
+fn forwarded_metadata(headers: &HeaderMap) -> MetadataMap {
+    let mut forwarded = headers.clone();
+    for header in rest::HOP_HEADERS {
+        forwarded.remove(*header);
+    }
+    MetadataMap::from_headers(forwarded)
+}
+
+/// Runs the graphql-ws or graphql-transport-ws protocol on an upgraded connection until
+/// either side closes it or the gateway shuts down. Connections quiet for longer than
+/// `idle_timeout` are closed, as are those still open at `expires_at`, when the caller's
+/// access token runs out.
+pub async fn serve_subscriptions(
+    schema: GatewaySchema,
+    ws: WebSocketStream<ServerStream>,
+    protocol: Protocols,
+    data: Data,
+    idle_timeout: Duration,
+    expires_at: Instant,
+    shutdown: &Shutdown,
+) {
+    let (mut sink, source) = ws.split();
+    let input = source
+        .take_while(|message| future::ready(matches!(message, Ok(message) if !message.is_close())))
+        .filter_map(|message| {
+            future::ready(match message {
+                Ok(Message::Text(text)) => Some(text.into_bytes()),
+                Ok(Message::Binary(bytes)) => Some(bytes),
+                _ => None,
+            })
+        });
+
+    let mut output = GraphQLWebSocket::new(schema, input, protocol)
+        .connection_data(data)
+        .keepalive_timeout(idle_timeout);
+
+    let shutdown = shutdown.started();
+    tokio::pin!(shutdown);
+    let expired = tokio::time::sleep_until(expires_at);
+    tokio::pin!(expired);
+
+    loop {
+        let message = tokio::select! {
+            biased;
+            _ = &mut shutdown => {
+                let close = CloseFrame {
+                    code: CloseCode::Restart,
+                    reason: "gateway restarting, reconnect".into(),
+                };
+                let _ = sink.send(Message::Close(Some(close))).await;
+                break;
+            }
+            _ = &mut expired => {
+                let close = CloseFrame {
+                    code: CloseCode::Policy,
+                    reason: "access token expired".into(),
+                };
+                let _ = sink.send(Message::Close(Some(close))).await;
+                break;
+            }
+            message = output.next() => match message {
+                Some(message) => message,
+                None => break,
+            },
+        };
+        let message = match message {
+            WsMessage::Text(text) => Message::Text(text),
+            WsMessage::Close(code, reason) => Message::Close(Some(CloseFrame {
+                code: code.into(),
+                reason: reason.into(),
+            })),
+        };
+        let closing = message.is_close();
+        if let Err(err) = sink.send(message).await {
+            debug!("GraphQL subscription write failed: {}", err);
+            break;
+        }
+        if closing {
+            break;
+        }
+    }
+}
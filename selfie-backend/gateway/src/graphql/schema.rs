@@ -0,0 +1,139 @@
+use async_graphql::{Context, Object, Result, Subscription, ID};
+use futures::{Stream, StreamExt};
+use tonic::{Code, Response, Status};
+
+use crate::services::chat::chat_service_server::ChatService;
+use crate::services::chat::{GetChatRequest, ListChatsRequest, StreamMessagesRequest};
+use crate::services::media::media_service_server::MediaService;
+use crate::services::media::GetMediaRequest;
+use crate::services::post::post_service_server::PostService;
+use crate::services::post::{GetFeedRequest, GetPostRequest};
+use crate::services::user::user_service_server::UserService;
+use crate::services::user::{GetProfileRequest, GetUserRequest};
+use super::types::{Chat, ChatPage, Media, Message, PageInfo, Post, PostPage, Profile, User};
+use super::{call_context, gateway, pagination, status_error};
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// The authenticated caller.
+    async fn me(&self, ctx: &Context<'_>) -> Result<Option<User>> {
+        let user_id = call_context(ctx).caller().map(|caller| caller.user_id.clone()).unwrap_or_default();
+        self.user(ctx, ID(user_id)).await
+    }
+
+    async fn user(&self, ctx: &Context<'_>, id: ID) -> Result<Option<User>> {
+        let response = gateway(ctx).get_user(call_context(ctx).request(GetUserRequest { user_id: id.0 })).await;
+        Ok(found(response)?.and_then(|response| response.user).map(User))
+    }
+
+    async fn profile(&self, ctx: &Context<'_>, user_id: ID) -> Result<Option<Profile>> {
+        let request = GetProfileRequest {
+            user_id: user_id.0,
+            viewer_id: String::new(),
+        };
+        let response = gateway(ctx).get_profile(call_context(ctx).request(request)).await;
+        Ok(found(response)?.and_then(|response| response.profile).map(Profile))
+    }
+
+    async fn post(&self, ctx: &Context<'_>, id: ID) -> Result<Option<Post>> {
+        let request = GetPostRequest {
+            post_id: id.0,
+            viewer_id: String::new(),
+        };
+        let response = gateway(ctx).get_post(call_context(ctx).request(request)).await;
+        Ok(found(response)?.and_then(|response| response.post).map(Post))
+    }
+
+    /// The caller's home feed.
+    async fn feed(&self, ctx: &Context<'_>, page: Option<i32>, per_page: Option<i32>) -> Result<PostPage> {
+        let request = GetFeedRequest {
+            user_id: String::new(),
+            pagination: pagination(page, per_page),
+        };
+        let response = gateway(ctx)
+            .get_feed(call_context(ctx).request(request))
+            .await
+            .map_err(status_error)?
+            .into_inner();
+
+        Ok(PostPage {
+            posts: response.posts.into_iter().map(Post).collect(),
+            page_info: response.pagination.map(PageInfo::from),
+        })
+    }
+
+    async fn media(&self, ctx: &Context<'_>, id: ID) -> Result<Option<Media>> {
+        let response = gateway(ctx).get_media(call_context(ctx).request(GetMediaRequest { media_id: id.0 })).await;
+        Ok(found(response)?.and_then(|response| response.media).map(Media))
+    }
+
+    /// Chats the caller participates in.
+    async fn chats(&self, ctx: &Context<'_>, page: Option<i32>, per_page: Option<i32>) -> Result<ChatPage> {
+        let request = ListChatsRequest {
+            user_id: String::new(),
+            pagination: pagination(page, per_page),
+        };
+        let response = gateway(ctx)
+            .list_chats(call_context(ctx).request(request))
+            .await
+            .map_err(status_error)?
+            .into_inner();
+
+        Ok(ChatPage {
+            chats: response.chats.into_iter().map(Chat).collect(),
+            page_info: response.pagination.map(PageInfo::from),
+        })
+    }
+
+    async fn chat(&self, ctx: &Context<'_>, id: ID) -> Result<Option<Chat>> {
+        let request = GetChatRequest {
+            chat_id: id.0,
+            user_id: String::new(),
+        };
+        let response = gateway(ctx).get_chat(call_context(ctx).request(request)).await;
+        Ok(found(response)?.and_then(|response| response.chat).map(Chat))
+    }
+}
+
+pub struct Subscription;
+
+#[Subscription]
+impl Subscription {
+    /// Messages sent to the caller's chats, or only to `chatIds`. Reconnecting clients pass the
+    /// last message they received as `afterMessageId` to get what they missed first.
+    async fn messages(
+        &self,
+        ctx: &Context<'_>,
+        chat_ids: Option<Vec<ID>>,
+        after_message_id: Option<ID>,
+    ) -> Result<impl Stream<Item = Result<Message>>> {
+        let request = StreamMessagesRequest {
+            user_id: String::new(),
+            chat_ids: chat_ids.unwrap_or_default().into_iter().map(|id| id.0).collect(),
+            after_message_id: after_message_id.map(|id| id.0).unwrap_or_default(),
+        };
+        let stream = gateway(ctx)
+            .stream_messages(call_context(ctx).request(request))
+            .await
+            .map_err(status_error)?
+            .into_inner();
+
+        Ok(stream.filter_map(|delivered| async move {
+            match delivered {
+                Ok(response) => response.message.map(|message| Ok(Message(message))),
+                Err(status) => Some(Err(status_error(status))),
+            }
+        }))
+    }
+}
+
+// Lookups of a single object resolve to null when it doesn't exist
+fn found<T>(response: Result<Response<T>, Status>) -> Result<Option<T>> {
+    match response {
+        Ok(response) => Ok(Some(response.into_inner())),
+        Err(status) if status.code() == Code::NotFound => Ok(None),
+        Err(status) => Err(status_error(status)),
+    }
+}
@@ -0,0 +1,442 @@
+use async_graphql::dataloader::DataLoader;
+use async_graphql::{Context, Enum, Object, Result, SimpleObject, ID};
+
+use crate::common::v1 as common;
+use crate::services::chat::chat_service_server::ChatService;
+use crate::services::chat::{self, GetMessagesRequest};
+use crate::services::media;
+use crate::services::post::post_service_server::PostService;
+use crate::services::post::{self, GetCommentsRequest, ListUserPostsRequest};
+use crate::services::user;
+use super::loaders::{MediaLoader, PostLoader, ProfileLoader, UserLoader};
+use super::{call_context, gateway, pagination, status_error};
+
+#[derive(SimpleObject)]
+pub struct Timestamp {
+    pub seconds: i64,
+    pub nanos: i32,
+}
+
+impl From<common::Timestamp> for Timestamp {
+    fn from(timestamp: common::Timestamp) -> Self {
+        Self {
+            seconds: timestamp.seconds,
+            nanos: timestamp.nanos,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct PageInfo {
+    pub total_items: i32,
+    pub total_pages: i32,
+    pub current_page: i32,
+    pub per_page: i32,
+}
+
+impl From<common::PaginationResponse> for PageInfo {
+    fn from(page: common::PaginationResponse) -> Self {
+        Self {
+            total_items: page.total_items,
+            total_pages: page.total_pages,
+            current_page: page.current_page,
+            per_page: page.per_page,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct PostPage {
+    pub posts: Vec<Post>,
+    pub page_info: Option<PageInfo>,
+}
+
+#[derive(SimpleObject)]
+pub struct CommentPage {
+    pub comments: Vec<Comment>,
+    pub page_info: Option<PageInfo>,
+}
+
+#[derive(SimpleObject)]
+pub struct ChatPage {
+    pub chats: Vec<Chat>,
+    pub page_info: Option<PageInfo>,
+}
+
+#[derive(SimpleObject)]
+pub struct MessagePage {
+    pub messages: Vec<Message>,
+    pub page_info: Option<PageInfo>,
+}
+
+pub struct User(pub user::User);
+
+#[Object]
+impl User {
+    async fn id(&self) -> ID {
+        ID(self.0.id.clone())
+    }
+
+    async fn username(&self) -> &str {
+        &self.0.username
+    }
+
+    async fn display_name(&self) -> &str {
+        &self.0.display_name
+    }
+
+    async fn avatar_url(&self) -> &str {
+        &self.0.avatar_url
+    }
+
+    async fn bio(&self) -> &str {
+        &self.0.bio
+    }
+
+    async fn verified(&self) -> bool {
+        self.0.verified
+    }
+
+    async fn created_at(&self) -> Option<Timestamp> {
+        self.0.created_at.clone().map(Timestamp::from)
+    }
+
+    async fn updated_at(&self) -> Option<Timestamp> {
+        self.0.updated_at.clone().map(Timestamp::from)
+    }
+
+    /// Follower counts and the caller's relationship to the user.
+    async fn profile(&self, ctx: &Context<'_>) -> Result<Option<Profile>> {
+        let profile = ctx.data_unchecked::<DataLoader<ProfileLoader>>().load_one(self.0.id.clone()).await?;
+        Ok(profile.map(Profile))
+    }
+
+    async fn posts(&self, ctx: &Context<'_>, page: Option<i32>, per_page: Option<i32>) -> Result<PostPage> {
+        let request = ListUserPostsRequest {
+            user_id: self.0.id.clone(),
+            viewer_id: String::new(),
+            pagination: pagination(page, per_page),
+        };
+        let response = gateway(ctx)
+            .list_user_posts(call_context(ctx).request(request))
+            .await
+            .map_err(status_error)?
+            .into_inner();
+
+        Ok(PostPage {
+            posts: response.posts.into_iter().map(Post).collect(),
+            page_info: response.pagination.map(PageInfo::from),
+        })
+    }
+}
+
+pub struct Profile(pub user::Profile);
+
+#[Object]
+impl Profile {
+    async fn user(&self) -> Option<User> {
+        self.0.user.clone().map(User)
+    }
+
+    async fn followers_count(&self) -> i32 {
+        self.0.followers_count
+    }
+
+    async fn following_count(&self) -> i32 {
+        self.0.following_count
+    }
+
+    async fn posts_count(&self) -> i32 {
+        self.0.posts_count
+    }
+
+    async fn is_following(&self) -> bool {
+        self.0.is_following
+    }
+
+    async fn is_blocked(&self) -> bool {
+        self.0.is_blocked
+    }
+}
+
+pub struct Post(pub post::Post);
+
+#[Object]
+impl Post {
+    async fn id(&self) -> ID {
+        ID(self.0.id.clone())
+    }
+
+    async fn author(&self, ctx: &Context<'_>) -> Result<Option<User>> {
+        let user = ctx.data_unchecked::<DataLoader<UserLoader>>().load_one(self.0.user_id.clone()).await?;
+        Ok(user.map(User))
+    }
+
+    async fn caption(&self) -> &str {
+        &self.0.caption
+    }
+
+    async fn media_urls(&self) -> &[String] {
+        &self.0.media_urls
+    }
+
+    /// Attached media that could be loaded, in the post's order.
+    async fn media(&self, ctx: &Context<'_>) -> Result<Vec<Media>> {
+        let mut loaded = ctx
+            .data_unchecked::<DataLoader<MediaLoader>>()
+            .load_many(self.0.media_ids.iter().cloned())
+            .await?;
+        Ok(self.0.media_ids.iter().filter_map(|id| loaded.remove(id)).map(Media).collect())
+    }
+
+    async fn likes_count(&self) -> i32 {
+        self.0.likes_count
+    }
+
+    async fn comments_count(&self) -> i32 {
+        self.0.comments_count
+    }
+
+    async fn is_liked(&self) -> bool {
+        self.0.is_liked
+    }
+
+    async fn created_at(&self) -> Option<Timestamp> {
+        self.0.created_at.clone().map(Timestamp::from)
+    }
+
+    async fn updated_at(&self) -> Option<Timestamp> {
+        self.0.updated_at.clone().map(Timestamp::from)
+    }
+
+    async fn comments(&self, ctx: &Context<'_>, page: Option<i32>, per_page: Option<i32>) -> Result<CommentPage> {
+        let request = GetCommentsRequest {
+            post_id: self.0.id.clone(),
+            pagination: pagination(page, per_page),
+        };
+        let response = gateway(ctx)
+            .get_comments(call_context(ctx).request(request))
+            .await
+            .map_err(status_error)?
+            .into_inner();
+
+        Ok(CommentPage {
+            comments: response.comments.into_iter().map(Comment).collect(),
+            page_info: response.pagination.map(PageInfo::from),
+        })
+    }
+}
+
+pub struct Comment(pub post::Comment);
+
+#[Object]
+impl Comment {
+    async fn id(&self) -> ID {
+        ID(self.0.id.clone())
+    }
+
+    async fn post(&self, ctx: &Context<'_>) -> Result<Option<Post>> {
+        let post = ctx.data_unchecked::<DataLoader<PostLoader>>().load_one(self.0.post_id.clone()).await?;
+        Ok(post.map(Post))
+    }
+
+    async fn author(&self, ctx: &Context<'_>) -> Result<Option<User>> {
+        let user = ctx.data_unchecked::<DataLoader<UserLoader>>().load_one(self.0.user_id.clone()).await?;
+        Ok(user.map(User))
+    }
+
+    async fn content(&self) -> &str {
+        &self.0.content
+    }
+
+    async fn created_at(&self) -> Option<Timestamp> {
+        self.0.created_at.clone().map(Timestamp::from)
+    }
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum MediaType {
+    Unspecified,
+    Image,
+    Video,
+    Audio,
+}
+
+impl From<media::MediaType> for MediaType {
+    fn from(media_type: media::MediaType) -> Self {
+        match media_type {
+            media::MediaType::Unspecified => MediaType::Unspecified,
+            media::MediaType::Image => MediaType::Image,
+            media::MediaType::Video => MediaType::Video,
+            media::MediaType::Audio => MediaType::Audio,
+        }
+    }
+}
+
+pub struct Media(pub media::Media);
+
+#[Object]
+impl Media {
+    async fn id(&self) -> ID {
+        ID(self.0.id.clone())
+    }
+
+    async fn owner(&self, ctx: &Context<'_>) -> Result<Option<User>> {
+        let user = ctx.data_unchecked::<DataLoader<UserLoader>>().load_one(self.0.user_id.clone()).await?;
+        Ok(user.map(User))
+    }
+
+    #[graphql(name = "type")]
+    async fn media_type(&self) -> MediaType {
+        self.0.r#type().into()
+    }
+
+    async fn url(&self) -> &str {
+        &self.0.url
+    }
+
+    async fn thumbnail_url(&self) -> Option<&str> {
+        self.0.thumbnail_url.as_deref()
+    }
+
+    async fn content_type(&self) -> &str {
+        &self.0.content_type
+    }
+
+    async fn size(&self) -> i64 {
+        self.0.size
+    }
+
+    async fn width(&self) -> Option<i32> {
+        self.0.width
+    }
+
+    async fn height(&self) -> Option<i32> {
+        self.0.height
+    }
+
+    /// Length of video and audio, in seconds.
+    async fn duration(&self) -> Option<i32> {
+        self.0.duration
+    }
+
+    async fn created_at(&self) -> Option<Timestamp> {
+        self.0.created_at.clone().map(Timestamp::from)
+    }
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum ChatType {
+    Unspecified,
+    Direct,
+    Group,
+}
+
+impl From<chat::ChatType> for ChatType {
+    fn from(chat_type: chat::ChatType) -> Self {
+        match chat_type {
+            chat::ChatType::Unspecified => ChatType::Unspecified,
+            chat::ChatType::Direct => ChatType::Direct,
+            chat::ChatType::Group => ChatType::Group,
+        }
+    }
+}
+
+pub struct Chat(pub chat::Chat);
+
+#[Object]
+impl Chat {
+    async fn id(&self) -> ID {
+        ID(self.0.id.clone())
+    }
+
+    #[graphql(name = "type")]
+    async fn chat_type(&self) -> ChatType {
+        self.0.r#type().into()
+    }
+
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    /// Participants that could be loaded.
+    async fn participants(&self, ctx: &Context<'_>) -> Result<Vec<User>> {
+        let mut loaded = ctx
+            .data_unchecked::<DataLoader<UserLoader>>()
+            .load_many(self.0.participant_ids.iter().cloned())
+            .await?;
+        Ok(self.0.participant_ids.iter().filter_map(|id| loaded.remove(id)).map(User).collect())
+    }
+
+    async fn last_message(&self) -> Option<Message> {
+        self.0.last_message.clone().map(Message)
+    }
+
+    async fn unread_count(&self) -> i32 {
+        self.0.unread_count
+    }
+
+    async fn created_at(&self) -> Option<Timestamp> {
+        self.0.created_at.clone().map(Timestamp::from)
+    }
+
+    async fn updated_at(&self) -> Option<Timestamp> {
+        self.0.updated_at.clone().map(Timestamp::from)
+    }
+
+    async fn messages(&self, ctx: &Context<'_>, page: Option<i32>, per_page: Option<i32>) -> Result<MessagePage> {
+        let request = GetMessagesRequest {
+            chat_id: self.0.id.clone(),
+            user_id: String::new(),
+            pagination: pagination(page, per_page),
+        };
+        let response = gateway(ctx)
+            .get_messages(call_context(ctx).request(request))
+            .await
+            .map_err(status_error)?
+            .into_inner();
+
+        Ok(MessagePage {
+            messages: response.messages.into_iter().map(Message).collect(),
+            page_info: response.pagination.map(PageInfo::from),
+        })
+    }
+}
+
+pub struct Message(pub chat::Message);
+
+#[Object]
+impl Message {
+    async fn id(&self) -> ID {
+        ID(self.0.id.clone())
+    }
+
+    async fn chat_id(&self) -> ID {
+        ID(self.0.chat_id.clone())
+    }
+
+    async fn sender(&self, ctx: &Context<'_>) -> Result<Option<User>> {
+        let user = ctx.data_unchecked::<DataLoader<UserLoader>>().load_one(self.0.sender_id.clone()).await?;
+        Ok(user.map(User))
+    }
+
+    async fn content(&self) -> &str {
+        &self.0.content
+    }
+
+    async fn media_urls(&self) -> &[String] {
+        &self.0.media_urls
+    }
+
+    async fn read_by(&self) -> Vec<ID> {
+        self.0.read_by.iter().cloned().map(ID).collect()
+    }
+
+    async fn created_at(&self) -> Option<Timestamp> {
+        self.0.created_at.clone().map(Timestamp::from)
+    }
+
+    async fn edited_at(&self) -> Option<Timestamp> {
+        self.0.edited_at.clone().map(Timestamp::from)
+    }
+}
@@ -0,0 +1,206 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use metrics::gauge;
+use tokio::sync::Mutex;
+use tonic::server::NamedService;
+use tonic::transport::Channel;
+use tonic::Code;
+use tonic_health::pb::health_check_response::ServingStatus as ProbeStatus;
+use tonic_health::pb::health_client::HealthClient;
+use tonic_health::pb::HealthCheckRequest;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use tracing::{info, warn};
+
+use crate::config::{HealthConfig, ServiceDiscoveryConfig};
+use crate::proxy::{EndpointSettings, Upstream};
+use crate::services::{auth, chat, media, post, user, GatewayServer};
+
+struct ProbeTarget {
+    upstream: Upstream,
+    endpoints: Vec<(String, HealthClient<Channel>)>,
+    healthy: Option<bool>,
+}
+
+/// Periodically checks every upstream endpoint and publishes the result through the gateway's
+/// own `grpc.health.v1.Health` service: each proxied service (e.g. `selfie.post.v1.PostService`)
+/// reports its upstream's status, and the overall status goes NOT_SERVING while a critical
+/// upstream is down or the gateway is shutting down.
+pub struct HealthProber {
+    reporter: HealthReporter,
+    probes: Mutex<Probes>,
+    interval: Duration,
+    draining: AtomicBool,
+}
+
+/// What is probed, replaced as a whole on reload.
+pub struct Probes {
+    targets: Vec<ProbeTarget>,
+    critical: Vec<Upstream>,
+    timeout: Duration,
+}
+
+impl HealthProber {
+    pub fn new(
+        reporter: HealthReporter,
+        discovery: &ServiceDiscoveryConfig,
+        config: &HealthConfig,
+    ) -> Result<Self> {
+        Ok(Self {
+            reporter,
+            probes: Mutex::new(Probes::new(discovery, config)?),
+            interval: Duration::from_secs(config.probe_interval_secs),
+            draining: AtomicBool::new(false),
+        })
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            self.probe_all().await;
+        }
+    }
+
+    /// Builds probes for `replace_probes` from the configured endpoints.
+    pub fn load_probes(discovery: &ServiceDiscoveryConfig, config: &HealthConfig) -> Result<Probes> {
+        Probes::new(discovery, config)
+    }
+
+    /// Probes `next` from the next round on. Services keep their last known status until
+    /// then; the probe interval only changes on restart.
+    pub async fn replace_probes(&self, mut next: Probes) {
+        let mut probes = self.probes.lock().await;
+        for (target, previous) in next.targets.iter_mut().zip(&probes.targets) {
+            target.healthy = previous.healthy;
+        }
+        *probes = next;
+    }
+
+    /// Reports every service NOT_SERVING for the rest of the process's life, so load
+    /// balancers take the gateway out of rotation before it stops accepting connections.
+    pub async fn drain(&self) {
+        let mut reporter = self.reporter.clone();
+        // Waits out a probe round in progress so it can't report SERVING afterwards
+        let probes = self.probes.lock().await;
+        self.draining.store(true, Ordering::Relaxed);
+
+        for target in &probes.targets {
+            reporter
+                .set_service_status(service_name(target.upstream), ServingStatus::NotServing)
+                .await;
+        }
+        reporter.set_service_status("", ServingStatus::NotServing).await;
+    }
+
+    async fn probe_all(&self) {
+        let mut reporter = self.reporter.clone();
+        let mut probes = self.probes.lock().await;
+        if self.draining.load(Ordering::Relaxed) {
+            return;
+        }
+        let timeout = probes.timeout;
+        let results = join_all(probes.targets.iter().map(|target| probe_service(target, timeout))).await;
+
+        for (target, healthy) in probes.targets.iter_mut().zip(results) {
+            if target.healthy != Some(healthy) {
+                if healthy {
+                    info!("{} service is healthy", target.upstream.as_str());
+                } else {
+                    warn!("{} service is unhealthy", target.upstream.as_str());
+                }
+                target.healthy = Some(healthy);
+            }
+
+            let status = if healthy { ServingStatus::Serving } else { ServingStatus::NotServing };
+            reporter
+                .set_service_status(service_name(target.upstream), status)
+                .await;
+            gauge!("upstream_healthy", if healthy { 1.0 } else { 0.0 }, "service" => target.upstream.as_str());
+        }
+
+        let critical_down = probes
+            .targets
+            .iter()
+            .any(|target| probes.critical.contains(&target.upstream) && target.healthy == Some(false));
+        let overall = if critical_down { ServingStatus::NotServing } else { ServingStatus::Serving };
+        reporter.set_service_status("", overall).await;
+    }
+}
+
+impl Probes {
+    fn new(discovery: &ServiceDiscoveryConfig, config: &HealthConfig) -> Result<Self> {
+        let targets = Upstream::ALL
+            .iter()
+            .map(|upstream| {
+                let settings = EndpointSettings::new(discovery, *upstream)?;
+                let endpoints = discovery
+                    .endpoints(*upstream)
+                    .iter()
+                    .map(|url| {
+                        let channel = settings.endpoint(url)?.connect_lazy();
+                        Ok((url.clone(), HealthClient::new(channel)))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                Ok(ProbeTarget {
+                    upstream: *upstream,
+                    endpoints,
+                    healthy: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let critical = config
+            .critical_services
+            .iter()
+            .map(|name| Upstream::parse(name).ok_or_else(|| anyhow!("unknown critical service {}", name)))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            targets,
+            critical,
+            timeout: Duration::from_millis(config.probe_timeout_ms),
+        })
+    }
+}
+
+// A service is up while any of its endpoints is
+async fn probe_service(target: &ProbeTarget, timeout: Duration) -> bool {
+    let probes = target.endpoints.iter().map(|(url, client)| async move {
+        let mut client = client.clone();
+        let request = HealthCheckRequest { service: String::new() };
+
+        match tokio::time::timeout(timeout, client.check(request)).await {
+            Ok(Ok(response)) => response.get_ref().status == ProbeStatus::Serving as i32,
+            // Reachable, but the service doesn't implement grpc.health.v1
+            Ok(Err(status)) if status.code() == Code::Unimplemented => true,
+            Ok(Err(status)) => {
+                warn!("Health check of {} failed: {}", url, status.message());
+                false
+            }
+            Err(_) => {
+                warn!("Health check of {} timed out", url);
+                false
+            }
+        }
+    });
+
+    join_all(probes).await.into_iter().any(|healthy| healthy)
+}
+
+// The gateway service that fronts each upstream, as named in health checks
+fn service_name(upstream: Upstream) -> &'static str {
+    match upstream {
+        Upstream::Auth => <auth::auth_service_server::AuthServiceServer<GatewayServer> as NamedService>::NAME,
+        Upstream::User => <user::user_service_server::UserServiceServer<GatewayServer> as NamedService>::NAME,
+        Upstream::Post => <post::post_service_server::PostServiceServer<GatewayServer> as NamedService>::NAME,
+        Upstream::Media => <media::media_service_server::MediaServiceServer<GatewayServer> as NamedService>::NAME,
+        Upstream::Chat => <chat::chat_service_server::ChatServiceServer<GatewayServer> as NamedService>::NAME,
+    }
+}
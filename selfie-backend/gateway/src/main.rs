@@ -1,68 +1,256 @@
+use std::sync::Arc;
+use std::time::Duration;
 use anyhow::Result;
+use tonic::codegen::http::{header::HeaderName, HeaderValue, Method};
 use tonic::transport::Server;
-use tracing::{info, Level};
+use tonic_web::GrpcWebLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{error, info, warn};
 use tower::ServiceBuilder;
 
+mod cache;
+mod common;
 mod config;
 mod error;
+mod graphql;
+mod health;
 mod proxy;
+mod ratelimit;
+mod reload;
+mod rest;
 mod services;
+mod shutdown;
+mod telemetry;
+mod tls;
+mod websocket;
 mod middleware;
 
-use middleware::{AuthMiddlewareLayer, LoggingMiddlewareLayer};
+use metrics_exporter_prometheus::Matcher;
+use middleware::{
+    AuthMiddlewareLayer, LoggingMiddlewareLayer, MethodLabels, MetricsLayer, PublicMethods, TokenVerifier, TraceContextLayer,
+};
+
+// Histogram buckets for gateway and upstream latencies
+const LATENCY_BUCKETS_MS: &[f64] = &[1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0];
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing subscriber
-    tracing_subscriber::fmt()
-        .with_max_level(Level::INFO)
-        .with_target(false)
-        .with_thread_ids(true)
-        .with_file(true)
-        .with_line_number(true)
-        .init();
+    // Load configuration from the optional config file and the environment; SIGHUP or an
+    // edit to the file applies changes without a restart
+    let config = config::load_config()?;
 
-    info!("Starting Selfie gRPC Gateway...");
+    // Initialize structured logging and trace export
+    telemetry::init(&config.telemetry)?;
 
-    // Load configuration
-    let config = config::load_config()?;
+    info!("Starting Selfie gRPC Gateway...");
     
-    // Initialize metrics
-    let metrics_recorder = metrics_exporter_prometheus::PrometheusBuilder::new()
-        .with_http_listener(config.metrics_addr.parse()?)
-        .build()?;
+    // Initialize metrics; the recorder must be installed before the first metric is emitted
+    metrics_exporter_prometheus::PrometheusBuilder::new()
+        .with_http_listener(config.metrics_addr.parse::<std::net::SocketAddr>()?)
+        .set_buckets_for_metric(Matcher::Suffix("duration_ms".to_string()), LATENCY_BUCKETS_MS)?
+        .install()?;
     
     // Initialize service proxies
-    let proxies = proxy::ServiceProxies::new(&config.service_discovery).await?;
+    let proxies = proxy::ServiceProxies::new(&config.service_discovery, &config.upstream).await?;
     
+    // Shared by the listeners and long-lived streams so they all wind down on SIGTERM
+    let shutdown = shutdown::Shutdown::default();
+
     // Create gateway server instance
-    let gateway = services::GatewayServer::new(proxies);
+    let cache = Arc::new(cache::ResponseCache::new(&config.cache));
+    let upload_limits = Arc::new(services::media::UploadLimits::new(&config.media));
+    let gateway = services::GatewayServer::new(proxies.clone(), cache.clone(), upload_limits.clone(), shutdown.clone());
+
+    // Load auth-service public keys for local token verification
+    let verifier = Arc::new(TokenVerifier::from_config(&config.auth)?);
+    // Tokens are rejected until the JWKS has been fetched; the refresh loop keeps trying
+    if let Err(err) = verifier.refresh_jwks().await {
+        warn!("Failed to fetch the JWKS: {:#}", err);
+    }
+    tokio::spawn(verifier.clone().run());
+    let public_methods = Arc::new(PublicMethods::new(&config.auth.public_methods));
+    
+    // Token buckets per user (or client IP before login), in memory or shared through Redis
+    let rate_limiter = Arc::new(ratelimit::RateLimiter::from_config(&config.rate_limit).await?);
+    
+    // HTTP/JSON routes from the google.api.http annotations in the descriptor set
+    let rest_routes = Arc::new(rest::RouteTable::from_descriptor_set(services::FILE_DESCRIPTOR_SET)?);
+    info!("Loaded {} REST routes", rest_routes.len());
+    
+    // Server reflection for grpcurl/Postman, served from the same descriptor set
+    let reflection = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(services::FILE_DESCRIPTOR_SET)
+        .build()?;
     
-    // Create middleware stack
+    // Per-service and overall serving status, driven by probing the upstreams
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    let prober = Arc::new(health::HealthProber::new(health_reporter, &config.service_discovery, &config.health)?);
+    tokio::spawn(prober.clone().run());
+    
+    // GraphQL over the same handlers; queries on the main port, subscriptions on the
+    // WebSocket listener
+    let graphql_schema = config.graphql.enabled.then(|| graphql::build_schema(&config.graphql));
+    
+    // TLS termination for both listeners; certificates are reloaded along with the config
+    let mut server_tls = Vec::new();
+    let (grpc_tls, websocket_tls) = if config.tls.enabled {
+        let grpc_tls = Arc::new(tls::ServerTls::from_config(&config.tls, tls::Alpn::Grpc)?);
+        let websocket_tls = Arc::new(tls::ServerTls::from_config(&config.tls, tls::Alpn::WebSocket)?);
+        server_tls.extend([grpc_tls.clone(), websocket_tls.clone()]);
+        (Some(grpc_tls), Some(websocket_tls))
+    } else {
+        (None, None)
+    };
+    
+    // Chat over WebSocket for clients without gRPC streaming, on its own listener
+    let mut websocket = None;
+    if config.websocket.enabled {
+        let bridge = Arc::new(websocket::WebSocketBridge::new(
+            gateway.clone(),
+            graphql_schema.clone(),
+            verifier.clone(),
+            rate_limiter.clone(),
+            services::FILE_DESCRIPTOR_SET,
+            &config.websocket,
+            shutdown.clone(),
+        )?);
+        let ws_addr = config.websocket.addr.parse()?;
+        websocket = Some(tokio::spawn(async move {
+            if let Err(err) = bridge.serve(ws_addr, websocket_tls).await {
+                error!("WebSocket bridge stopped: {}", err);
+            }
+        }));
+    }
+    
+    // Request metrics, auth and rate-limit counters all label calls by service and method
+    let method_labels = MethodLabels::from_descriptor_set(services::FILE_DESCRIPTOR_SET)?;
+
+    // Create middleware stack. gRPC-Web is translated to plain gRPC before the REST layer
+    // decides whether a request needs transcoding.
     let middleware = ServiceBuilder::new()
+        .layer(TraceContextLayer)
+        .layer(cors_layer(&config.cors_allowed_origins)?)
+        .layer(GrpcWebLayer::new())
+        .option_layer(graphql_schema.map(|schema| {
+            graphql::GraphQLLayer::new(schema, gateway.clone(), verifier.clone(), rate_limiter.clone())
+        }))
+        .layer(rest::RestLayer::new(rest_routes))
         .layer(LoggingMiddlewareLayer)
-        .layer(AuthMiddlewareLayer)
+        .layer(MetricsLayer::new(method_labels.clone()))
+        .layer(AuthMiddlewareLayer::new(verifier.clone(), public_methods.clone(), method_labels.clone()))
+        .layer(ratelimit::RateLimitLayer::new(rate_limiter.clone(), method_labels))
         .into_inner();
 
     // Start the gRPC server
-    let addr = format!("{}:{}", config.host, config.port).parse()?;
-    info!("Gateway listening on {}", addr);
+    let addr: std::net::SocketAddr = format!("{}:{}", config.host, config.port).parse()?;
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Gateway listening on {}{}", addr, if grpc_tls.is_some() { " (TLS)" } else { "" });
+
+    // Fixed at startup; the config itself moves to the reload task
+    let drain_delay = Duration::from_secs(config.shutdown.drain_delay_secs);
+    let grace_period = Duration::from_secs(config.shutdown.grace_period_secs);
+    let metrics_flush = Duration::from_secs(config.shutdown.metrics_flush_secs);
 
-    Server::builder()
-        .accept_http1(true) // Enable HTTP/1.1 for health checks
+    let reloadable = reload::Reloadable {
+        proxies,
+        prober: prober.clone(),
+        verifier,
+        public_methods,
+        rate_limiter,
+        cache,
+        upload_limits,
+        server_tls,
+    };
+    tokio::spawn(async move {
+        if let Err(err) = reloadable.watch(config).await {
+            error!("Configuration reloading stopped: {}", err);
+        }
+    });
+
+    let server = Server::builder()
+        .accept_http1(true) // HTTP/1.1 for gRPC-Web, REST clients and health checks
         .layer(middleware)
         // Add service implementations
-        .add_service(services::auth::AuthServer::new(gateway.clone()))
-        .add_service(services::user::UserServer::new(gateway.clone()))
-        .add_service(services::post::PostServer::new(gateway.clone()))
-        .add_service(services::media::MediaServer::new(gateway.clone()))
-        .add_service(services::chat::ChatServer::new(gateway.clone()))
+        .add_service(services::auth::auth_service_server::AuthServiceServer::new(gateway.clone()))
+        .add_service(services::user::user_service_server::UserServiceServer::new(gateway.clone()))
+        .add_service(services::post::post_service_server::PostServiceServer::new(gateway.clone()))
+        .add_service(services::media::media_service_server::MediaServiceServer::new(gateway.clone()))
+        .add_service(services::chat::chat_service_server::ChatServiceServer::new(gateway.clone()))
+        .add_service(services::page::page_service_server::PageServiceServer::new(gateway.clone()))
+        .add_service(reflection)
         // Add health service
-        .add_service(tonic_health::server::HealthServer::new(
-            tonic_health::server::HealthReporter::new(),
-        ))
-        .serve(addr)
-        .await?;
+        .add_service(health_service)
+        .serve_with_incoming_shutdown(tls::incoming(listener, grpc_tls, &shutdown), shutdown.started());
+
+    // Serve until told to stop
+    let mut server = tokio::spawn(server);
+    tokio::select! {
+        served = &mut server => return served?.map_err(Into::into),
+        signal = shutdown::signal_received() => signal?,
+    }
+
+    // Load balancers route around the gateway once health checks fail, so new connections
+    // stop arriving before the listeners close
+    info!("Reporting NOT_SERVING for {:?} before closing listeners", drain_delay);
+    prober.drain().await;
+    tokio::time::sleep(drain_delay).await;
 
+    // Closes the listeners, sends GOAWAY on HTTP/2 connections and ends open streams;
+    // unary calls in flight finish within the grace period
+    shutdown.start();
+    let drained = async {
+        let served = server.await;
+        if let Some(websocket) = websocket {
+            let _ = websocket.await;
+        }
+        served
+    };
+    match tokio::time::timeout(grace_period, drained).await {
+        Ok(served) => {
+            served??;
+            info!("All connections drained");
+        }
+        Err(_) => warn!("Grace period of {:?} elapsed, dropping the remaining calls", grace_period),
+    }
+
+    // Prometheus pulls metrics, so the exporter stays up long enough for a last scrape
+    if !metrics_flush.is_zero() {
+        info!("Serving metrics for another {:?} before exiting", metrics_flush);
+        tokio::time::sleep(metrics_flush).await;
+    }
+    telemetry::shutdown();
+    info!("Gateway stopped");
     Ok(())
-}
\ No newline at end of file
+}
+
+// Browsers need CORS for both gRPC-Web and REST; grpc-* trailers are exposed so gRPC-Web
+// clients can read call status
+fn cors_layer(allowed_origins: &[String]) -> Result<CorsLayer> {
+    let origins = allowed_origins
+        .iter()
+        .map(|origin| HeaderValue::from_str(origin))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE])
+        .allow_headers([
+            HeaderName::from_static("authorization"),
+            HeaderName::from_static("content-type"),
+            HeaderName::from_static("x-grpc-web"),
+            HeaderName::from_static("x-user-agent"),
+            HeaderName::from_static("grpc-timeout"),
+            HeaderName::from_static("traceparent"),
+            HeaderName::from_static("tracestate"),
+            HeaderName::from_static("x-request-id"),
+        ])
+        .expose_headers([
+            HeaderName::from_static("grpc-status"),
+            HeaderName::from_static("grpc-message"),
+            HeaderName::from_static("grpc-status-details-bin"),
+            HeaderName::from_static("retry-after"),
+            HeaderName::from_static("x-request-id"),
+        ])
+        .allow_credentials(true)
+        .max_age(Duration::from_secs(3600)))
+}
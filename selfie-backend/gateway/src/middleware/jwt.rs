@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Context, Result};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, errors::ErrorKind, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::config::AuthConfig;
+use crate::error::GatewayError;
+
+const ACCESS_TOKEN_TYPE: &str = "access";
+
+// Bounds a JWKS fetch, so a hung auth-service can't stall the refresh loop
+const JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Mirrors the claims issued by auth-service's JwtService
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    exp: u64,
+    #[serde(rename = "type")]
+    token_type: String,
+}
+
+/// Verifies auth-service access tokens locally against its Ed25519 public keys, read from
+/// `auth.public_key_path`, fetched from `auth.jwks_url`, or both.
+pub struct TokenVerifier {
+    key_set: RwLock<KeySet>,
+    client: reqwest::Client,
+}
+
+/// Public keys and validation settings, as loaded by `TokenVerifier::load_keys`.
+pub struct KeySet {
+    // From `public_key_path`, read on load
+    file_keys: HashMap<String, DecodingKey>,
+    // From `jwks_url`, replaced by every successful fetch. auth-service lists retired keys
+    // until their grace period is over, so they drop out here when it ends.
+    jwks_keys: HashMap<String, DecodingKey>,
+    jwks_url: Option<String>,
+    jwks_refresh: Duration,
+    validation: Validation,
+}
+
+impl TokenVerifier {
+    pub fn from_config(config: &AuthConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(JWKS_FETCH_TIMEOUT)
+            .build()
+            .context("failed to build the JWKS client")?;
+
+        Ok(Self {
+            key_set: RwLock::new(KeySet::load(config)?),
+            client,
+        })
+    }
+
+    /// Re-reads the public key files and settings for `replace_keys`.
+    pub fn load_keys(config: &AuthConfig) -> Result<KeySet> {
+        KeySet::load(config)
+    }
+
+    /// Verifies tokens against `key_set` from now on. Keys fetched from an unchanged JWKS
+    /// URL stay in use until the next fetch.
+    pub fn replace_keys(&self, mut key_set: KeySet) {
+        let mut current = self.key_set.write().expect("JWT key set poisoned");
+        if key_set.jwks_url == current.jwks_url {
+            key_set.jwks_keys = std::mem::take(&mut current.jwks_keys);
+        }
+        *current = key_set;
+    }
+
+    /// Fetches the JWKS every `auth.jwks_refresh_secs`. A failed fetch keeps the keys from
+    /// the last one.
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            let refresh = self.key_set.read().expect("JWT key set poisoned").jwks_refresh;
+            tokio::time::sleep(refresh).await;
+            if let Err(err) = self.refresh_jwks().await {
+                warn!("Keeping the current JWT verification keys: {:#}", err);
+            }
+        }
+    }
+
+    /// Replaces the keys from `auth.jwks_url` with the ones it lists now.
+    pub async fn refresh_jwks(&self) -> Result<()> {
+        let Some(url) = self.key_set.read().expect("JWT key set poisoned").jwks_url.clone() else {
+            return Ok(());
+        };
+
+        let jwks: JwkSet = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("failed to fetch {}", url))?
+            .json()
+            .await
+            .with_context(|| format!("invalid JWKS from {}", url))?;
+        let keys = jwks_keys(&jwks);
+        if keys.is_empty() {
+            bail!("no usable keys in the JWKS from {}", url);
+        }
+
+        let mut key_set = self.key_set.write().expect("JWT key set poisoned");
+        // The URL may have changed on reload while the fetch was out
+        if key_set.jwks_url.as_deref() == Some(url.as_str()) {
+            let mut kids: Vec<&str> = keys.keys().map(String::as_str).collect();
+            let mut known: Vec<&str> = key_set.jwks_keys.keys().map(String::as_str).collect();
+            kids.sort_unstable();
+            known.sort_unstable();
+            if kids != known {
+                info!("JWT verification keys from the JWKS: {}", kids.join(", "));
+            }
+            key_set.jwks_keys = keys;
+        }
+        Ok(())
+    }
+
+    /// Returns the user id carried by a valid, unexpired access token.
+    pub fn verify(&self, token: &str) -> Result<String, GatewayError> {
+        self.verify_until(token).map(|(user_id, _)| user_id)
+    }
+
+    /// Like `verify`, also returning when the token expires, for connections that outlive
+    /// the request that authenticated them.
+    pub fn verify_until(&self, token: &str) -> Result<(String, SystemTime), GatewayError> {
+        let claims = self.key_set.read().expect("JWT key set poisoned").verify(token)?;
+        Ok((claims.sub, UNIX_EPOCH + Duration::from_secs(claims.exp)))
+    }
+}
+
+impl KeySet {
+    fn load(config: &AuthConfig) -> Result<Self> {
+        let mut file_keys = HashMap::new();
+
+        // A directory holds one PEM per key id, a single file is keyed by its stem
+        if let Some(key_path) = &config.public_key_path {
+            let path = Path::new(key_path);
+            if path.is_dir() {
+                for entry in std::fs::read_dir(path)? {
+                    let entry_path = entry?.path();
+                    if entry_path.extension().and_then(|ext| ext.to_str()) == Some("pem") {
+                        let (kid, key) = load_public_key(&entry_path)?;
+                        file_keys.insert(kid, key);
+                    }
+                }
+            } else {
+                let (kid, key) = load_public_key(path)?;
+                file_keys.insert(kid, key);
+            }
+
+            // Without a JWKS to fall back on, no keys means no token can be verified
+            if file_keys.is_empty() && config.jwks_url.is_none() {
+                bail!("no JWT public keys found at {}", key_path);
+            }
+            info!("Loaded {} JWT verification key(s) from {}", file_keys.len(), key_path);
+        }
+
+        let mut validation = Validation::new(Algorithm::EdDSA);
+        validation.set_required_spec_claims(&["sub", "exp", "iat", "jti", "type"]);
+        validation.leeway = config.leeway_secs;
+
+        Ok(Self {
+            file_keys,
+            jwks_keys: HashMap::new(),
+            jwks_url: config.jwks_url.clone(),
+            jwks_refresh: Duration::from_secs(config.jwks_refresh_secs),
+            validation,
+        })
+    }
+
+    fn key(&self, kid: &str) -> Option<&DecodingKey> {
+        self.file_keys.get(kid).or_else(|| self.jwks_keys.get(kid))
+    }
+
+    fn verify(&self, token: &str) -> Result<Claims, GatewayError> {
+        let header = decode_header(token)
+            .map_err(|_| GatewayError::AuthenticationFailed("Malformed token".to_string()))?;
+
+        let claims = match header.kid.as_deref() {
+            Some(kid) => {
+                let key = self.key(kid).ok_or_else(|| {
+                    GatewayError::AuthenticationFailed("Unknown signing key".to_string())
+                })?;
+                self.decode_with(token, key)?
+            }
+            // Tokens without a key id are tried against every known key
+            None => self
+                .file_keys
+                .values()
+                .chain(self.jwks_keys.values())
+                .find_map(|key| self.decode_with(token, key).ok())
+                .ok_or_else(|| GatewayError::AuthenticationFailed("Invalid token".to_string()))?,
+        };
+
+        if claims.token_type != ACCESS_TOKEN_TYPE {
+            return Err(GatewayError::AuthenticationFailed("Not an access token".to_string()));
+        }
+
+        Ok(claims)
+    }
+
+    fn decode_with(&self, token: &str, key: &DecodingKey) -> Result<Claims, GatewayError> {
+        decode::<Claims>(token, key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|err| match err.kind() {
+                ErrorKind::ExpiredSignature => {
+                    GatewayError::AuthenticationFailed("Token expired".to_string())
+                }
+                _ => GatewayError::AuthenticationFailed("Invalid token".to_string()),
+            })
+    }
+}
+
+fn load_public_key(path: &Path) -> Result<(String, DecodingKey)> {
+    let pem = std::fs::read(path)
+        .with_context(|| format!("failed to read JWT public key {}", path.display()))?;
+    let key = DecodingKey::from_ed_pem(&pem)
+        .with_context(|| format!("invalid Ed25519 public key {}", path.display()))?;
+    let kid = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default()
+        .to_string();
+
+    Ok((kid, key))
+}
+
+// The keys in a JWKS by `kid`; keys without one, or that don't decode, are skipped
+fn jwks_keys(jwks: &JwkSet) -> HashMap<String, DecodingKey> {
+    jwks.keys
+        .iter()
+        .filter_map(|jwk| {
+            let kid = jwk.common.key_id.clone()?;
+            match DecodingKey::from_jwk(jwk) {
+                Ok(key) => Some((kid, key)),
+                Err(err) => {
+                    warn!("Skipping JWKS key {}: {}", kid, err);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use ed25519_dalek::pkcs8::spki::der::pem::LineEnding;
+    use ed25519_dalek::pkcs8::EncodePrivateKey;
+    use ed25519_dalek::SigningKey;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use rand::rngs::OsRng;
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // A signing key as auth-service generates it, with its JWKS entry
+    struct TestKey {
+        kid: String,
+        signing_key: SigningKey,
+    }
+
+    impl TestKey {
+        fn new(kid: &str) -> Self {
+            Self {
+                kid: kid.to_string(),
+                signing_key: SigningKey::generate(&mut OsRng),
+            }
+        }
+
+        fn jwk(&self) -> serde_json::Value {
+            json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "alg": "EdDSA",
+                "use": "sig",
+                "kid": self.kid,
+                "x": URL_SAFE_NO_PAD.encode(self.signing_key.verifying_key().to_bytes()),
+            })
+        }
+
+        fn access_token(&self, user_id: &str) -> String {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let claims = json!({
+                "sub": user_id,
+                "iat": now,
+                "exp": now + 900,
+                "jti": uuid::Uuid::new_v4().to_string(),
+                "type": ACCESS_TOKEN_TYPE,
+            });
+            let mut header = Header::new(Algorithm::EdDSA);
+            header.kid = Some(self.kid.clone());
+            let pem = self.signing_key.to_pkcs8_pem(LineEnding::LF).unwrap();
+            encode(&header, &claims, &EncodingKey::from_ed_pem(pem.as_bytes()).unwrap()).unwrap()
+        }
+    }
+
+    // Serves whatever `jwks` holds at the time of each request
+    async fn serve_jwks(jwks: Arc<Mutex<String>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).await;
+                let body = jwks.lock().unwrap().clone();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://{}/.well-known/jwks.json", addr)
+    }
+
+    fn publish(jwks: &Mutex<String>, keys: &[&TestKey]) {
+        let keys: Vec<_> = keys.iter().map(|key| key.jwk()).collect();
+        *jwks.lock().unwrap() = json!({ "keys": keys }).to_string();
+    }
+
+    fn config(jwks_url: &str) -> AuthConfig {
+        AuthConfig {
+            public_key_path: None,
+            jwks_url: Some(jwks_url.to_string()),
+            jwks_refresh_secs: 60,
+            leeway_secs: 0,
+            public_methods: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_key_rotation_through_the_jwks() {
+        let old = TestKey::new("1700000000-0000aaaa");
+        let new = TestKey::new("1702592000-0000bbbb");
+        let jwks = Arc::new(Mutex::new(String::new()));
+        let url = serve_jwks(jwks.clone()).await;
+
+        publish(&jwks, &[&old]);
+        let verifier = TokenVerifier::from_config(&config(&url)).unwrap();
+        verifier.refresh_jwks().await.unwrap();
+        assert_eq!(verifier.verify(&old.access_token("alice")).unwrap(), "alice");
+        assert!(verifier.verify(&new.access_token("alice")).is_err());
+
+        // auth-service publishes the new key ahead of signing with it and keeps listing the
+        // old one through its grace period
+        publish(&jwks, &[&old, &new]);
+        verifier.refresh_jwks().await.unwrap();
+        assert_eq!(verifier.verify(&new.access_token("bob")).unwrap(), "bob");
+        assert_eq!(verifier.verify(&old.access_token("alice")).unwrap(), "alice");
+
+        // Once the grace period is over the old key is gone
+        publish(&jwks, &[&new]);
+        verifier.refresh_jwks().await.unwrap();
+        assert!(verifier.verify(&old.access_token("alice")).is_err());
+        assert_eq!(verifier.verify(&new.access_token("bob")).unwrap(), "bob");
+    }
+
+    #[tokio::test]
+    async fn failed_refresh_keeps_the_last_keys() {
+        let key = TestKey::new("1700000000-0000aaaa");
+        let jwks = Arc::new(Mutex::new(String::new()));
+        let url = serve_jwks(jwks.clone()).await;
+
+        publish(&jwks, &[&key]);
+        let verifier = TokenVerifier::from_config(&config(&url)).unwrap();
+        verifier.refresh_jwks().await.unwrap();
+
+        publish(&jwks, &[]);
+        assert!(verifier.refresh_jwks().await.is_err());
+        *jwks.lock().unwrap() = "not json".to_string();
+        assert!(verifier.refresh_jwks().await.is_err());
+
+        assert_eq!(verifier.verify(&key.access_token("alice")).unwrap(), "alice");
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_fetched_keys_for_the_same_jwks() {
+        let key = TestKey::new("1700000000-0000aaaa");
+        let jwks = Arc::new(Mutex::new(String::new()));
+        let url = serve_jwks(jwks.clone()).await;
+
+        publish(&jwks, &[&key]);
+        let verifier = TokenVerifier::from_config(&config(&url)).unwrap();
+        verifier.refresh_jwks().await.unwrap();
+
+        verifier.replace_keys(TokenVerifier::load_keys(&config(&url)).unwrap());
+        assert!(verifier.verify(&key.access_token("alice")).is_ok());
+
+        verifier.replace_keys(TokenVerifier::load_keys(&config("http://127.0.0.1:1/jwks.json")).unwrap());
+        assert!(verifier.verify(&key.access_token("alice")).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use anyhow::{Context as _, Result};
+use bytes::Bytes;
+use metrics::{counter, decrement_gauge, histogram, increment_gauge};
+use prost_reflect::DescriptorPool;
+use tonic::body::BoxBody;
+use tonic::codegen::http::{HeaderMap, Request, Response};
+use tonic::codegen::Body;
+use tonic::{Code, Status};
+use tower::{Layer, Service};
+
+// Services served by the gateway that aren't in its own descriptor set
+const BUILTIN_SERVICES: &[&str] = &["grpc.health.v1.Health", "grpc.reflection.v1alpha.ServerReflection"];
+
+/// `service` and `method` labels for a request path, for any metric labelled by call.
+#[derive(Clone)]
+pub struct MethodLabels {
+    known_services: Arc<HashSet<String>>,
+}
+
+impl MethodLabels {
+    pub fn from_descriptor_set(bytes: &[u8]) -> Result<Self> {
+        let pool = DescriptorPool::decode(bytes).context("invalid file descriptor set")?;
+        let mut known_services: HashSet<String> = pool.services().map(|service| service.full_name().to_string()).collect();
+        known_services.extend(BUILTIN_SERVICES.iter().map(|service| service.to_string()));
+
+        Ok(Self {
+            known_services: Arc::new(known_services),
+        })
+    }
+
+    // Unknown paths share one label set so scanners can't blow up series cardinality
+    pub fn labels(&self, path: &str) -> (String, String) {
+        match path.trim_start_matches('/').split_once('/') {
+            Some((service, method)) if self.known_services.contains(service) => {
+                (service.to_string(), method.to_string())
+            }
+            _ => ("unknown".to_string(), "unknown".to_string()),
+        }
+    }
+}
+
+/// Request rate, errors by gRPC status code, latency and in-flight calls per service and
+/// method. A call is measured until its response body finishes, so streams count their full
+/// duration and errors sent in trailers are attributed correctly.
+#[derive(Clone)]
+pub struct MetricsLayer {
+    method_labels: MethodLabels,
+}
+
+impl MetricsLayer {
+    pub fn new(method_labels: MethodLabels) -> Self {
+        Self { method_labels }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsMiddleware<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        MetricsMiddleware {
+            inner: service,
+            method_labels: self.method_labels.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricsMiddleware<S> {
+    inner: S,
+    method_labels: MethodLabels,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for MetricsMiddleware<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let (service, method) = self.method_labels.labels(req.uri().path());
+        let call = CallMetrics::start(service, method);
+
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            match fut.await {
+                Ok(response) => {
+                    let (parts, body) = response.into_parts();
+                    // Trailers-only responses (errors from the gateway or a unary upstream call)
+                    // carry the status in the headers
+                    let code = status_code(&parts.headers);
+                    let body = MeteredBody { inner: body, call: Some(call), code };
+                    Ok(Response::from_parts(parts, body.boxed_unsync()))
+                }
+                Err(err) => {
+                    call.finish(Code::Unavailable);
+                    Err(err)
+                }
+            }
+        })
+    }
+}
+
+struct CallMetrics {
+    service: String,
+    method: String,
+    start: Instant,
+}
+
+impl CallMetrics {
+    fn start(service: String, method: String) -> Self {
+        increment_gauge!("gateway_requests_in_flight", 1.0, "service" => service.clone(), "method" => method.clone());
+        Self {
+            service,
+            method,
+            start: Instant::now(),
+        }
+    }
+
+    fn finish(self, code: Code) {
+        let duration_ms = self.start.elapsed().as_secs_f64() * 1000.0;
+        let code = code_label(code);
+        let labels = [
+            ("service", self.service.clone()),
+            ("method", self.method.clone()),
+            ("code", code.to_string()),
+        ];
+
+        decrement_gauge!("gateway_requests_in_flight", 1.0, "service" => self.service, "method" => self.method);
+        counter!("gateway_requests_total", 1, &labels);
+        histogram!("gateway_request_duration_ms", duration_ms, &labels);
+        if code != "OK" {
+            counter!("gateway_request_errors_total", 1, &labels);
+        }
+    }
+}
+
+/// Response body that records the call's metrics once it has been fully sent or dropped.
+struct MeteredBody {
+    inner: BoxBody,
+    call: Option<CallMetrics>,
+    code: Option<Code>,
+}
+
+impl Body for MeteredBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Status>>> {
+        let poll = Pin::new(&mut self.inner).poll_data(cx);
+        if let Poll::Ready(Some(Err(status))) = &poll {
+            self.code = Some(status.code());
+        }
+        poll
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, Status>> {
+        let poll = Pin::new(&mut self.inner).poll_trailers(cx);
+        match &poll {
+            Poll::Ready(Ok(Some(trailers))) => {
+                if let Some(code) = status_code(trailers) {
+                    self.code = Some(code);
+                }
+            }
+            Poll::Ready(Err(status)) => self.code = Some(status.code()),
+            _ => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+}
+
+impl Drop for MeteredBody {
+    fn drop(&mut self) {
+        if let Some(call) = self.call.take() {
+            // No status seen means the client went away before the call completed
+            call.finish(self.code.unwrap_or(Code::Cancelled));
+        }
+    }
+}
+
+fn status_code(headers: &HeaderMap) -> Option<Code> {
+    headers
+        .get("grpc-status")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i32>().ok())
+        .map(Code::from_i32)
+}
+
+/// Prometheus label for a gRPC status code, e.g. `RESOURCE_EXHAUSTED`.
+pub fn code_label(code: Code) -> &'static str {
+    match code {
+        Code::Ok => "OK",
+        Code::Cancelled => "CANCELLED",
+        Code::Unknown => "UNKNOWN",
+        Code::InvalidArgument => "INVALID_ARGUMENT",
+        Code::DeadlineExceeded => "DEADLINE_EXCEEDED",
+        Code::NotFound => "NOT_FOUND",
+        Code::AlreadyExists => "ALREADY_EXISTS",
+        Code::PermissionDenied => "PERMISSION_DENIED",
+        Code::ResourceExhausted => "RESOURCE_EXHAUSTED",
+        Code::FailedPrecondition => "FAILED_PRECONDITION",
+        Code::Aborted => "ABORTED",
+        Code::OutOfRange => "OUT_OF_RANGE",
+        Code::Unimplemented => "UNIMPLEMENTED",
+        Code::Internal => "INTERNAL",
+        Code::Unavailable => "UNAVAILABLE",
+        Code::DataLoss => "DATA_LOSS",
+        Code::Unauthenticated => "UNAUTHENTICATED",
+    }
+}
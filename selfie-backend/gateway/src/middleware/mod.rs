@@ -1,36 +1,95 @@
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
 use std::task::{Context, Poll};
 use std::pin::Pin;
 use std::future::Future;
 use tower::{Layer, Service};
-use tonic::{Request, Status};
+use tonic::body::BoxBody;
+use tonic::codegen::http::{Request, Response};
+use tonic::Status;
 use tracing::{info, warn, error};
-use metrics::{counter, histogram};
+use ::metrics::counter;
+
+use crate::error::GatewayError;
+
+pub mod jwt;
+pub mod metrics;
+pub mod trace;
+
+pub use jwt::TokenVerifier;
+pub use self::metrics::{MethodLabels, MetricsLayer};
+pub use trace::TraceContextLayer;
+
+/// gRPC methods reachable without an access token, as configured in `auth.public_methods`.
+pub struct PublicMethods {
+    methods: RwLock<HashSet<String>>,
+}
+
+impl PublicMethods {
+    pub fn new(methods: &[String]) -> Self {
+        Self {
+            methods: RwLock::new(methods.iter().cloned().collect()),
+        }
+    }
+
+    pub fn reload(&self, methods: &[String]) {
+        *self.methods.write().expect("public methods poisoned") = methods.iter().cloned().collect();
+    }
+
+    fn contains(&self, path: &str) -> bool {
+        self.methods.read().expect("public methods poisoned").contains(path)
+    }
+}
+
+/// Identity of the caller, attached to the request extensions once the access token is verified.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser {
+    pub user_id: String,
+}
 
 #[derive(Clone)]
 pub struct AuthMiddleware<S> {
     inner: S,
+    verifier: Arc<TokenVerifier>,
+    public_methods: Arc<PublicMethods>,
+    method_labels: MethodLabels,
 }
 
 impl<S> AuthMiddleware<S> {
-    pub fn new(inner: S) -> Self {
-        Self { inner }
+    pub fn new(
+        inner: S,
+        verifier: Arc<TokenVerifier>,
+        public_methods: Arc<PublicMethods>,
+        method_labels: MethodLabels,
+    ) -> Self {
+        Self { inner, verifier, public_methods, method_labels }
     }
 }
 
 #[derive(Clone)]
-pub struct AuthMiddlewareLayer;
+pub struct AuthMiddlewareLayer {
+    verifier: Arc<TokenVerifier>,
+    public_methods: Arc<PublicMethods>,
+    method_labels: MethodLabels,
+}
+
+impl AuthMiddlewareLayer {
+    pub fn new(verifier: Arc<TokenVerifier>, public_methods: Arc<PublicMethods>, method_labels: MethodLabels) -> Self {
+        Self { verifier, public_methods, method_labels }
+    }
+}
 
 impl<S> Layer<S> for AuthMiddlewareLayer {
     type Service = AuthMiddleware<S>;
 
     fn layer(&self, service: S) -> Self::Service {
-        AuthMiddleware::new(service)
+        AuthMiddleware::new(service, self.verifier.clone(), self.public_methods.clone(), self.method_labels.clone())
     }
 }
 
 impl<S, ReqBody> Service<Request<ReqBody>> for AuthMiddleware<S>
 where
-    S: Service<Request<ReqBody>>,
+    S: Service<Request<ReqBody>, Response = Response<BoxBody>>,
     S::Future: Send + 'static,
 {
     type Response = S::Response;
@@ -42,46 +101,37 @@ where
     }
 
     fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
-        let start = std::time::Instant::now();
         let path = req.uri().path().to_string();
 
-        // Skip auth for health check and non-authenticated endpoints
-        if path.contains("/health") || path.contains("/auth/login") || path.contains("/auth/register") {
-            let fut = self.inner.call(req);
-            return Box::pin(async move {
-                let result = fut.await;
-                let duration = start.elapsed();
-                histogram!("request_duration_ms", duration.as_millis() as f64, "path" => path);
-                result
-            });
+        // Skip auth for health checks and the unauthenticated auth-service RPCs
+        if self.public_methods.contains(&path) {
+            return Box::pin(self.inner.call(req));
         }
 
         // Verify auth token
-        let auth_result = req.metadata()
+        let auth_result = req.headers()
             .get("authorization")
             .and_then(|v| v.to_str().ok())
-            .and_then(|v| v.strip_prefix("Bearer "));
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or_else(|| {
+                GatewayError::AuthenticationFailed("Missing or invalid authentication token".to_string())
+            })
+            .and_then(|token| self.verifier.verify(token));
 
+        let (service, method) = self.method_labels.labels(&path);
         match auth_result {
-            Some(token) => {
-                // Add token validation here
-                info!("Request authenticated with token");
-                counter!("requests_authenticated_total", "path" => path.clone());
-                
-                let fut = self.inner.call(req);
-                Box::pin(async move {
-                    let result = fut.await;
-                    let duration = start.elapsed();
-                    histogram!("request_duration_ms", duration.as_millis() as f64, "path" => path);
-                    result
-                })
+            Ok(user_id) => {
+                info!("Request authenticated for user {}", user_id);
+                counter!("requests_authenticated_total", 1, "service" => service, "method" => method);
+                req.extensions_mut().insert(AuthenticatedUser { user_id });
+
+                Box::pin(self.inner.call(req))
             }
-            None => {
-                warn!("Unauthenticated request to {}", path);
-                counter!("requests_unauthenticated_total", "path" => path);
-                Box::pin(async move {
-                    Err(Status::unauthenticated("Missing or invalid authentication token").into())
-                })
+            Err(err) => {
+                warn!("Unauthenticated request to {}: {}", path, err);
+                counter!("requests_unauthenticated_total", 1, "service" => service, "method" => method);
+                let status: Status = err.into();
+                Box::pin(async move { Ok(status.to_http()) })
             }
         }
     }
@@ -113,6 +163,7 @@ impl<S> Layer<S> for LoggingMiddlewareLayer {
 impl<S, ReqBody> Service<Request<ReqBody>> for LoggingMiddleware<S>
 where
     S: Service<Request<ReqBody>>,
+    S::Error: std::fmt::Display,
     S::Future: Send + 'static,
 {
     type Response = S::Response;
@@ -125,37 +176,21 @@ where
 
     fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
         let start = std::time::Instant::now();
-        let path = req.uri().path().to_string();
-        let method = req.method().clone();
 
-        info!(
-            "Request started: {} {}",
-            method,
-            path
-        );
+        // Method, path and the correlation ids come from the enclosing request span
+        info!("Request started");
 
         let fut = self.inner.call(req);
         Box::pin(async move {
             let result = fut.await;
-            let duration = start.elapsed();
+            let duration_ms = start.elapsed().as_millis() as u64;
             
             match &result {
                 Ok(_) => {
-                    info!(
-                        "Request completed: {} {} ({:?})",
-                        method,
-                        path,
-                        duration
-                    );
+                    info!(duration_ms, "Request completed");
                 }
                 Err(e) => {
-                    error!(
-                        "Request failed: {} {} ({:?}) - Error: {}",
-                        method,
-                        path,
-                        duration,
-                        e
-                    );
+                    error!(duration_ms, error = %e, "Request failed");
                 }
             }
             
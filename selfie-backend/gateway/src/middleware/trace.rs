@@ -0,0 +1,85 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tonic::codegen::http::{HeaderValue, Request, Response};
+use tower::{Layer, Service};
+use tracing::{field, info_span, Instrument};
+
+use crate::telemetry;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Client-supplied ids longer than this are replaced rather than propagated
+pub const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Opens the request span, continuing the caller's `traceparent` when present, and makes sure
+/// every request carries an `x-request-id`. Both end up in the headers forwarded upstream and
+/// the request id is echoed on the response.
+#[derive(Clone)]
+pub struct TraceContextLayer;
+
+impl<S> Layer<S> for TraceContextLayer {
+    type Service = TraceContext<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        TraceContext { inner: service }
+    }
+}
+
+#[derive(Clone)]
+pub struct TraceContext<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for TraceContext<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .filter(|value| !value.is_empty() && value.len() <= MAX_REQUEST_ID_LEN)
+            .cloned()
+            .unwrap_or_else(|| {
+                HeaderValue::from_str(&uuid::Uuid::new_v4().to_string()).expect("uuid is a valid header value")
+            });
+        req.headers_mut().insert(REQUEST_ID_HEADER, request_id.clone());
+
+        let span = info_span!(
+            "request",
+            otel.name = req.uri().path(),
+            method = %req.method(),
+            path = req.uri().path(),
+            request_id = request_id.to_str().unwrap_or_default(),
+            trace_id = field::Empty,
+        );
+        telemetry::set_remote_parent(&span, req.headers());
+        span.record("trace_id", telemetry::trace_id(&span));
+
+        let fut = {
+            let _entered = span.enter();
+            self.inner.call(req)
+        };
+
+        Box::pin(
+            async move {
+                let mut result = fut.await;
+                if let Ok(response) = &mut result {
+                    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+                }
+                result
+            }
+            .instrument(span),
+        )
+    }
+}
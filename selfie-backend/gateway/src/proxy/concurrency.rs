@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use metrics::{counter, gauge};
+use tracing::debug;
+
+use crate::config::ConcurrencyConfig;
+use super::resilience::Upstream;
+
+// Weight of each answered call in the service's usual latency, roughly the last 500 calls
+const LONG_RTT_WEIGHT: f64 = 0.002;
+// Weight of each answered call in its recent latency, roughly the last 10 calls
+const SHORT_RTT_WEIGHT: f64 = 0.2;
+// Share of each newly computed limit that is applied, so one slow burst doesn't halve it
+const SMOOTHING: f64 = 0.2;
+// Applied to the limit when a call times out or the service says it is overloaded
+const DROP_BACKOFF: f64 = 0.9;
+
+/// How readily a call is shed while its upstream is at its limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Priority {
+    Critical,
+    Normal,
+    Sheddable,
+}
+
+impl Priority {
+    // Part of the limit calls of this priority may fill; the rest is held back for more
+    // important calls, so the least important are turned away first
+    fn share(self) -> f64 {
+        match self {
+            Priority::Critical => 1.0,
+            Priority::Normal => 0.9,
+            Priority::Sheddable => 0.7,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Priority::Critical => "critical",
+            Priority::Normal => "normal",
+            Priority::Sheddable => "sheddable",
+        }
+    }
+}
+
+/// Limit bounds and RPC priorities from `ConcurrencyConfig`.
+pub(super) struct LimitPolicy {
+    enabled: bool,
+    min_limit: f64,
+    max_limit: f64,
+    rtt_tolerance: f64,
+    // Keyed by lowercased RPC name; everything else is `Normal`
+    priorities: HashMap<String, Priority>,
+}
+
+impl LimitPolicy {
+    pub(super) fn new(config: &ConcurrencyConfig) -> Self {
+        let critical = config.critical_rpcs.iter().map(|rpc| (rpc, Priority::Critical));
+        let sheddable = config.sheddable_rpcs.iter().map(|rpc| (rpc, Priority::Sheddable));
+
+        Self {
+            enabled: config.enabled,
+            min_limit: config.min_limit as f64,
+            max_limit: config.max_limit as f64,
+            rtt_tolerance: config.rtt_tolerance,
+            priorities: critical
+                .chain(sheddable)
+                .map(|(rpc, priority)| (rpc.to_ascii_lowercase(), priority))
+                .collect(),
+        }
+    }
+
+    pub(super) fn priority(&self, rpc: &str) -> Priority {
+        self.priorities
+            .get(&rpc.to_ascii_lowercase())
+            .copied()
+            .unwrap_or(Priority::Normal)
+    }
+}
+
+/// What a finished call says about the service's capacity.
+pub(super) enum Outcome {
+    /// The service answered, successfully or not; its latency is a sample
+    Answered,
+    /// The service ran out of time for the call or turned it away as overloaded
+    Dropped,
+    /// The call's duration says nothing about load, e.g. a client-streamed upload
+    Ignored,
+}
+
+/// Gradient concurrency limit for one upstream service. The limit tracks the ratio between
+/// the service's usual latency and its recent latency: while they match it grows by about
+/// its square root, and as recent calls slow down past `rtt_tolerance` it shrinks in
+/// proportion. Timeouts cut it multiplicatively.
+pub(super) struct ConcurrencyLimiter {
+    upstream: Upstream,
+    state: Mutex<LimitState>,
+}
+
+struct LimitState {
+    limit: f64,
+    in_flight: u32,
+    // Moving averages of answered calls' latency, in seconds
+    long_rtt: Option<f64>,
+    short_rtt: Option<f64>,
+}
+
+impl ConcurrencyLimiter {
+    pub(super) fn new(upstream: Upstream, config: &ConcurrencyConfig) -> Self {
+        let limiter = Self {
+            upstream,
+            state: Mutex::new(LimitState {
+                limit: config.initial_limit as f64,
+                in_flight: 0,
+                long_rtt: None,
+                short_rtt: None,
+            }),
+        };
+        gauge!("upstream_concurrency_limit", config.initial_limit as f64, "service" => upstream.as_str());
+        limiter
+    }
+
+    /// Takes a slot for a call of `priority`, or returns `None` if the service's limit
+    /// leaves no room for it.
+    pub(super) fn try_acquire(&self, policy: &LimitPolicy, priority: Priority) -> Option<Permit<'_>> {
+        let mut state = self.state.lock().expect("concurrency limiter poisoned");
+        if policy.enabled {
+            // Bounds may have changed on reload since the limit was last adjusted
+            let limit = state.limit.clamp(policy.min_limit, policy.max_limit);
+            let allowed = (limit * priority.share()).floor().max(1.0) as u32;
+            if state.in_flight >= allowed {
+                drop(state);
+                debug!("Shedding {} call to {} service", priority.as_str(), self.upstream.as_str());
+                counter!(
+                    "upstream_requests_shed_total", 1,
+                    "service" => self.upstream.as_str(),
+                    "priority" => priority.as_str()
+                );
+                return None;
+            }
+        }
+
+        state.in_flight += 1;
+        gauge!("upstream_in_flight", state.in_flight as f64, "service" => self.upstream.as_str());
+        Some(Permit {
+            limiter: self,
+            in_flight: state.in_flight,
+            started: Instant::now(),
+            released: false,
+        })
+    }
+
+    #[cfg(test)]
+    pub(super) fn limit(&self) -> f64 {
+        self.state.lock().unwrap().limit
+    }
+
+    fn release(&self, policy: Option<&LimitPolicy>, outcome: Outcome, in_flight: u32, rtt: Duration) {
+        let mut state = self.state.lock().expect("concurrency limiter poisoned");
+        state.in_flight -= 1;
+        gauge!("upstream_in_flight", state.in_flight as f64, "service" => self.upstream.as_str());
+
+        let Some(policy) = policy else {
+            return;
+        };
+        let limit = match outcome {
+            Outcome::Ignored => return,
+            Outcome::Dropped => state.limit * DROP_BACKOFF,
+            Outcome::Answered => match state.sample(policy, in_flight, rtt) {
+                Some(limit) => limit,
+                None => return,
+            },
+        };
+        state.limit = limit.clamp(policy.min_limit, policy.max_limit);
+        gauge!("upstream_concurrency_limit", state.limit, "service" => self.upstream.as_str());
+    }
+}
+
+impl LimitState {
+    // Folds an answered call into the latency averages and returns the limit it calls for,
+    // if it is evidence either way
+    fn sample(&mut self, policy: &LimitPolicy, in_flight: u32, rtt: Duration) -> Option<f64> {
+        let rtt = rtt.as_secs_f64().max(1e-6);
+        let short = self.short_rtt.map_or(rtt, |short| short + (rtt - short) * SHORT_RTT_WEIGHT);
+        let mut long = self.long_rtt.map_or(rtt, |long| long + (rtt - long) * LONG_RTT_WEIGHT);
+        // After a slow period the usual latency would take hundreds of calls to come back
+        // down, leaving the limit free to grow past what the service can take
+        if long / short > 2.0 {
+            long *= 0.95;
+        }
+        self.short_rtt = Some(short);
+        self.long_rtt = Some(long);
+
+        // A service doing far less than the limit allows says nothing about where it lies
+        if (in_flight as f64) < self.limit / 2.0 {
+            return None;
+        }
+
+        let gradient = (policy.rtt_tolerance * long / short).clamp(0.5, 1.0);
+        let target = self.limit * gradient + self.limit.sqrt();
+        Some(self.limit * (1.0 - SMOOTHING) + target * SMOOTHING)
+    }
+}
+
+/// A call's slot under its upstream's limit. Dropping it without `release`, as when the
+/// caller goes away, frees the slot without adjusting the limit.
+pub(super) struct Permit<'a> {
+    limiter: &'a ConcurrencyLimiter,
+    // Calls in flight, this one included, when it started
+    in_flight: u32,
+    started: Instant,
+    released: bool,
+}
+
+impl Permit<'_> {
+    pub(super) fn release(mut self, policy: &LimitPolicy, outcome: Outcome) {
+        self.released = true;
+        self.limiter.release(Some(policy), outcome, self.in_flight, self.started.elapsed());
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if !self.released {
+            self.limiter.release(None, Outcome::Ignored, self.in_flight, self.started.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(initial_limit: u32) -> ConcurrencyConfig {
+        ConcurrencyConfig {
+            initial_limit,
+            min_limit: 5,
+            max_limit: 100,
+            ..ConcurrencyConfig::default()
+        }
+    }
+
+    // Finishes a call that ran alongside `in_flight - 1` others and took `rtt_ms`
+    fn finish(limiter: &ConcurrencyLimiter, policy: &LimitPolicy, outcome: Outcome, in_flight: u32, rtt_ms: u64) {
+        limiter.state.lock().unwrap().in_flight += 1;
+        limiter.release(Some(policy), outcome, in_flight, Duration::from_millis(rtt_ms));
+    }
+
+    #[test]
+    fn grows_while_latency_holds_steady() {
+        let config = config(20);
+        let policy = LimitPolicy::new(&config);
+        let limiter = ConcurrencyLimiter::new(Upstream::Post, &config);
+
+        for _ in 0..50 {
+            let in_flight = limiter.limit() as u32;
+            finish(&limiter, &policy, Outcome::Answered, in_flight, 10);
+        }
+
+        assert!(limiter.limit() > 30.0, "limit {}", limiter.limit());
+        assert!(limiter.limit() <= 100.0);
+    }
+
+    #[test]
+    fn shrinks_as_recent_latency_rises() {
+        let config = config(40);
+        let policy = LimitPolicy::new(&config);
+        let limiter = ConcurrencyLimiter::new(Upstream::Post, &config);
+
+        for _ in 0..20 {
+            finish(&limiter, &policy, Outcome::Answered, 40, 10);
+        }
+        let before = limiter.limit();
+        for _ in 0..20 {
+            finish(&limiter, &policy, Outcome::Answered, 40, 100);
+        }
+
+        assert!(limiter.limit() < before, "limit {} was {}", limiter.limit(), before);
+        assert!(limiter.limit() >= 5.0);
+    }
+
+    #[test]
+    fn ignores_latency_while_far_below_the_limit() {
+        let config = config(40);
+        let policy = LimitPolicy::new(&config);
+        let limiter = ConcurrencyLimiter::new(Upstream::Post, &config);
+
+        for _ in 0..20 {
+            finish(&limiter, &policy, Outcome::Answered, 5, 100);
+        }
+
+        assert_eq!(limiter.limit(), 40.0);
+    }
+
+    #[test]
+    fn backs_off_on_dropped_calls_down_to_the_minimum() {
+        let config = config(20);
+        let policy = LimitPolicy::new(&config);
+        let limiter = ConcurrencyLimiter::new(Upstream::Post, &config);
+
+        finish(&limiter, &policy, Outcome::Dropped, 20, 10);
+        assert!((limiter.limit() - 20.0 * DROP_BACKOFF).abs() < 1e-9);
+
+        for _ in 0..50 {
+            finish(&limiter, &policy, Outcome::Dropped, 20, 10);
+        }
+        assert_eq!(limiter.limit(), 5.0);
+    }
+
+    #[test]
+    fn sheds_sheddable_then_normal_then_critical() {
+        let config = config(10);
+        let policy = LimitPolicy::new(&config);
+        let limiter = ConcurrencyLimiter::new(Upstream::Post, &config);
+        let mut permits = Vec::new();
+
+        // Shares of 10: sheddable 7, normal 9, critical 10
+        for _ in 0..7 {
+            permits.push(limiter.try_acquire(&policy, Priority::Sheddable).unwrap());
+        }
+        assert!(limiter.try_acquire(&policy, Priority::Sheddable).is_none());
+
+        for _ in 0..2 {
+            permits.push(limiter.try_acquire(&policy, Priority::Normal).unwrap());
+        }
+        assert!(limiter.try_acquire(&policy, Priority::Normal).is_none());
+
+        permits.push(limiter.try_acquire(&policy, Priority::Critical).unwrap());
+        assert!(limiter.try_acquire(&policy, Priority::Critical).is_none());
+
+        permits.pop();
+        assert!(limiter.try_acquire(&policy, Priority::Critical).is_some());
+    }
+
+    #[test]
+    fn dropped_permit_frees_its_slot_without_moving_the_limit() {
+        let config = config(10);
+        let policy = LimitPolicy::new(&config);
+        let limiter = ConcurrencyLimiter::new(Upstream::Post, &config);
+
+        let permit = limiter.try_acquire(&policy, Priority::Normal).unwrap();
+        assert_eq!(limiter.state.lock().unwrap().in_flight, 1);
+        drop(permit);
+
+        assert_eq!(limiter.state.lock().unwrap().in_flight, 0);
+        assert_eq!(limiter.limit(), 10.0);
+    }
+
+    #[test]
+    fn priorities_come_from_the_config() {
+        let policy = LimitPolicy::new(&ConcurrencyConfig::default());
+
+        assert_eq!(policy.priority("login"), Priority::Critical);
+        assert_eq!(policy.priority("SearchUsers"), Priority::Sheddable);
+        assert_eq!(policy.priority("GetPost"), Priority::Normal);
+    }
+}
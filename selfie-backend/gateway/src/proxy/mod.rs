@@ -1,47 +1,234 @@
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tonic::transport::Channel;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use tokio::sync::{mpsc, Mutex};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use tower::discover::Change;
+use tracing::{info, warn};
 
-pub mod auth;
-pub mod user;
-pub mod post;
-pub mod media;
-pub mod chat;
+use crate::config::{ServiceDiscoveryConfig, UpstreamConfig, UpstreamTlsConfig};
+use crate::services::{auth, chat, media, post, user};
 
+mod concurrency;
+pub mod resilience;
+
+pub use resilience::{Resilience, Upstream, UpstreamError};
+
+/// Upstream clients. Each wraps a load-balanced `Channel`, so cloning one is cheap and
+/// concurrent calls never wait on each other.
 #[derive(Clone)]
 pub struct ServiceProxies {
-    pub auth: Arc<RwLock<auth::AuthServiceClient>>,
-    pub user: Arc<RwLock<user::UserServiceClient>>,
-    pub post: Arc<RwLock<post::PostServiceClient>>,
-    pub media: Arc<RwLock<media::MediaServiceClient>>,
-    pub chat: Arc<RwLock<chat::ChatServiceClient>>,
+    pub auth: AuthServiceClient,
+    pub user: UserServiceClient,
+    pub post: PostServiceClient,
+    pub media: MediaServiceClient,
+    pub chat: ChatServiceClient,
+    pub resilience: Arc<Resilience>,
+    endpoints: Arc<Vec<EndpointSet>>,
 }
 
 impl ServiceProxies {
-    pub async fn new(config: &crate::config::ServiceDiscoveryConfig) -> Result<Self, tonic::transport::Error> {
+    pub async fn new(config: &ServiceDiscoveryConfig, upstream: &UpstreamConfig) -> Result<Self> {
+        let mut channels = Vec::new();
+        let mut endpoints = Vec::new();
+        for service in Upstream::ALL {
+            let (channel, set) = EndpointSet::new(service);
+            set.apply(set.prepare(config)?).await?;
+            channels.push(channel);
+            endpoints.push(set);
+        }
+
+        let [auth, user, post, media, chat]: [Channel; 5] =
+            channels.try_into().map_err(|_| anyhow!("one channel per upstream"))?;
+        Ok(Self {
+            auth: AuthServiceClient::new(auth),
+            user: UserServiceClient::new(user),
+            post: PostServiceClient::new(post),
+            media: MediaServiceClient::new(media),
+            chat: ChatServiceClient::new(chat),
+            resilience: Arc::new(Resilience::new(upstream)),
+            endpoints: Arc::new(endpoints),
+        })
+    }
+
+    /// Resolves every service's configured endpoints for `replace_endpoints`, reading their
+    /// TLS files.
+    pub fn load_endpoints(&self, discovery: &ServiceDiscoveryConfig) -> Result<Vec<EndpointUpdate>> {
+        self.endpoints.iter().map(|set| set.prepare(discovery)).collect()
+    }
+
+    /// Moves every client onto `updates` and applies the upstream policy. Calls already on a
+    /// removed endpoint run to completion on its connection.
+    pub async fn replace_endpoints(&self, updates: Vec<EndpointUpdate>, upstream: &UpstreamConfig) {
+        for (set, update) in self.endpoints.iter().zip(updates) {
+            if let Err(err) = set.apply(update).await {
+                warn!("{:#}", err);
+            }
+        }
+        self.resilience.reload(upstream);
+    }
+}
+
+/// How the gateway connects to one service's endpoints.
+#[derive(Clone, PartialEq)]
+pub(crate) struct EndpointSettings {
+    connect_timeout: Duration,
+    keepalive_interval: Duration,
+    tls: Option<ClientTls>,
+}
+
+impl EndpointSettings {
+    /// Reads the service's TLS files, so comparing settings also notices rotated certificates.
+    pub(crate) fn new(config: &ServiceDiscoveryConfig, upstream: Upstream) -> Result<Self> {
+        let tls = config
+            .tls(upstream)
+            .map(ClientTls::load)
+            .transpose()
+            .with_context(|| format!("TLS for {} service", upstream.as_str()))?;
+
+        Ok(Self {
+            connect_timeout: Duration::from_millis(config.connect_timeout_ms),
+            keepalive_interval: Duration::from_secs(config.keepalive_interval_secs),
+            tls,
+        })
+    }
+
+    // Endpoints connect lazily and reconnect on failure, so a backend that is down only
+    // fails the calls routed to it
+    pub(crate) fn endpoint(&self, url: &str) -> Result<Endpoint> {
+        let mut endpoint = Endpoint::from_shared(url.to_string())?
+            .connect_timeout(self.connect_timeout)
+            .tcp_keepalive(Some(self.keepalive_interval))
+            .http2_keep_alive_interval(self.keepalive_interval)
+            .keep_alive_while_idle(true);
+        if let Some(tls) = &self.tls {
+            endpoint = endpoint.tls_config(tls.config())?;
+        }
+        Ok(endpoint)
+    }
+}
+
+/// PEM material for mTLS to a service. Only the configured CA is trusted, which pins the
+/// service to certificates it issued.
+#[derive(Clone, PartialEq)]
+struct ClientTls {
+    ca: Vec<u8>,
+    identity: Option<(Vec<u8>, Vec<u8>)>,
+    domain_name: Option<String>,
+}
+
+impl ClientTls {
+    fn load(config: &UpstreamTlsConfig) -> Result<Self> {
+        let identity = match (&config.cert_path, &config.key_path) {
+            (Some(cert), Some(key)) => Some((read_pem(cert)?, read_pem(key)?)),
+            _ => None,
+        };
         Ok(Self {
-            auth: Arc::new(RwLock::new(
-                auth::AuthServiceClient::connect(config.auth_service.clone()).await?,
-            )),
-            user: Arc::new(RwLock::new(
-                user::UserServiceClient::connect(config.user_service.clone()).await?,
-            )),
-            post: Arc::new(RwLock::new(
-                post::PostServiceClient::connect(config.post_service.clone()).await?,
-            )),
-            media: Arc::new(RwLock::new(
-                media::MediaServiceClient::connect(config.media_service.clone()).await?,
-            )),
-            chat: Arc::new(RwLock::new(
-                chat::ChatServiceClient::connect(config.chat_service.clone()).await?,
-            )),
+            ca: read_pem(&config.ca_path)?,
+            identity,
+            domain_name: config.domain_name.clone(),
         })
     }
+
+    fn config(&self) -> ClientTlsConfig {
+        let mut config = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(&self.ca));
+        if let Some((cert, key)) = &self.identity {
+            config = config.identity(Identity::from_pem(cert, key));
+        }
+        if let Some(domain_name) = &self.domain_name {
+            config = config.domain_name(domain_name.clone());
+        }
+        config
+    }
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("failed to read {}", path))
+}
+
+/// One service's endpoints as configured, ready to hand to its balancer.
+pub struct EndpointUpdate {
+    urls: Vec<String>,
+    settings: EndpointSettings,
+    endpoints: Vec<(String, Endpoint)>,
+}
+
+/// The endpoints one service's channel balances across, keyed by URL.
+struct EndpointSet {
+    upstream: Upstream,
+    changes: mpsc::Sender<Change<String, Endpoint>>,
+    current: Mutex<Option<(Vec<String>, EndpointSettings)>>,
 }
 
+impl EndpointSet {
+    fn new(upstream: Upstream) -> (Channel, Self) {
+        let (channel, changes) = Channel::balance_channel(ENDPOINT_CHANGES_BUFFER);
+        let set = Self {
+            upstream,
+            changes,
+            current: Mutex::new(None),
+        };
+        (channel, set)
+    }
+
+    // Everything that can go wrong with the configuration, before the balancer is touched
+    fn prepare(&self, config: &ServiceDiscoveryConfig) -> Result<EndpointUpdate> {
+        let urls = config.endpoints(self.upstream);
+        if urls.is_empty() {
+            bail!("no endpoints configured for {} service", self.upstream.as_str());
+        }
+        let settings = EndpointSettings::new(config, self.upstream)?;
+        let endpoints = urls
+            .iter()
+            .map(|url| Ok((url.clone(), settings.endpoint(url)?)))
+            .collect::<Result<_>>()?;
+
+        Ok(EndpointUpdate {
+            urls: urls.to_vec(),
+            settings,
+            endpoints,
+        })
+    }
+
+    async fn apply(&self, update: EndpointUpdate) -> Result<()> {
+        let mut current = self.current.lock().await;
+        // Endpoints only pick up new settings by being replaced
+        let (live, refresh) = match current.as_ref() {
+            Some((live, live_settings)) => (live.as_slice(), *live_settings != update.settings),
+            None => (&[][..], true),
+        };
+        if live == update.urls && !refresh {
+            return Ok(());
+        }
+
+        for url in live.iter().filter(|url| !update.urls.contains(url)) {
+            self.send(Change::Remove(url.clone())).await?;
+        }
+        for (url, endpoint) in update.endpoints {
+            if refresh || !live.contains(&url) {
+                self.send(Change::Insert(url, endpoint)).await?;
+            }
+        }
+
+        info!("{} service endpoints: {}", self.upstream.as_str(), update.urls.join(", "));
+        *current = Some((update.urls, update.settings));
+        Ok(())
+    }
+
+    async fn send(&self, change: Change<String, Endpoint>) -> Result<()> {
+        self.changes
+            .send(change)
+            .await
+            .map_err(|_| anyhow!("{} service balancer has stopped", self.upstream.as_str()))
+    }
+}
+
+// Endpoint changes queued for a balancer before `update` waits on it
+const ENDPOINT_CHANGES_BUFFER: usize = 64;
+
 // Service client type definitions
-pub type AuthServiceClient = auth::AuthServiceClient<Channel>;
-pub type UserServiceClient = user::UserServiceClient<Channel>;
-pub type PostServiceClient = post::PostServiceClient<Channel>;
-pub type MediaServiceClient = media::MediaServiceClient<Channel>;
-pub type ChatServiceClient = chat::ChatServiceClient<Channel>;
\ No newline at end of file
+pub type AuthServiceClient = auth::auth_service_client::AuthServiceClient<Channel>;
+pub type UserServiceClient = user::user_service_client::UserServiceClient<Channel>;
+pub type PostServiceClient = post::post_service_client::PostServiceClient<Channel>;
+pub type MediaServiceClient = media::media_service_client::MediaServiceClient<Channel>;
+pub type ChatServiceClient = chat::chat_service_client::ChatServiceClient<Channel>;
@@ -0,0 +1,543 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use metrics::{counter, gauge, histogram};
+use rand::Rng;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Extensions, Request, Response, Status};
+use tracing::{debug, info_span, warn, Instrument};
+
+use crate::config::UpstreamConfig;
+use crate::error::GatewayError;
+use crate::middleware::metrics::code_label;
+use crate::telemetry;
+use super::concurrency::{ConcurrencyLimiter, LimitPolicy, Outcome};
+
+// Read-only RPCs that are safe to send more than once
+const IDEMPOTENT_RPCS: &[&str] = &[
+    "ValidateToken",
+    "GetUser",
+    "GetProfile",
+    "SearchUsers",
+    "GetBlockedUsers",
+    "GetPost",
+    "ListUserPosts",
+    "GetFeed",
+    "GetLikes",
+    "GetComments",
+    "GetMedia",
+    "GetChat",
+    "ListChats",
+    "GetMessages",
+];
+
+const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Upstream {
+    Auth,
+    User,
+    Post,
+    Media,
+    Chat,
+}
+
+impl Upstream {
+    pub const ALL: [Upstream; 5] = [Upstream::Auth, Upstream::User, Upstream::Post, Upstream::Media, Upstream::Chat];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Upstream::Auth => "auth",
+            Upstream::User => "user",
+            Upstream::Post => "post",
+            Upstream::Media => "media",
+            Upstream::Chat => "chat",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Upstream> {
+        Upstream::ALL.into_iter().find(|upstream| upstream.as_str().eq_ignore_ascii_case(name))
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    /// One probe call is in flight; its outcome closes or re-opens the breaker
+    HalfOpen { since: Instant },
+}
+
+/// Consecutive-failure circuit breaker for one upstream service. Thresholds come from the
+/// current `Policy`, so a reload also applies to breakers that are already open.
+pub struct CircuitBreaker {
+    upstream: Upstream,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    fn new(upstream: Upstream) -> Self {
+        let breaker = Self {
+            upstream,
+            state: Mutex::new(BreakerState::Closed { failures: 0 }),
+        };
+        breaker.export(BreakerState::Closed { failures: 0 });
+        breaker
+    }
+
+    /// Whether a call may go out now. After the open period a single probe is let through.
+    fn try_acquire(&self, policy: &Policy) -> bool {
+        let mut state = self.state.lock().expect("circuit breaker poisoned");
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } if Instant::now() >= until => {
+                *state = BreakerState::HalfOpen { since: Instant::now() };
+                self.export(*state);
+                true
+            }
+            // A probe whose caller went away never reports back, so it is replaced eventually
+            BreakerState::HalfOpen { since } if since.elapsed() >= policy.open_for => {
+                *state = BreakerState::HalfOpen { since: Instant::now() };
+                true
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => false,
+        }
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock().expect("circuit breaker poisoned");
+        if *state != (BreakerState::Closed { failures: 0 }) {
+            *state = BreakerState::Closed { failures: 0 };
+            self.export(*state);
+        }
+    }
+
+    fn record_failure(&self, policy: &Policy) {
+        let mut state = self.state.lock().expect("circuit breaker poisoned");
+        let next = match *state {
+            BreakerState::Closed { failures } if failures + 1 < policy.failure_threshold => {
+                BreakerState::Closed { failures: failures + 1 }
+            }
+            BreakerState::Closed { .. } | BreakerState::HalfOpen { .. } => {
+                warn!("Opening circuit for {} service", self.upstream.as_str());
+                BreakerState::Open { until: Instant::now() + policy.open_for }
+            }
+            open @ BreakerState::Open { .. } => open,
+        };
+        *state = next;
+        self.export(next);
+    }
+
+    // 0 = closed, 1 = half-open, 2 = open
+    fn export(&self, state: BreakerState) {
+        let value = match state {
+            BreakerState::Closed { .. } => 0.0,
+            BreakerState::HalfOpen { .. } => 1.0,
+            BreakerState::Open { .. } => 2.0,
+        };
+        gauge!("upstream_circuit_state", value, "service" => self.upstream.as_str());
+    }
+}
+
+/// Deadlines, retries, breaker thresholds and concurrency limits from `UpstreamConfig`.
+struct Policy {
+    default_timeout: Duration,
+    // Keyed by lowercased RPC name
+    rpc_timeouts: HashMap<String, Duration>,
+    deadline_margin: Duration,
+    max_attempts: u32,
+    retry_backoff: Duration,
+    retry_max_backoff: Duration,
+    failure_threshold: u32,
+    open_for: Duration,
+    concurrency: LimitPolicy,
+}
+
+impl Policy {
+    fn new(config: &UpstreamConfig) -> Self {
+        Self {
+            default_timeout: Duration::from_millis(config.default_timeout_ms),
+            rpc_timeouts: config
+                .rpc_timeout_ms
+                .iter()
+                .map(|(rpc, timeout)| (rpc.to_ascii_lowercase(), Duration::from_millis(*timeout)))
+                .collect(),
+            deadline_margin: Duration::from_millis(config.deadline_margin_ms),
+            max_attempts: config.max_attempts.max(1),
+            retry_backoff: Duration::from_millis(config.retry_backoff_ms),
+            retry_max_backoff: Duration::from_millis(config.retry_max_backoff_ms),
+            failure_threshold: config.breaker_failure_threshold,
+            open_for: Duration::from_secs(config.breaker_open_secs),
+            concurrency: LimitPolicy::new(&config.concurrency),
+        }
+    }
+
+    fn timeout(&self, rpc: &str) -> Duration {
+        self.rpc_timeouts
+            .get(&rpc.to_ascii_lowercase())
+            .copied()
+            .unwrap_or(self.default_timeout)
+    }
+
+    // Exponential backoff with full jitter
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .retry_backoff
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.retry_max_backoff);
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+/// Breakers, concurrency limits, retry policy and deadlines applied to every upstream call.
+pub struct Resilience {
+    breakers: Vec<CircuitBreaker>,
+    limiters: Vec<ConcurrencyLimiter>,
+    policy: RwLock<Arc<Policy>>,
+}
+
+impl Resilience {
+    pub fn new(config: &UpstreamConfig) -> Self {
+        Self {
+            breakers: Upstream::ALL.iter().map(|upstream| CircuitBreaker::new(*upstream)).collect(),
+            limiters: Upstream::ALL
+                .iter()
+                .map(|upstream| ConcurrencyLimiter::new(*upstream, &config.concurrency))
+                .collect(),
+            policy: RwLock::new(Arc::new(Policy::new(config))),
+        }
+    }
+
+    /// Applies new settings to calls started from now on; calls in flight finish under the
+    /// policy they started with. Breaker state and learned concurrency limits are kept.
+    pub fn reload(&self, config: &UpstreamConfig) {
+        *self.policy.write().expect("upstream policy poisoned") = Arc::new(Policy::new(config));
+    }
+
+    fn policy(&self) -> Arc<Policy> {
+        self.policy.read().expect("upstream policy poisoned").clone()
+    }
+
+    /// Runs `call` against `upstream` under its concurrency limit, circuit breaker and the
+    /// caller's deadline, retrying transient failures of idempotent RPCs. Errors returned by the upstream are
+    /// passed back untouched; only gateway-side failures are produced here.
+    pub async fn call<C, Req, Resp, F, Fut>(
+        &self,
+        upstream: Upstream,
+        rpc: &'static str,
+        client: C,
+        request: Request<Req>,
+        call: F,
+    ) -> Result<Response<Resp>, UpstreamError>
+    where
+        C: Clone,
+        Req: Clone,
+        F: Fn(C, Request<Req>) -> Fut,
+        Fut: Future<Output = Result<Response<Resp>, Status>>,
+    {
+        let policy = self.policy();
+        let (mut metadata, _, message) = request.into_parts();
+
+        // The client's grpc-timeout bounds the whole call, retries included; without one the
+        // gateway still stops waiting after the RPC's configured timeout. Either way no single
+        // attempt outlives the configured timeout.
+        let caller_timeout = caller_deadline(&metadata);
+        metadata.remove(GRPC_TIMEOUT);
+        let deadline = Deadline {
+            at: Instant::now() + caller_timeout.unwrap_or_else(|| policy.timeout(rpc)),
+            propagate: caller_timeout.is_some(),
+            timeout: policy.timeout(rpc),
+        };
+
+        let attempts = if IDEMPOTENT_RPCS.contains(&rpc) { policy.max_attempts } else { 1 };
+
+        let mut attempt = 1;
+        loop {
+            let request = Request::from_parts(metadata.clone(), Extensions::default(), message.clone());
+            let call = |request| call(client.clone(), request);
+
+            match self.attempt(&policy, upstream, rpc, attempt, deadline, true, request, call).await {
+                Err(UpstreamError::Upstream(status)) if attempt < attempts && status.code() == Code::Unavailable => {}
+                result => return result,
+            }
+
+            let backoff = policy.backoff(attempt).min(deadline.at.saturating_duration_since(Instant::now()));
+            debug!("Retrying {} on {} service in {:?}", rpc, upstream.as_str(), backoff);
+            counter!("upstream_retries_total", 1, "service" => upstream.as_str(), "rpc" => rpc);
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    /// Like `call`, for requests that can only be sent once, such as client streams. The call
+    /// may run for up to `timeout`, or until the caller's deadline if that comes sooner. Such
+    /// calls count against the concurrency limit, but their duration doesn't feed it.
+    pub async fn call_once<Req, Resp, F, Fut>(
+        &self,
+        upstream: Upstream,
+        rpc: &'static str,
+        timeout: Duration,
+        mut request: Request<Req>,
+        call: F,
+    ) -> Result<Response<Resp>, UpstreamError>
+    where
+        F: FnOnce(Request<Req>) -> Fut,
+        Fut: Future<Output = Result<Response<Resp>, Status>>,
+    {
+        let caller_timeout = caller_deadline(request.metadata());
+        request.metadata_mut().remove(GRPC_TIMEOUT);
+        let deadline = Deadline {
+            at: Instant::now() + caller_timeout.unwrap_or(timeout),
+            propagate: caller_timeout.is_some(),
+            timeout,
+        };
+
+        self.attempt(&self.policy(), upstream, rpc, 1, deadline, false, request, call).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn attempt<Req, Resp, F, Fut>(
+        &self,
+        policy: &Policy,
+        upstream: Upstream,
+        rpc: &'static str,
+        attempt: u32,
+        deadline: Deadline,
+        sampled: bool,
+        mut request: Request<Req>,
+        call: F,
+    ) -> Result<Response<Resp>, UpstreamError>
+    where
+        F: FnOnce(Request<Req>) -> Fut,
+        Fut: Future<Output = Result<Response<Resp>, Status>>,
+    {
+        let breaker = &self.breakers[upstream.index()];
+
+        // Leave the gateway a little room to relay the response before the caller gives up
+        let remaining = deadline
+            .at
+            .saturating_duration_since(Instant::now())
+            .saturating_sub(policy.deadline_margin);
+        if remaining.is_zero() {
+            return Err(UpstreamError::Gateway(deadline_exceeded(upstream, rpc)));
+        }
+        let budget = remaining.min(deadline.timeout);
+        // Whether running out of time would be down to the caller's deadline rather than the
+        // service taking longer than it is allowed
+        let caller_bound = deadline.propagate && remaining < deadline.timeout;
+
+        // Shed before the breaker, so a call turned away can't take the half-open probe
+        let priority = policy.concurrency.priority(rpc);
+        let Some(permit) = self.limiters[upstream.index()].try_acquire(&policy.concurrency, priority) else {
+            return Err(UpstreamError::Gateway(GatewayError::Overloaded(format!(
+                "{} service is overloaded, retry later",
+                upstream.as_str()
+            ))));
+        };
+
+        if !breaker.try_acquire(policy) {
+            counter!("upstream_circuit_rejected_total", 1, "service" => upstream.as_str());
+            return Err(UpstreamError::Gateway(GatewayError::ServiceUnavailable(format!(
+                "{} service is temporarily unavailable",
+                upstream.as_str()
+            ))));
+        }
+
+        if deadline.propagate {
+            request.set_timeout(budget);
+        }
+
+        // Each attempt is its own span, and the upstream continues the trace from it
+        let span = info_span!("upstream", otel.name = rpc, service = upstream.as_str(), rpc, attempt);
+        span.in_scope(|| telemetry::inject(request.metadata_mut()));
+
+        let attempt_call = call(request).instrument(span);
+        let started = Instant::now();
+        let result = match tokio::time::timeout(budget, attempt_call).await {
+            Ok(result) => result,
+            Err(_) => Err(deadline_exceeded(upstream, rpc).into()),
+        };
+        record_attempt(upstream, rpc, started, &result);
+        let caller_expired = caller_bound && matches!(&result, Err(status) if status.code() == Code::DeadlineExceeded);
+
+        let outcome = match &result {
+            _ if !sampled || caller_expired => Outcome::Ignored,
+            Err(status) if signals_overload(status.code()) => Outcome::Dropped,
+            _ => Outcome::Answered,
+        };
+        permit.release(&policy.concurrency, outcome);
+
+        match result {
+            Ok(response) => {
+                breaker.record_success();
+                Ok(response)
+            }
+            // The caller not waiting long enough says nothing about the service either way
+            Err(status) if caller_expired => Err(UpstreamError::Upstream(status)),
+            Err(status) => {
+                if counts_as_failure(status.code()) {
+                    breaker.record_failure(policy);
+                } else {
+                    // The service answered; the failure is the caller's problem
+                    breaker.record_success();
+                }
+                Err(UpstreamError::Upstream(status))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Deadline {
+    at: Instant,
+    /// Whether the caller set the deadline, in which case upstreams are told about it
+    propagate: bool,
+    /// The RPC's configured timeout, which bounds each attempt
+    timeout: Duration,
+}
+
+/// Why an upstream call failed: the gateway gave up on it, or the service returned an error.
+pub enum UpstreamError {
+    Gateway(GatewayError),
+    Upstream(Status),
+}
+
+// Upstream latency per attempt, kept apart from the gateway-wide request metrics so gateway
+// overhead shows up as the difference between the two
+fn record_attempt<T>(upstream: Upstream, rpc: &'static str, started: Instant, result: &Result<T, Status>) {
+    let code = match result {
+        Ok(_) => Code::Ok,
+        Err(status) => status.code(),
+    };
+    let labels = [
+        ("service", upstream.as_str()),
+        ("rpc", rpc),
+        ("code", code_label(code)),
+    ];
+
+    counter!("upstream_requests_total", 1, &labels);
+    histogram!("upstream_request_duration_ms", started.elapsed().as_secs_f64() * 1000.0, &labels);
+}
+
+// Codes that say the service is unhealthy rather than that the request was bad
+fn counts_as_failure(code: Code) -> bool {
+    matches!(code, Code::Unavailable | Code::DeadlineExceeded | Code::Unknown | Code::Internal | Code::DataLoss)
+}
+
+// Codes that say the service had no capacity for the call
+fn signals_overload(code: Code) -> bool {
+    matches!(code, Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted)
+}
+
+fn deadline_exceeded(upstream: Upstream, rpc: &str) -> GatewayError {
+    GatewayError::DeadlineExceeded(format!("{} on {} service exceeded its deadline", rpc, upstream.as_str()))
+}
+
+/// Parses a `grpc-timeout` value: up to 8 digits followed by a unit (H, M, S, m, u, n).
+pub fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (digits, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = digits.parse().ok()?;
+
+    let timeout = match unit {
+        "H" => Duration::from_secs(amount.checked_mul(3600)?),
+        "M" => Duration::from_secs(amount.checked_mul(60)?),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    };
+    Some(timeout)
+}
+
+/// The caller's remaining deadline, if it sent one.
+pub fn caller_deadline(metadata: &MetadataMap) -> Option<Duration> {
+    metadata
+        .get(GRPC_TIMEOUT)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_grpc_timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // GetPost is given `timeout_ms`, and three failures in a row open the breaker
+    fn config(timeout_ms: u64) -> UpstreamConfig {
+        UpstreamConfig {
+            deadline_margin_ms: 0,
+            max_attempts: 1,
+            breaker_failure_threshold: 3,
+            rpc_timeout_ms: HashMap::from([("GetPost".to_string(), timeout_ms)]),
+            ..UpstreamConfig::default()
+        }
+    }
+
+    fn breaker_state(resilience: &Resilience) -> BreakerState {
+        *resilience.breakers[Upstream::Post.index()].state.lock().unwrap()
+    }
+
+    // Calls GetPost on a service that takes `delay_ms` to answer
+    async fn get_post(resilience: &Resilience, grpc_timeout: &str, delay_ms: u64) -> Result<Response<()>, UpstreamError> {
+        let mut request = Request::new(());
+        request.metadata_mut().insert(GRPC_TIMEOUT, grpc_timeout.parse().unwrap());
+        let slow_service = |_: (), _: Request<()>| async move {
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+            Ok(Response::new(()))
+        };
+        resilience.call(Upstream::Post, "GetPost", (), request, slow_service).await
+    }
+
+    fn timed_out(result: &Result<Response<()>, UpstreamError>) -> bool {
+        matches!(result, Err(UpstreamError::Upstream(status)) if status.code() == Code::DeadlineExceeded)
+    }
+
+    #[tokio::test]
+    async fn short_client_deadlines_never_open_the_breaker() {
+        let resilience = Resilience::new(&config(1000));
+
+        for _ in 0..10 {
+            assert!(timed_out(&get_post(&resilience, "20m", 200).await));
+        }
+
+        assert_eq!(breaker_state(&resilience), BreakerState::Closed { failures: 0 });
+    }
+
+    #[tokio::test]
+    async fn short_client_deadlines_leave_the_concurrency_limit_alone() {
+        let resilience = Resilience::new(&config(1000));
+        let limiter = &resilience.limiters[Upstream::Post.index()];
+        let initial = limiter.limit();
+
+        for _ in 0..10 {
+            assert!(timed_out(&get_post(&resilience, "20m", 200).await));
+        }
+        assert_eq!(limiter.limit(), initial);
+
+        // Timeouts of the service's own making still back it off
+        let resilience = Resilience::new(&config(20));
+        let limiter = &resilience.limiters[Upstream::Post.index()];
+        assert!(timed_out(&get_post(&resilience, "5S", 200).await));
+        assert!(limiter.limit() < initial);
+    }
+
+    #[tokio::test]
+    async fn upstream_timeouts_open_the_breaker() {
+        let resilience = Resilience::new(&config(20));
+
+        // The client would wait, but no attempt outlives the RPC's own timeout
+        for _ in 0..3 {
+            assert!(timed_out(&get_post(&resilience, "5S", 200).await));
+        }
+
+        assert!(matches!(breaker_state(&resilience), BreakerState::Open { .. }));
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::config::RateLimit;
+use super::{Decision, RateLimitStore};
+
+// Idle buckets are pruned once the map grows past this many keys
+const MAX_TRACKED_KEYS: usize = 100_000;
+// What a prune shrinks the map to at most, so the next one is this many new keys away
+const PRUNED_KEYS: usize = MAX_TRACKED_KEYS * 9 / 10;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // The limit the bucket last refilled under; limits differ per RPC and change on reload
+    capacity: f64,
+    per_second: f64,
+}
+
+impl Bucket {
+    // A bucket that has refilled completely carries no state worth keeping
+    fn is_full(&self, now: Instant) -> bool {
+        self.tokens + now.duration_since(self.updated).as_secs_f64() * self.per_second >= self.capacity
+    }
+}
+
+/// Token buckets kept in process memory; only suitable for a single gateway instance.
+#[derive(Default)]
+pub struct InMemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for InMemoryStore {
+    async fn acquire(&self, key: &str, limit: &RateLimit) -> Result<Decision> {
+        let now = Instant::now();
+        let capacity = limit.burst as f64;
+        let mut buckets = self.buckets.lock().expect("rate limit buckets poisoned");
+
+        if buckets.len() >= MAX_TRACKED_KEYS {
+            prune(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+            capacity,
+            per_second: limit.per_second,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(capacity);
+        bucket.updated = now;
+        bucket.capacity = capacity;
+        bucket.per_second = limit.per_second;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(Decision::Allowed)
+        } else {
+            let wait = (1.0 - bucket.tokens) / limit.per_second;
+            Ok(Decision::Limited {
+                retry_after: Duration::from_secs_f64(wait),
+            })
+        }
+    }
+}
+
+// Drops full buckets, then the least recently used ones if that wasn't enough, so a flood of
+// distinct clients costs one scan per `MAX_TRACKED_KEYS - PRUNED_KEYS` new keys
+fn prune(buckets: &mut HashMap<String, Bucket>, now: Instant) {
+    buckets.retain(|_, bucket| !bucket.is_full(now));
+
+    if buckets.len() > PRUNED_KEYS {
+        let mut updated: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+        let excess = buckets.len() - PRUNED_KEYS;
+        let (_, cutoff, _) = updated.select_nth_unstable(excess);
+        let cutoff = *cutoff;
+        buckets.retain(|_, bucket| bucket.updated >= cutoff);
+    }
+}
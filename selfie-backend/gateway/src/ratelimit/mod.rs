@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use anyhow::Result;
+use async_trait::async_trait;
+use metrics::counter;
+use tonic::body::BoxBody;
+use tonic::codegen::http::{HeaderMap, Request, Response};
+use tonic::transport::server::TcpConnectInfo;
+use tonic::Status;
+use tower::{Layer, Service};
+use tracing::{info, warn};
+
+use crate::config::{RateLimit, RateLimitConfig, RateLimitStoreKind};
+use crate::error::GatewayError;
+use crate::middleware::{AuthenticatedUser, MethodLabels};
+
+pub mod memory;
+pub mod redis;
+
+pub use memory::InMemoryStore;
+pub use self::redis::RedisStore;
+
+/// Outcome of taking a token from a bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Allowed,
+    Limited { retry_after: Duration },
+}
+
+/// Token bucket storage. Buckets are created full on first use and refill continuously at
+/// `per_second` up to `burst` tokens; every call takes one token.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    async fn acquire(&self, key: &str, limit: &RateLimit) -> Result<Decision>;
+}
+
+/// Resolves the limit and bucket key for a call and consults the store.
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+    policy: RwLock<Policy>,
+}
+
+struct Policy {
+    default_limit: RateLimit,
+    // Keyed by lowercased RPC name, e.g. `login`
+    method_limits: HashMap<String, RateLimit>,
+    trusted_proxy_hops: usize,
+}
+
+impl Policy {
+    fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            default_limit: config.default.clone(),
+            method_limits: config
+                .methods
+                .iter()
+                .map(|(name, limit)| (name.to_ascii_lowercase(), limit.clone()))
+                .collect(),
+            trusted_proxy_hops: config.trusted_proxy_hops,
+        }
+    }
+}
+
+impl RateLimiter {
+    pub async fn from_config(config: &RateLimitConfig) -> Result<Self> {
+        let store: Arc<dyn RateLimitStore> = match config.store {
+            RateLimitStoreKind::Memory => Arc::new(InMemoryStore::new()),
+            RateLimitStoreKind::Redis => {
+                let url = config
+                    .redis_url
+                    .as_deref()
+                    .ok_or_else(|| anyhow::anyhow!("rate_limit.redis_url is required for the redis store"))?;
+                Arc::new(RedisStore::connect(url).await?)
+            }
+        };
+        info!("Rate limiting with {:?} store", config.store);
+
+        Ok(Self::new(store, config))
+    }
+
+    pub fn new(store: Arc<dyn RateLimitStore>, config: &RateLimitConfig) -> Self {
+        Self {
+            store,
+            policy: RwLock::new(Policy::new(config)),
+        }
+    }
+
+    /// Applies new limits to buckets as they are next used. The store is fixed at startup.
+    pub fn reload(&self, config: &RateLimitConfig) {
+        *self.policy.write().expect("rate limit policy poisoned") = Policy::new(config);
+    }
+
+    /// Takes a token for the call at `path` made by `client`. Store failures let the call
+    /// through rather than turning a Redis outage into a gateway outage.
+    pub async fn check(&self, path: &str, client: &str) -> Decision {
+        let rpc = path.rsplit('/').next().unwrap_or(path).to_ascii_lowercase();
+
+        // RPCs with their own limit get their own bucket; everything else shares one
+        let (scope, limit) = {
+            let policy = self.policy.read().expect("rate limit policy poisoned");
+            match policy.method_limits.get(&rpc) {
+                Some(limit) => (rpc.as_str(), limit.clone()),
+                None => ("default", policy.default_limit.clone()),
+            }
+        };
+
+        let key = format!("{}:{}", scope, client);
+        match self.store.acquire(&key, &limit).await {
+            Ok(decision) => decision,
+            Err(err) => {
+                warn!("Rate limit store error, allowing request: {}", err);
+                counter!("rate_limit_store_errors_total", 1);
+                Decision::Allowed
+            }
+        }
+    }
+
+    // Authenticated callers are limited per user, everyone else per client address
+    fn client_key<B>(&self, req: &Request<B>) -> String {
+        if let Some(user) = req.extensions().get::<AuthenticatedUser>() {
+            return format!("user:{}", user.user_id);
+        }
+
+        let hops = self.policy.read().expect("rate limit policy poisoned").trusted_proxy_hops;
+        let forwarded = forwarded_for(req.headers(), hops);
+
+        let remote = forwarded.or_else(|| {
+            req.extensions()
+                .get::<TcpConnectInfo>()
+                .and_then(|info| info.remote_addr())
+                .map(|addr| addr.ip())
+        });
+
+        match remote {
+            Some(ip) => format!("ip:{}", ip),
+            None => "ip:unknown".to_string(),
+        }
+    }
+}
+
+// The X-Forwarded-For entry appended by the outermost trusted proxy, `hops` from the right.
+// Anything further left came from the client and can't be trusted.
+fn forwarded_for(headers: &HeaderMap, hops: usize) -> Option<IpAddr> {
+    let hop = hops.checked_sub(1)?;
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .rev()
+        .nth(hop)
+        .and_then(|ip| ip.trim().parse().ok())
+}
+
+/// Rejects calls over their limit with `RESOURCE_EXHAUSTED`. Sits inside `AuthMiddleware`
+/// so the caller's identity is known.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+    method_labels: MethodLabels,
+}
+
+impl RateLimitLayer {
+    pub fn new(limiter: Arc<RateLimiter>, method_labels: MethodLabels) -> Self {
+        Self { limiter, method_labels }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitMiddleware<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        RateLimitMiddleware {
+            inner: service,
+            limiter: self.limiter.clone(),
+            method_labels: self.method_labels.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitMiddleware<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+    method_labels: MethodLabels,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for RateLimitMiddleware<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // Take the service that was driven to readiness and leave a fresh clone behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+        let method_labels = self.method_labels.clone();
+
+        Box::pin(async move {
+            let path = req.uri().path().to_string();
+            let client = limiter.client_key(&req);
+
+            match limiter.check(&path, &client).await {
+                Decision::Allowed => inner.call(req).await,
+                Decision::Limited { retry_after } => {
+                    warn!("Rate limited {} on {}", client, path);
+                    let (service, method) = method_labels.labels(&path);
+                    counter!("requests_rate_limited_total", 1, "service" => service, "method" => method);
+                    let status: Status = GatewayError::RateLimited(retry_after).into();
+                    Ok(status.to_http())
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+use anyhow::Result;
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use redis::Script;
+
+use crate::config::RateLimit;
+use super::{Decision, RateLimitStore};
+
+const KEY_PREFIX: &str = "gateway:ratelimit:";
+
+// Refills and takes from the bucket atomically using the Redis clock, so every gateway
+// replica sees the same state. Returns the wait in milliseconds, or 0 when allowed.
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local capacity = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(state[1]) or capacity
+local ts = tonumber(state[2]) or now
+tokens = math.min(capacity, tokens + (now - ts) / 1000 * rate)
+
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    wait = math.ceil((1 - tokens) / rate * 1000)
+end
+
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(capacity / rate * 1000) + 1000)
+return wait
+"#;
+
+/// Token buckets shared by all gateway replicas through Redis.
+pub struct RedisStore {
+    connection: ConnectionManager,
+    script: Script,
+}
+
+impl RedisStore {
+    pub async fn connect(url: &str) -> Result<Self> {
+        let client = redis::Client::open(url)?;
+        let connection = ConnectionManager::new(client).await?;
+
+        Ok(Self {
+            connection,
+            script: Script::new(TOKEN_BUCKET_SCRIPT),
+        })
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for RedisStore {
+    async fn acquire(&self, key: &str, limit: &RateLimit) -> Result<Decision> {
+        let mut connection = self.connection.clone();
+        let wait_ms: u64 = self
+            .script
+            .key(format!("{}{}", KEY_PREFIX, key))
+            .arg(limit.burst)
+            .arg(limit.per_second)
+            .invoke_async(&mut connection)
+            .await?;
+
+        if wait_ms == 0 {
+            Ok(Decision::Allowed)
+        } else {
+            Ok(Decision::Limited {
+                retry_after: Duration::from_millis(wait_ms),
+            })
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use anyhow::Result;
+use metrics::counter;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+
+use crate::cache::ResponseCache;
+use crate::config::{self, Config};
+use crate::health::HealthProber;
+use crate::middleware::{PublicMethods, TokenVerifier};
+use crate::proxy::ServiceProxies;
+use crate::ratelimit::RateLimiter;
+use crate::services::media::UploadLimits;
+use crate::tls::ServerTls;
+
+// How often the config, key and certificate files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Parts of the gateway that take a new configuration while running. Requests in flight
+/// finish under the settings they started with.
+pub struct Reloadable {
+    pub proxies: ServiceProxies,
+    pub prober: Arc<HealthProber>,
+    pub verifier: Arc<TokenVerifier>,
+    pub public_methods: Arc<PublicMethods>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub cache: Arc<ResponseCache>,
+    pub upload_limits: Arc<UploadLimits>,
+    /// One per listener that terminates TLS
+    pub server_tls: Vec<Arc<ServerTls>>,
+}
+
+impl Reloadable {
+    /// Reloads the configuration on SIGHUP and whenever the config file or a key or
+    /// certificate it names changes. A configuration that fails to load or validate is logged
+    /// and the running one kept.
+    pub async fn watch(self, mut current: Config) -> Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let path = config::config_path();
+        let mut modified = modified_times(path.as_deref(), &current);
+
+        let mut poll = tokio::time::interval(WATCH_INTERVAL);
+        poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => info!("Received SIGHUP, reloading configuration"),
+                _ = poll.tick() => {
+                    if modified_times(path.as_deref(), &current) == modified {
+                        continue;
+                    }
+                    info!("Configuration, key or certificate files changed, reloading");
+                }
+            }
+
+            match self.reload(&current).await {
+                Ok(next) => {
+                    info!("Configuration reloaded");
+                    counter!("gateway_config_reloads_total", 1, "result" => "applied");
+                    current = next;
+                }
+                Err(err) => {
+                    error!("Keeping the current configuration: {:#}", err);
+                    counter!("gateway_config_reloads_total", 1, "result" => "rejected");
+                }
+            }
+            modified = modified_times(path.as_deref(), &current);
+        }
+    }
+
+    async fn reload(&self, current: &Config) -> Result<Config> {
+        let next = config::load_config()?;
+
+        // Everything that reads files or can otherwise fail is built first, so a broken key,
+        // certificate or endpoint leaves the running configuration untouched
+        let key_set = TokenVerifier::load_keys(&next.auth)?;
+        let acceptors = if next.tls.enabled {
+            self.server_tls
+                .iter()
+                .map(|tls| tls.load_acceptor(&next.tls))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            Vec::new()
+        };
+        let endpoints = self.proxies.load_endpoints(&next.service_discovery)?;
+        let probes = HealthProber::load_probes(&next.service_discovery, &next.health)?;
+
+        self.verifier.replace_keys(key_set);
+        for (tls, acceptor) in self.server_tls.iter().zip(acceptors) {
+            tls.replace_acceptor(acceptor);
+        }
+        self.proxies.replace_endpoints(endpoints, &next.upstream).await;
+        self.prober.replace_probes(probes).await;
+        self.public_methods.reload(&next.auth.public_methods);
+        self.rate_limiter.reload(&next.rate_limit);
+        self.cache.reload(&next.cache);
+        self.upload_limits.reload(&next.media);
+
+        for setting in restart_required(current, &next) {
+            warn!("{} changed; the new value takes effect after a restart", setting);
+        }
+        Ok(next)
+    }
+}
+
+// Settings fixed by listeners, exporters and connections opened at startup
+fn restart_required(current: &Config, next: &Config) -> Vec<&'static str> {
+    [
+        ("host", current.host != next.host),
+        ("port", current.port != next.port),
+        ("metrics_addr", current.metrics_addr != next.metrics_addr),
+        ("cors_allowed_origins", current.cors_allowed_origins != next.cors_allowed_origins),
+        ("rate_limit.store", current.rate_limit.store != next.rate_limit.store),
+        ("rate_limit.redis_url", current.rate_limit.redis_url != next.rate_limit.redis_url),
+        ("health.probe_interval_secs", current.health.probe_interval_secs != next.health.probe_interval_secs),
+        ("telemetry", current.telemetry != next.telemetry),
+        ("websocket", current.websocket != next.websocket),
+        ("graphql", current.graphql != next.graphql),
+        ("tls.enabled", current.tls.enabled != next.tls.enabled),
+        ("shutdown", current.shutdown != next.shutdown),
+    ]
+    .into_iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(setting, _)| setting)
+    .collect()
+}
+
+fn modified_times(config_path: Option<&Path>, config: &Config) -> Vec<Option<SystemTime>> {
+    config_path
+        .map(Path::to_path_buf)
+        .into_iter()
+        .chain(config.watched_files())
+        .map(|path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
@@ -0,0 +1,146 @@
+use prost_reflect::{DynamicMessage, FieldDescriptor, Kind, MessageDescriptor, ReflectMessage, SerializeOptions, Value};
+
+use crate::error::GatewayError;
+use super::routes::{BodyBinding, Route};
+
+/// Builds the gRPC request message from the JSON body, path variables and query string.
+pub fn build_request(
+    route: &Route,
+    bindings: &[(String, String)],
+    query: Option<&str>,
+    body: &[u8],
+) -> Result<DynamicMessage, GatewayError> {
+    let input = route.rpc.input();
+
+    let mut message = match &route.body {
+        BodyBinding::Message => decode_json(input, body)?,
+        BodyBinding::Field(name) => {
+            let mut message = DynamicMessage::new(input.clone());
+            if !body.is_empty() {
+                let field = input.get_field_by_name(name).ok_or_else(|| {
+                    GatewayError::Internal(format!("unknown body field {}", name))
+                })?;
+                let value = decode_field_json(&field, body)?;
+                message.set_field(&field, value);
+            }
+            message
+        }
+        BodyBinding::None => DynamicMessage::new(input),
+    };
+
+    // Query parameters only fill fields that aren't carried by the body
+    if route.body != BodyBinding::Message {
+        if let Some(query) = query {
+            for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+                set_path(&mut message, &key, &value)?;
+            }
+        }
+    }
+
+    // Path variables win over anything supplied in the body or query
+    for (name, value) in bindings {
+        set_path(&mut message, name, value)?;
+    }
+
+    Ok(message)
+}
+
+/// Serializes a response message as proto3 JSON.
+pub fn encode_json(message: &DynamicMessage) -> Result<Vec<u8>, GatewayError> {
+    let mut buf = Vec::new();
+    let mut serializer = serde_json::Serializer::new(&mut buf);
+    message
+        .serialize_with_options(&mut serializer, &SerializeOptions::new())
+        .map_err(|err| GatewayError::Internal(format!("failed to encode {}: {}", message.descriptor().full_name(), err)))?;
+    Ok(buf)
+}
+
+fn decode_json(descriptor: MessageDescriptor, body: &[u8]) -> Result<DynamicMessage, GatewayError> {
+    if body.is_empty() {
+        return Ok(DynamicMessage::new(descriptor));
+    }
+
+    let mut deserializer = serde_json::Deserializer::from_slice(body);
+    let message = DynamicMessage::deserialize(descriptor, &mut deserializer)
+        .and_then(|message| deserializer.end().map(|_| message))
+        .map_err(|err| GatewayError::InvalidRequest(format!("invalid JSON body: {}", err)))?;
+    Ok(message)
+}
+
+fn decode_field_json(field: &FieldDescriptor, body: &[u8]) -> Result<Value, GatewayError> {
+    match field.kind() {
+        Kind::Message(descriptor) if !field.is_list() && !field.is_map() => {
+            decode_json(descriptor, body).map(Value::Message)
+        }
+        _ => {
+            let raw: serde_json::Value = serde_json::from_slice(body)
+                .map_err(|err| GatewayError::InvalidRequest(format!("invalid JSON body: {}", err)))?;
+            match raw {
+                serde_json::Value::String(text) => parse_scalar(field, &text),
+                other => parse_scalar(field, &other.to_string()),
+            }
+        }
+    }
+}
+
+// Sets a (possibly nested, dot-separated) field from its string form; repeated fields append
+fn set_path(message: &mut DynamicMessage, path: &str, raw: &str) -> Result<(), GatewayError> {
+    let (head, rest) = match path.split_once('.') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (path, None),
+    };
+
+    let field = message
+        .descriptor()
+        .get_field_by_name(head)
+        .or_else(|| message.descriptor().get_field_by_json_name(head))
+        .ok_or_else(|| GatewayError::InvalidRequest(format!("unknown field {}", path)))?;
+
+    match rest {
+        Some(rest) => match message.get_field_mut(&field) {
+            Value::Message(nested) => set_path(nested, rest, raw),
+            _ => Err(GatewayError::InvalidRequest(format!("{} is not a message field", head))),
+        },
+        None if field.is_list() => {
+            let value = parse_scalar(&field, raw)?;
+            if let Value::List(values) = message.get_field_mut(&field) {
+                values.push(value);
+            }
+            Ok(())
+        }
+        None if field.is_map() => Err(GatewayError::InvalidRequest(format!(
+            "map field {} can't be set from the URL",
+            path
+        ))),
+        None => {
+            let value = parse_scalar(&field, raw)?;
+            message.set_field(&field, value);
+            Ok(())
+        }
+    }
+}
+
+fn parse_scalar(field: &FieldDescriptor, raw: &str) -> Result<Value, GatewayError> {
+    let invalid = || GatewayError::InvalidRequest(format!("invalid value for {}: {}", field.name(), raw));
+
+    let value = match field.kind() {
+        Kind::String => Value::String(raw.to_string()),
+        Kind::Bool => Value::Bool(raw.parse().map_err(|_| invalid())?),
+        Kind::Int32 | Kind::Sint32 | Kind::Sfixed32 => Value::I32(raw.parse().map_err(|_| invalid())?),
+        Kind::Int64 | Kind::Sint64 | Kind::Sfixed64 => Value::I64(raw.parse().map_err(|_| invalid())?),
+        Kind::Uint32 | Kind::Fixed32 => Value::U32(raw.parse().map_err(|_| invalid())?),
+        Kind::Uint64 | Kind::Fixed64 => Value::U64(raw.parse().map_err(|_| invalid())?),
+        Kind::Float => Value::F32(raw.parse().map_err(|_| invalid())?),
+        Kind::Double => Value::F64(raw.parse().map_err(|_| invalid())?),
+        Kind::Enum(descriptor) => {
+            let number = match descriptor.get_value_by_name(raw) {
+                Some(value) => value.number(),
+                None => raw.parse().map_err(|_| invalid())?,
+            };
+            Value::EnumNumber(number)
+        }
+        Kind::Bytes | Kind::Message(_) => return Err(invalid()),
+    };
+
+    Ok(value)
+}
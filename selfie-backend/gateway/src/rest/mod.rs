@@ -0,0 +1,343 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use prost::Message;
+use prost_reflect::{DynamicMessage, MessageDescriptor};
+use serde_json::json;
+use tonic::body::BoxBody;
+use tonic::codegen::http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Version};
+use tonic::codegen::Body as HttpBody;
+use tonic::transport::Body;
+use tonic::{Code, Status};
+use tower::{Layer, Service};
+use tracing::{debug, warn};
+
+use crate::common::v1 as common;
+use crate::error::{GatewayError, RETRY_AFTER};
+
+pub mod message;
+pub mod routes;
+
+pub use routes::RouteTable;
+
+// Same ceiling tonic applies to decoded gRPC messages
+const MAX_BODY_BYTES: usize = 4 * 1024 * 1024;
+
+// Request headers that describe the HTTP/1.1 exchange rather than the call itself
+pub(crate) const HOP_HEADERS: &[&str] = &[
+    "host",
+    "connection",
+    "content-length",
+    "content-type",
+    "accept",
+    "accept-encoding",
+    "transfer-encoding",
+    "upgrade",
+    "te",
+];
+
+/// Serves HTTP/JSON requests by transcoding them into gRPC calls on the inner service, which
+/// keeps the auth, identity and logging layers in the path. gRPC traffic passes straight through.
+#[derive(Clone)]
+pub struct RestLayer {
+    routes: Arc<RouteTable>,
+}
+
+impl RestLayer {
+    pub fn new(routes: Arc<RouteTable>) -> Self {
+        Self { routes }
+    }
+}
+
+impl<S> Layer<S> for RestLayer {
+    type Service = RestService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        RestService {
+            inner: service,
+            routes: self.routes.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RestService<S> {
+    inner: S,
+    routes: Arc<RouteTable>,
+}
+
+impl<S> Service<Request<Body>> for RestService<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: std::fmt::Display + Send,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        if is_grpc(req.headers()) {
+            return Box::pin(self.inner.call(req));
+        }
+
+        // Take the service that was driven to readiness and leave a fresh clone behind
+        let clone = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, clone);
+        let routes = self.routes.clone();
+
+        Box::pin(async move {
+            match transcode(inner, &routes, req).await {
+                Ok(response) => Ok(response),
+                Err(status) => Ok(error_response(&status)),
+            }
+        })
+    }
+}
+
+fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.starts_with("application/grpc"))
+        .unwrap_or(false)
+}
+
+async fn transcode<S>(mut inner: S, routes: &RouteTable, req: Request<Body>) -> Result<Response<BoxBody>, Status>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>>,
+    S::Error: std::fmt::Display,
+{
+    let (parts, body) = req.into_parts();
+
+    let (route, bindings) = routes
+        .find(&parts.method, parts.uri.path())
+        .ok_or_else(|| Status::not_found(format!("no route for {} {}", parts.method, parts.uri.path())))?;
+
+    let body = collect_body(body).await?;
+    let message = message::build_request(route, &bindings, parts.uri.query(), &body)?;
+    debug!("Transcoding {} {} to {}", parts.method, parts.uri.path(), route.grpc_path);
+
+    let mut grpc_req = Request::builder()
+        .method(Method::POST)
+        .uri(route.grpc_path.as_str())
+        .version(Version::HTTP_2)
+        .header(header::CONTENT_TYPE, "application/grpc")
+        .header(header::TE, "trailers")
+        .body(Body::from(encode_frame(&message)))
+        .map_err(|err| Status::internal(err.to_string()))?;
+
+    // Forward caller headers (authorization, request ids, ...) as gRPC metadata
+    for (name, value) in parts.headers.iter() {
+        if !HOP_HEADERS.contains(&name.as_str()) {
+            grpc_req.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    *grpc_req.extensions_mut() = parts.extensions;
+
+    let response = inner
+        .call(grpc_req)
+        .await
+        .map_err(|err| Status::unavailable(err.to_string()))?;
+
+    // Trailers-only responses carry the status in the headers
+    if let Some(status) = Status::from_header_map(response.headers()) {
+        if status.code() != Code::Ok {
+            return Err(status);
+        }
+    }
+
+    let output = route.rpc.output();
+    if route.rpc.is_server_streaming() {
+        Ok(stream_response(output, response.into_body()))
+    } else {
+        unary_response(output, response.into_body()).await
+    }
+}
+
+async fn unary_response(output: MessageDescriptor, mut body: BoxBody) -> Result<Response<BoxBody>, Status> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        buf.put(chunk?);
+    }
+
+    if let Some(status) = trailer_status(body.trailers().await?.as_ref()) {
+        return Err(status);
+    }
+
+    let payload = next_frame(&mut buf)
+        .ok_or_else(|| Status::internal("upstream returned no message"))?;
+    let message = DynamicMessage::decode(output, payload)
+        .map_err(|err| Status::internal(format!("invalid upstream message: {}", err)))?;
+
+    Ok(json_response(StatusCode::OK, message::encode_json(&message)?))
+}
+
+// Server-streaming RPCs are returned as newline-delimited JSON, one message per line
+fn stream_response(output: MessageDescriptor, mut body: BoxBody) -> Response<BoxBody> {
+    let (mut sender, stream_body) = Body::channel();
+
+    tokio::spawn(async move {
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(status) => {
+                    let _ = sender.send_data(ndjson_error(&status)).await;
+                    return;
+                }
+            };
+            buf.put(chunk);
+
+            while let Some(payload) = next_frame(&mut buf) {
+                let line = match DynamicMessage::decode(output.clone(), payload) {
+                    Ok(message) => message::encode_json(&message).map_err(Status::from),
+                    Err(err) => Err(Status::internal(format!("invalid upstream message: {}", err))),
+                };
+                let line = match line {
+                    Ok(mut line) => {
+                        line.push(b'\n');
+                        Bytes::from(line)
+                    }
+                    Err(status) => ndjson_error(&status),
+                };
+                if sender.send_data(line).await.is_err() {
+                    // Client went away; dropping the body cancels the upstream stream
+                    return;
+                }
+            }
+        }
+
+        let failed = match body.trailers().await {
+            Ok(trailers) => trailer_status(trailers.as_ref()),
+            Err(status) => Some(status),
+        };
+        if let Some(status) = failed {
+            let _ = sender.send_data(ndjson_error(&status)).await;
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(boxed(stream_body))
+        .expect("valid streaming response")
+}
+
+// The error the upstream reported in its trailers, if any
+fn trailer_status(trailers: Option<&HeaderMap>) -> Option<Status> {
+    trailers
+        .and_then(Status::from_header_map)
+        .filter(|status| status.code() != Code::Ok)
+}
+
+pub(crate) async fn collect_body(mut body: Body) -> Result<Bytes, Status> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| Status::invalid_argument(format!("failed to read body: {}", err)))?;
+        if buf.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(GatewayError::InvalidRequest("request body too large".to_string()).into());
+        }
+        buf.put(chunk);
+    }
+    Ok(buf.freeze())
+}
+
+// Length-prefixed gRPC message frame, uncompressed
+fn encode_frame(message: &DynamicMessage) -> Bytes {
+    let payload = message.encode_to_vec();
+    let mut frame = BytesMut::with_capacity(payload.len() + 5);
+    frame.put_u8(0);
+    frame.put_u32(payload.len() as u32);
+    frame.put_slice(&payload);
+    frame.freeze()
+}
+
+fn next_frame(buf: &mut BytesMut) -> Option<Bytes> {
+    if buf.len() < 5 {
+        return None;
+    }
+    let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+    if buf.len() < 5 + len {
+        return None;
+    }
+    buf.advance(5);
+    Some(buf.split_to(len).freeze())
+}
+
+/// HTTP status for a gRPC code, following the google.rpc.Code mapping.
+pub fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::Cancelled => StatusCode::from_u16(499).expect("valid status code"),
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => StatusCode::BAD_REQUEST,
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub(crate) fn error_body(status: &Status) -> serde_json::Value {
+    let mut error = json!({
+        "code": status.code() as i32,
+        "status": format!("{:?}", status.code()),
+        "message": status.message(),
+    });
+
+    // Gateway and service errors carry a selfie.common.v1.Error in the status details
+    if let Ok(details) = common::Error::decode(status.details()) {
+        if !details.code.is_empty() {
+            error["details"] = json!({
+                "code": details.code,
+                "message": details.message,
+                "metadata": details.metadata,
+            });
+        }
+    }
+
+    json!({ "error": error })
+}
+
+pub(crate) fn error_response(status: &Status) -> Response<BoxBody> {
+    if status.code() == Code::Internal || status.code() == Code::Unknown {
+        warn!("REST call failed: {}", status.message());
+    }
+    let mut response = json_response(http_status(status.code()), error_body(status).to_string().into_bytes());
+    if let Some(retry_after) = status.metadata().get(RETRY_AFTER) {
+        if let Ok(value) = HeaderValue::from_bytes(retry_after.as_bytes()) {
+            response.headers_mut().insert(header::RETRY_AFTER, value);
+        }
+    }
+    response
+}
+
+fn ndjson_error(status: &Status) -> Bytes {
+    let mut line = error_body(status).to_string().into_bytes();
+    line.push(b'\n');
+    Bytes::from(line)
+}
+
+pub(crate) fn json_response(status: StatusCode, body: Vec<u8>) -> Response<BoxBody> {
+    let mut response = Response::new(boxed(Body::from(body)));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn boxed(body: Body) -> BoxBody {
+    body.map_err(|err| Status::internal(err.to_string())).boxed_unsync()
+}
@@ -0,0 +1,196 @@
+use std::cmp::Reverse;
+
+use anyhow::{bail, Context, Result};
+use percent_encoding::percent_decode_str;
+use prost_reflect::{DescriptorPool, DynamicMessage, MethodDescriptor, Value};
+use tonic::codegen::http::Method;
+
+const HTTP_RULE_EXTENSION: &str = "google.api.http";
+
+/// Which part of the request message the HTTP body is decoded into.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BodyBinding {
+    /// No body; fields not bound by the path come from the query string.
+    None,
+    /// The whole message (`body: "*"`).
+    Message,
+    /// A single top-level field (`body: "field"`).
+    Field(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Variable(String),
+}
+
+/// A `google.api.http` path template such as `/v1/posts/{post_id}/comments`.
+#[derive(Debug, Clone)]
+pub struct PathTemplate {
+    segments: Vec<Segment>,
+}
+
+impl PathTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let segments = template
+            .trim_start_matches('/')
+            .split('/')
+            .map(|segment| {
+                match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                    // `{field=*}` matches a single segment like `{field}`; wider globs aren't used
+                    Some(var) => match var.split_once('=') {
+                        Some((name, "*")) | Some((name, "")) => Ok(Segment::Variable(name.to_string())),
+                        Some((_, pattern)) => bail!("unsupported path pattern {} in {}", pattern, template),
+                        None => Ok(Segment::Variable(var.to_string())),
+                    },
+                    None => Ok(Segment::Literal(segment.to_string())),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { segments })
+    }
+
+    /// Returns the decoded path variables when `path` matches the template.
+    pub fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        if parts.len() != self.segments.len() {
+            return None;
+        }
+
+        let mut bindings = Vec::new();
+        for (segment, part) in self.segments.iter().zip(parts) {
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Literal(_) => return None,
+                Segment::Variable(_) if part.is_empty() => return None,
+                Segment::Variable(name) => {
+                    let value = percent_decode_str(part).decode_utf8().ok()?;
+                    bindings.push((name.clone(), value.into_owned()));
+                }
+            }
+        }
+
+        Some(bindings)
+    }
+
+    fn literal_count(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|segment| matches!(segment, Segment::Literal(_)))
+            .count()
+    }
+}
+
+pub struct Route {
+    pub http_method: Method,
+    pub template: PathTemplate,
+    pub body: BodyBinding,
+    pub rpc: MethodDescriptor,
+    /// gRPC request path, e.g. `/selfie.post.v1.PostService/GetPost`
+    pub grpc_path: String,
+}
+
+/// HTTP routes for every RPC annotated with `google.api.http` in the gateway's descriptor set.
+pub struct RouteTable {
+    routes: Vec<Route>,
+}
+
+impl RouteTable {
+    pub fn from_descriptor_set(bytes: &[u8]) -> Result<Self> {
+        let pool = DescriptorPool::decode(bytes).context("invalid file descriptor set")?;
+        let http_rule = pool
+            .get_extension_by_name(HTTP_RULE_EXTENSION)
+            .context("descriptor set does not include google/api/annotations.proto")?;
+
+        let mut routes = Vec::new();
+        for service in pool.services() {
+            for rpc in service.methods() {
+                let options = rpc.options();
+                if !options.has_extension(&http_rule) {
+                    continue;
+                }
+
+                if let Value::Message(rule) = options.get_extension(&http_rule).as_ref() {
+                    let grpc_path = format!("/{}/{}", service.full_name(), rpc.name());
+                    add_rule(&mut routes, rule, &rpc, &grpc_path, true)?;
+                }
+            }
+        }
+
+        // Prefer the most specific template when several share a shape, e.g.
+        // `/v1/media/upload-url` over `/v1/media/{media_id}`
+        routes.sort_by_key(|route| Reverse(route.template.literal_count()));
+
+        Ok(Self { routes })
+    }
+
+    pub fn find(&self, method: &Method, path: &str) -> Option<(&Route, Vec<(String, String)>)> {
+        self.routes
+            .iter()
+            .filter(|route| route.http_method == method)
+            .find_map(|route| route.template.matches(path).map(|bindings| (route, bindings)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+}
+
+fn add_rule(
+    routes: &mut Vec<Route>,
+    rule: &DynamicMessage,
+    rpc: &MethodDescriptor,
+    grpc_path: &str,
+    allow_additional: bool,
+) -> Result<()> {
+    let verbs = [
+        ("get", Method::GET),
+        ("put", Method::PUT),
+        ("post", Method::POST),
+        ("delete", Method::DELETE),
+        ("patch", Method::PATCH),
+    ];
+
+    let pattern = verbs.into_iter().find_map(|(field, method)| {
+        match rule.get_field_by_name(field).as_deref() {
+            Some(Value::String(path)) if !path.is_empty() => Some((method, path.clone())),
+            _ => None,
+        }
+    });
+
+    let Some((http_method, path)) = pattern else {
+        bail!("{} has an http rule without a supported verb", rpc.full_name());
+    };
+
+    let body = match rule.get_field_by_name("body").as_deref() {
+        Some(Value::String(body)) if body == "*" => BodyBinding::Message,
+        Some(Value::String(body)) if !body.is_empty() => {
+            if rpc.input().get_field_by_name(body).is_none() {
+                bail!("{} binds its body to unknown field {}", rpc.full_name(), body);
+            }
+            BodyBinding::Field(body.clone())
+        }
+        _ => BodyBinding::None,
+    };
+
+    routes.push(Route {
+        http_method,
+        template: PathTemplate::parse(&path)?,
+        body,
+        rpc: rpc.clone(),
+        grpc_path: grpc_path.to_string(),
+    });
+
+    if allow_additional {
+        if let Some(Value::List(bindings)) = rule.get_field_by_name("additional_bindings").as_deref() {
+            for binding in bindings {
+                if let Value::Message(binding) = binding {
+                    add_rule(routes, binding, rpc, grpc_path, false)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,119 +1,138 @@
 use tonic::{Request, Response, Status};
-use tracing::{info, error};
 use futures::Stream;
 use std::pin::Pin;
 
-use crate::proxy::ServiceProxies;
-use super::{GatewayServer, map_error, get_auth_token};
+use crate::error::GatewayError;
+use crate::proxy::Upstream;
+use crate::shutdown::until_shutdown;
+use super::GatewayServer;
+use super::identity::{bind_caller, caller_bound, CallerBound};
 
 // Generated protobuf code
 tonic::include_proto!("selfie.chat.v1");
 
+// Identity fields bound to the authenticated caller
+caller_bound! {
+    GetChatRequest { user_id: enforce }
+    ListChatsRequest { user_id: enforce }
+    SendMessageRequest { sender_id: enforce }
+    GetMessagesRequest { user_id: enforce }
+    MarkAsReadRequest { user_id: enforce }
+    DeleteMessageRequest { user_id: enforce }
+    StreamMessagesRequest { user_id: enforce }
+}
+
+// The creator is always a participant of the chat they create
+impl CallerBound for CreateChatRequest {
+    fn bind_caller(&mut self, caller: &str) -> Result<(), GatewayError> {
+        if !self.participant_ids.iter().any(|id| id == caller) {
+            self.participant_ids.push(caller.to_string());
+        }
+        Ok(())
+    }
+}
+
 #[tonic::async_trait]
 impl chat_service_server::ChatService for GatewayServer {
     async fn create_chat(
         &self,
-        request: Request<CreateChatRequest>
+        mut request: Request<CreateChatRequest>
     ) -> Result<Response<CreateChatResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.chat.write().await;
-        client
-            .create_chat(request)
-            .await
-            .map_err(map_error)
+        self.call_upstream(Upstream::Chat, "CreateChat", &self.proxies.chat, request, |mut client, request| async move {
+            client.create_chat(request).await
+        })
+        .await
     }
 
     async fn get_chat(
         &self,
-        request: Request<GetChatRequest>
+        mut request: Request<GetChatRequest>
     ) -> Result<Response<GetChatResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.chat.write().await;
-        client
-            .get_chat(request)
-            .await
-            .map_err(map_error)
+        self.call_upstream(Upstream::Chat, "GetChat", &self.proxies.chat, request, |mut client, request| async move {
+            client.get_chat(request).await
+        })
+        .await
     }
 
     async fn list_chats(
         &self,
-        request: Request<ListChatsRequest>
+        mut request: Request<ListChatsRequest>
     ) -> Result<Response<ListChatsResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.chat.write().await;
-        client
-            .list_chats(request)
-            .await
-            .map_err(map_error)
+        self.call_upstream(Upstream::Chat, "ListChats", &self.proxies.chat, request, |mut client, request| async move {
+            client.list_chats(request).await
+        })
+        .await
     }
 
     async fn send_message(
         &self,
-        request: Request<SendMessageRequest>
+        mut request: Request<SendMessageRequest>
     ) -> Result<Response<SendMessageResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.chat.write().await;
-        client
-            .send_message(request)
-            .await
-            .map_err(map_error)
+        self.call_upstream(Upstream::Chat, "SendMessage", &self.proxies.chat, request, |mut client, request| async move {
+            client.send_message(request).await
+        })
+        .await
     }
 
     async fn get_messages(
         &self,
-        request: Request<GetMessagesRequest>
+        mut request: Request<GetMessagesRequest>
     ) -> Result<Response<GetMessagesResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.chat.write().await;
-        client
-            .get_messages(request)
-            .await
-            .map_err(map_error)
+        self.call_upstream(Upstream::Chat, "GetMessages", &self.proxies.chat, request, |mut client, request| async move {
+            client.get_messages(request).await
+        })
+        .await
     }
 
     async fn mark_as_read(
         &self,
-        request: Request<MarkAsReadRequest>
+        mut request: Request<MarkAsReadRequest>
     ) -> Result<Response<MarkAsReadResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.chat.write().await;
-        client
-            .mark_as_read(request)
-            .await
-            .map_err(map_error)
+        self.call_upstream(Upstream::Chat, "MarkAsRead", &self.proxies.chat, request, |mut client, request| async move {
+            client.mark_as_read(request).await
+        })
+        .await
     }
 
     async fn delete_message(
         &self,
-        request: Request<DeleteMessageRequest>
+        mut request: Request<DeleteMessageRequest>
     ) -> Result<Response<DeleteMessageResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.chat.write().await;
-        client
-            .delete_message(request)
-            .await
-            .map_err(map_error)
+        self.call_upstream(Upstream::Chat, "DeleteMessage", &self.proxies.chat, request, |mut client, request| async move {
+            client.delete_message(request).await
+        })
+        .await
     }
 
     type StreamMessagesStream = Pin<Box<dyn Stream<Item = Result<StreamMessagesResponse, Status>> + Send + 'static>>;
 
     async fn stream_messages(
         &self,
-        request: Request<StreamMessagesRequest>
+        mut request: Request<StreamMessagesRequest>
     ) -> Result<Response<Self::StreamMessagesStream>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.chat.write().await;
-        client
-            .stream_messages(request)
-            .await
-            .map_err(map_error)
+        let response = self.call_upstream(Upstream::Chat, "StreamMessages", &self.proxies.chat, request, |mut client, request| async move {
+            client.stream_messages(request).await
+        })
+        .await?;
+
+        // The upstream stream is forwarded as-is and holds no shared client state; it is cut
+        // short when the gateway shuts down so the client resumes elsewhere
+        Ok(response.map(|stream| Box::pin(until_shutdown(stream, &self.shutdown)) as Self::StreamMessagesStream))
     }
 }
\ No newline at end of file
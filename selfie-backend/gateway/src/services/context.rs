@@ -0,0 +1,81 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::time::Instant;
+use futures::stream::{self, StreamExt};
+use tonic::metadata::MetadataMap;
+use tonic::{Request, Status};
+use tracing::debug;
+
+use crate::middleware::AuthenticatedUser;
+use crate::proxy::resilience::caller_deadline;
+
+// Lookups of one kind run concurrently, at most this many at a time
+const FANOUT_CONCURRENCY: usize = 16;
+
+/// Caller, correlation headers and deadline of an incoming call, applied to every call the
+/// gateway makes on its behalf. Later calls get whatever is left of the caller's deadline.
+#[derive(Clone)]
+pub(crate) struct CallContext {
+    metadata: MetadataMap,
+    caller: Option<AuthenticatedUser>,
+    deadline: Option<Instant>,
+}
+
+impl CallContext {
+    pub(crate) fn new(metadata: MetadataMap, caller: Option<AuthenticatedUser>) -> Self {
+        let deadline = caller_deadline(&metadata).map(|timeout| Instant::now() + timeout);
+        Self { metadata, caller, deadline }
+    }
+
+    pub(crate) fn of<T>(parent: &Request<T>) -> Self {
+        Self::new(parent.metadata().clone(), parent.extensions().get::<AuthenticatedUser>().cloned())
+    }
+
+    pub(crate) fn caller(&self) -> Option<&AuthenticatedUser> {
+        self.caller.as_ref()
+    }
+
+    pub(crate) fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::from_parts(self.metadata.clone(), Default::default(), message);
+        if let Some(caller) = &self.caller {
+            request.extensions_mut().insert(caller.clone());
+        }
+        if let Some(deadline) = self.deadline {
+            request.set_timeout(deadline.saturating_duration_since(Instant::now()));
+        }
+        request
+    }
+}
+
+/// Looks up each distinct id once; ids that failed or came back empty are returned alongside.
+pub(crate) async fn fetch_all<V, F, Fut>(ids: impl Iterator<Item = String>, fetch: F) -> (HashMap<String, V>, Vec<String>)
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<Option<V>, Status>>,
+{
+    let ids: HashSet<String> = ids.filter(|id| !id.is_empty()).collect();
+    let results: Vec<(String, Result<Option<V>, Status>)> = stream::iter(ids)
+        .map(|id| {
+            let lookup = fetch(id.clone());
+            async move { (id, lookup.await) }
+        })
+        .buffer_unordered(FANOUT_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut found = HashMap::new();
+    let mut missing = Vec::new();
+    for (id, result) in results {
+        match result {
+            Ok(Some(value)) => {
+                found.insert(id, value);
+            }
+            Ok(None) => missing.push(id),
+            Err(status) => {
+                debug!("Lookup of {} failed: {}", id, status.message());
+                missing.push(id);
+            }
+        }
+    }
+    (found, missing)
+}
@@ -0,0 +1,63 @@
+use tonic::Request;
+
+use crate::error::GatewayError;
+use crate::middleware::AuthenticatedUser;
+
+/// Binds the client-supplied identity fields of a request message to the authenticated caller.
+///
+/// Implemented per RPC (usually through `caller_bound!`) so downstream services can trust
+/// `user_id`, `sender_id` and `viewer_id` as forwarded by the gateway.
+pub(crate) trait CallerBound {
+    fn bind_caller(&mut self, caller: &str) -> Result<(), GatewayError>;
+}
+
+/// The field names the acting user: it is filled in when empty and rejected when it names
+/// someone else.
+pub(crate) fn enforce(field: &mut String, caller: &str, name: &str) -> Result<(), GatewayError> {
+    if field.is_empty() {
+        *field = caller.to_string();
+    } else if field != caller {
+        return Err(GatewayError::PermissionDenied(format!(
+            "{} does not match the authenticated user",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// The field only describes who is looking, so it is always replaced with the caller.
+pub(crate) fn overwrite(field: &mut String, caller: &str, _name: &str) -> Result<(), GatewayError> {
+    *field = caller.to_string();
+    Ok(())
+}
+
+/// The user attached by `AuthMiddleware`.
+pub(crate) fn caller<T>(request: &Request<T>) -> Result<String, GatewayError> {
+    request
+        .extensions()
+        .get::<AuthenticatedUser>()
+        .map(|user| user.user_id.clone())
+        .ok_or_else(|| GatewayError::AuthenticationFailed("Missing caller identity".to_string()))
+}
+
+/// Applies the message's identity rules using the user attached by `AuthMiddleware`.
+pub(crate) fn bind_caller<T: CallerBound>(request: &mut Request<T>) -> Result<(), GatewayError> {
+    let caller = caller(request)?;
+    request.get_mut().bind_caller(&caller)
+}
+
+/// Declares the identity rule (`enforce` or `overwrite`) for each caller field of a message.
+macro_rules! caller_bound {
+    ($($message:ty { $($field:ident: $rule:ident),+ $(,)? })*) => {
+        $(
+            impl $crate::services::identity::CallerBound for $message {
+                fn bind_caller(&mut self, caller: &str) -> Result<(), $crate::error::GatewayError> {
+                    $($crate::services::identity::$rule(&mut self.$field, caller, stringify!($field))?;)+
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+pub(crate) use caller_bound;
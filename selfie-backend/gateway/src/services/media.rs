@@ -1,83 +1,262 @@
-use tonic::{Request, Response, Status};
-use tracing::{info, error};
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Duration;
+use futures::future::{self, Either};
+use futures::{Stream, StreamExt};
+use tokio::sync::oneshot;
+use tonic::{Request, Response, Status, Streaming};
 
-use crate::proxy::ServiceProxies;
-use super::{GatewayServer, map_error, get_auth_token};
+use crate::config::MediaConfig;
+use crate::error::GatewayError;
+use crate::proxy::Upstream;
+use super::GatewayServer;
+use super::identity::{bind_caller, caller, caller_bound, CallerBound};
 
 // Generated protobuf code
 tonic::include_proto!("selfie.media.v1");
 
+// Identity fields bound to the authenticated caller
+caller_bound! {
+    UploadMediaRequest { user_id: enforce }
+    UploadMetadata { user_id: enforce }
+    DeleteMediaRequest { user_id: enforce }
+    GetUploadUrlRequest { user_id: enforce }
+}
+
+use upload_media_stream_request::Payload;
+
+/// Per-`MediaType` size caps, checked before any upload reaches media-service.
+pub struct UploadLimits {
+    limits: RwLock<Limits>,
+}
+
+struct Limits {
+    max_bytes: HashMap<String, u64>,
+    timeout: Duration,
+}
+
+impl UploadLimits {
+    pub fn new(config: &MediaConfig) -> Self {
+        Self {
+            limits: RwLock::new(Limits::new(config)),
+        }
+    }
+
+    /// Applies to uploads started from now on.
+    pub fn reload(&self, config: &MediaConfig) {
+        *self.limits.write().expect("upload limits poisoned") = Limits::new(config);
+    }
+
+    fn timeout(&self) -> Duration {
+        self.limits.read().expect("upload limits poisoned").timeout
+    }
+
+    fn check(&self, media_type: i32, size: u64) -> Result<(), GatewayError> {
+        // MEDIA_TYPE_VIDEO is configured as `video`
+        let kind = MediaType::try_from(media_type)
+            .ok()
+            .filter(|kind| *kind != MediaType::Unspecified)
+            .map(|kind| kind.as_str_name().trim_start_matches("MEDIA_TYPE_").to_ascii_lowercase())
+            .ok_or_else(|| GatewayError::InvalidRequest("unsupported media type".to_string()))?;
+
+        let max_bytes = self.limits.read().expect("upload limits poisoned").max_bytes.get(&kind).copied();
+        match max_bytes {
+            Some(max) if size <= max => Ok(()),
+            Some(max) => Err(GatewayError::InvalidRequest(format!(
+                "{} uploads are limited to {} bytes",
+                kind, max
+            ))),
+            None => Err(GatewayError::InvalidRequest(format!("{} uploads are not accepted", kind))),
+        }
+    }
+}
+
+impl Limits {
+    fn new(config: &MediaConfig) -> Self {
+        Self {
+            max_bytes: config
+                .max_upload_bytes
+                .iter()
+                .map(|(kind, max)| (kind.to_ascii_lowercase(), *max))
+                .collect(),
+            timeout: Duration::from_secs(config.upload_timeout_secs),
+        }
+    }
+}
+
+impl UploadMetadata {
+    // The handshake media-service verifies the received file against
+    fn validate(&self) -> Result<(), GatewayError> {
+        if self.content_length <= 0 {
+            return Err(GatewayError::InvalidRequest("content_length must be positive".to_string()));
+        }
+        if self.sha256.len() != 64 || !self.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(GatewayError::InvalidRequest("sha256 must be a hex-encoded SHA-256 digest".to_string()));
+        }
+        Ok(())
+    }
+}
+
+// Passes the client's chunks on as they arrive. A chunk past the declared content length, a
+// second metadata message or a client error is reported on `violation` and stalls the stream
+// instead of ending it, so media-service never sees a cleanly finished upload.
+fn forward_chunks(
+    incoming: Streaming<UploadMediaStreamRequest>,
+    content_length: u64,
+    violation: oneshot::Sender<Status>,
+) -> impl Stream<Item = UploadMediaStreamRequest> + Send + 'static {
+    let mut violation = Some(violation);
+    let mut received = 0u64;
+
+    incoming.filter_map(move |message| {
+        let chunk = match message {
+            Ok(UploadMediaStreamRequest { payload: Some(Payload::Chunk(chunk)) }) => {
+                received += chunk.len() as u64;
+                if received <= content_length {
+                    Ok(chunk)
+                } else {
+                    Err(GatewayError::InvalidRequest("upload is larger than its content_length".to_string()).into())
+                }
+            }
+            Ok(_) => Err(GatewayError::InvalidRequest("expected a chunk after the upload metadata".to_string()).into()),
+            Err(status) => Err(status),
+        };
+
+        match chunk {
+            Ok(chunk) => Either::Left(future::ready(Some(UploadMediaStreamRequest {
+                payload: Some(Payload::Chunk(chunk)),
+            }))),
+            Err(status) => {
+                if let Some(violation) = violation.take() {
+                    let _ = violation.send(status);
+                }
+                Either::Right(future::pending())
+            }
+        }
+    })
+}
+
 #[tonic::async_trait]
 impl media_service_server::MediaService for GatewayServer {
     async fn upload_media(
         &self,
-        request: Request<UploadMediaRequest>
+        mut request: Request<UploadMediaRequest>
     ) -> Result<Response<UploadMediaResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
+        self.upload_limits.check(request.get_ref().r#type, request.get_ref().data.len() as u64)?;
         
-        let mut client = self.proxies.media.write().await;
-        client
-            .upload_media(request)
-            .await
-            .map_err(map_error)
+        self.call_upstream(Upstream::Media, "UploadMedia", &self.proxies.media, request, |mut client, request| async move {
+            client.upload_media(request).await
+        })
+        .await
     }
 
     async fn get_media(
         &self,
         request: Request<GetMediaRequest>
     ) -> Result<Response<GetMediaResponse>, Status> {
-        let mut client = self.proxies.media.write().await;
-        client
-            .get_media(request)
+        let tags = vec![format!("media:{}", request.get_ref().media_id)];
+        self.cache
+            .get_or_fetch("GetMedia", tags, request, |request| {
+                self.call_upstream(Upstream::Media, "GetMedia", &self.proxies.media, request, |mut client, request| async move {
+                    client.get_media(request).await
+                })
+            })
             .await
-            .map_err(map_error)
     }
 
     async fn delete_media(
         &self,
-        request: Request<DeleteMediaRequest>
+        mut request: Request<DeleteMediaRequest>
     ) -> Result<Response<DeleteMediaResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.media.write().await;
-        client
-            .delete_media(request)
-            .await
-            .map_err(map_error)
+        let tags = vec![format!("media:{}", request.get_ref().media_id)];
+        let response = self.call_upstream(Upstream::Media, "DeleteMedia", &self.proxies.media, request, |mut client, request| async move {
+            client.delete_media(request).await
+        })
+        .await?;
+
+        self.cache.invalidate(&tags);
+        Ok(response)
     }
 
     async fn generate_thumbnail(
         &self,
         request: Request<GenerateThumbnailRequest>
     ) -> Result<Response<GenerateThumbnailResponse>, Status> {
-        let mut client = self.proxies.media.write().await;
-        client
-            .generate_thumbnail(request)
-            .await
-            .map_err(map_error)
+        let tags = vec![format!("media:{}", request.get_ref().media_id)];
+        let response = self.call_upstream(Upstream::Media, "GenerateThumbnail", &self.proxies.media, request, |mut client, request| async move {
+            client.generate_thumbnail(request).await
+        })
+        .await?;
+
+        self.cache.invalidate(&tags);
+        Ok(response)
     }
 
     async fn optimize_media(
         &self,
         request: Request<OptimizeMediaRequest>
     ) -> Result<Response<OptimizeMediaResponse>, Status> {
-        let mut client = self.proxies.media.write().await;
-        client
-            .optimize_media(request)
-            .await
-            .map_err(map_error)
+        let tags = vec![format!("media:{}", request.get_ref().media_id)];
+        let response = self.call_upstream(Upstream::Media, "OptimizeMedia", &self.proxies.media, request, |mut client, request| async move {
+            client.optimize_media(request).await
+        })
+        .await?;
+
+        self.cache.invalidate(&tags);
+        Ok(response)
     }
 
     async fn get_upload_url(
         &self,
-        request: Request<GetUploadUrlRequest>
+        mut request: Request<GetUploadUrlRequest>
     ) -> Result<Response<GetUploadUrlResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.media.write().await;
-        client
-            .get_upload_url(request)
-            .await
-            .map_err(map_error)
+        self.call_upstream(Upstream::Media, "GetUploadUrl", &self.proxies.media, request, |mut client, request| async move {
+            client.get_upload_url(request).await
+        })
+        .await
+    }
+
+    async fn upload_media_stream(
+        &self,
+        request: Request<Streaming<UploadMediaStreamRequest>>
+    ) -> Result<Response<UploadMediaResponse>, Status> {
+        let caller = caller(&request)?;
+        let (metadata, extensions, mut incoming) = request.into_parts();
+
+        // Refuse oversized or malformed uploads before any file data is read
+        let mut upload = match incoming.message().await? {
+            Some(UploadMediaStreamRequest { payload: Some(Payload::Metadata(upload)) }) => upload,
+            _ => return Err(GatewayError::InvalidRequest("upload must start with its metadata".to_string()).into()),
+        };
+        upload.bind_caller(&caller)?;
+        upload.validate()?;
+        self.upload_limits.check(upload.r#type, upload.content_length as u64)?;
+
+        let (violation_tx, violation_rx) = oneshot::channel();
+        let chunks = forward_chunks(incoming, upload.content_length as u64, violation_tx);
+        let first = UploadMediaStreamRequest { payload: Some(Payload::Metadata(upload)) };
+        let outgoing = futures::stream::once(future::ready(first)).chain(chunks);
+        let request = Request::from_parts(metadata, extensions, outgoing);
+
+        let upload = self.call_upstream_once(
+            Upstream::Media,
+            "UploadMediaStream",
+            self.upload_limits.timeout(),
+            &self.proxies.media,
+            request,
+            |mut client, request| async move { client.upload_media_stream(request).await },
+        );
+
+        // Dropping the upstream call on a violation resets its stream, abandoning the upload
+        tokio::select! {
+            biased;
+            Ok(status) = violation_rx => Err(status),
+            result = upload => result,
+        }
     }
-}
\ No newline at end of file
+}
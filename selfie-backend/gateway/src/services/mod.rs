@@ -1,59 +1,119 @@
-use tonic::{Request, Response, Status};
-use tracing::{error, info};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Request, Response, Status};
+use tracing::{debug, error};
+
+use crate::cache::ResponseCache;
+use crate::proxy::{Upstream, UpstreamError};
+use crate::shutdown::Shutdown;
 
 pub mod auth;
 pub mod user;
 pub mod post;
 pub mod media;
 pub mod chat;
+pub mod context;
+pub mod page;
+pub mod identity;
+
+/// Descriptors for every gateway proto, emitted by build.rs.
+pub(crate) const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("selfie_descriptor");
 
 #[derive(Clone)]
 pub struct GatewayServer {
     proxies: crate::proxy::ServiceProxies,
+    cache: Arc<ResponseCache>,
+    upload_limits: Arc<media::UploadLimits>,
+    shutdown: Shutdown,
 }
 
 impl GatewayServer {
-    pub fn new(proxies: crate::proxy::ServiceProxies) -> Self {
-        Self { proxies }
+    pub fn new(
+        proxies: crate::proxy::ServiceProxies,
+        cache: Arc<ResponseCache>,
+        upload_limits: Arc<media::UploadLimits>,
+        shutdown: Shutdown,
+    ) -> Self {
+        Self { proxies, cache, upload_limits, shutdown }
     }
-}
 
-// Helper function to map errors to gRPC status
-pub(crate) fn map_error(err: impl std::error::Error) -> Status {
-    error!("Service error: {}", err);
-    match err.to_string() {
-        e if e.contains("not found") => Status::not_found(e),
-        e if e.contains("invalid") => Status::invalid_argument(e),
-        e if e.contains("unauthenticated") => Status::unauthenticated(e),
-        e if e.contains("permission denied") => Status::permission_denied(e),
-        e => Status::internal(format!("Internal error: {}", e)),
+    /// Calls `rpc` on an upstream service with the gateway's deadline, retry and circuit
+    /// breaker policy. `call` may run more than once, each time with a fresh client clone.
+    pub(crate) async fn call_upstream<C, Req, Resp, F, Fut>(
+        &self,
+        upstream: Upstream,
+        rpc: &'static str,
+        client: &C,
+        request: Request<Req>,
+        call: F,
+    ) -> Result<Response<Resp>, Status>
+    where
+        C: Clone,
+        Req: Clone,
+        F: Fn(C, Request<Req>) -> Fut,
+        Fut: Future<Output = Result<Response<Resp>, Status>>,
+    {
+        self.proxies
+            .resilience
+            .call(upstream, rpc, client.clone(), request, call)
+            .await
+            .map_err(upstream_status)
     }
-}
 
-// Helper function to extract metadata from request
-pub(crate) fn get_auth_token(req: &Request<()>) -> Result<String, Status> {
-    let metadata = req.metadata();
-    let auth_header = metadata
-        .get("authorization")
-        .ok_or_else(|| Status::unauthenticated("Missing authorization header"))?;
-    
-    let auth_str = auth_header
-        .to_str()
-        .map_err(|_| Status::unauthenticated("Invalid authorization header"))?;
-
-    if !auth_str.starts_with("Bearer ") {
-        return Err(Status::unauthenticated("Invalid authorization format"));
+    /// `call_upstream` for requests that can't be replayed, such as client streams. `timeout`
+    /// applies when the client sets no deadline.
+    pub(crate) async fn call_upstream_once<C, Req, Resp, F, Fut>(
+        &self,
+        upstream: Upstream,
+        rpc: &'static str,
+        timeout: Duration,
+        client: &C,
+        request: Request<Req>,
+        call: F,
+    ) -> Result<Response<Resp>, Status>
+    where
+        C: Clone,
+        F: FnOnce(C, Request<Req>) -> Fut,
+        Fut: Future<Output = Result<Response<Resp>, Status>>,
+    {
+        let client = client.clone();
+        self.proxies
+            .resilience
+            .call_once(upstream, rpc, timeout, request, |request| call(client, request))
+            .await
+            .map_err(upstream_status)
     }
+}
 
-    Ok(auth_str[7..].to_string())
+fn upstream_status(err: UpstreamError) -> Status {
+    match err {
+        UpstreamError::Gateway(err) => err.into(),
+        UpstreamError::Upstream(status) => map_error(status),
+    }
 }
 
-// Helper trait for proxy response mapping
-#[async_trait::async_trait]
-pub(crate) trait ProxyService {
-    type Request;
-    type Response;
-    type Error: std::error::Error;
+// Response headers that belong to the upstream hop rather than to the error itself
+const HOP_HEADERS: &[&str] = &["content-type", "date", "server", "grpc-encoding", "grpc-accept-encoding"];
 
-    async fn proxy_call(&self, req: Request<Self::Request>) -> Result<Response<Self::Response>, Status>;
-}
\ No newline at end of file
+// Passes upstream errors through with their original code, message, details and metadata
+pub(crate) fn map_error(status: Status) -> Status {
+    if matches!(status.code(), Code::Internal | Code::Unknown | Code::Unavailable | Code::DataLoss) {
+        error!("Upstream error: {:?} {}", status.code(), status.message());
+    } else {
+        debug!("Upstream returned {:?}: {}", status.code(), status.message());
+    }
+
+    let mut headers = status.metadata().clone().into_headers();
+    for header in HOP_HEADERS {
+        headers.remove(*header);
+    }
+
+    Status::with_details_and_metadata(
+        status.code(),
+        status.message(),
+        status.details().to_vec().into(),
+        MetadataMap::from_headers(headers),
+    )
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use metrics::counter;
+use tonic::{Request, Response, Status};
+use tracing::debug;
+
+use super::GatewayServer;
+use super::context::{fetch_all, CallContext};
+use super::identity::{bind_caller, caller_bound};
+use super::media::media_service_server::MediaService;
+use super::media::{GetMediaRequest, Media};
+use super::post::post_service_server::PostService;
+use super::post::{GetFeedRequest, ListUserPostsRequest, Post};
+use super::user::user_service_server::UserService;
+use super::user::{GetProfileRequest, GetUserRequest, User};
+
+// Generated code refers to the other selfie packages by relative path
+// (`super::super::post::v1`), so the package tree is mirrored here
+mod packages {
+    pub mod common {
+        pub use crate::common::v1;
+    }
+    pub mod media {
+        pub use crate::services::media as v1;
+    }
+    pub mod post {
+        pub use crate::services::post as v1;
+    }
+    pub mod user {
+        pub use crate::services::user as v1;
+    }
+    pub mod gateway {
+        pub mod v1 {
+            tonic::include_proto!("selfie.gateway.v1");
+        }
+    }
+}
+
+pub use packages::gateway::v1::*;
+
+// Identity fields bound to the authenticated caller
+caller_bound! {
+    GetHydratedFeedRequest { user_id: enforce }
+    GetProfilePageRequest { viewer_id: overwrite }
+}
+
+#[tonic::async_trait]
+impl page_service_server::PageService for GatewayServer {
+    async fn get_hydrated_feed(
+        &self,
+        mut request: Request<GetHydratedFeedRequest>
+    ) -> Result<Response<GetHydratedFeedResponse>, Status> {
+        bind_caller(&mut request)?;
+        let context = CallContext::of(&request);
+        let GetHydratedFeedRequest { user_id, pagination } = request.into_inner();
+
+        // Without the feed itself there is no page to degrade to
+        let feed = self
+            .get_feed(context.request(GetFeedRequest { user_id, pagination }))
+            .await?
+            .into_inner();
+
+        let ((authors, missing_authors), (media, missing_media)) = tokio::join!(
+            self.load_authors(&context, feed.posts.iter().map(|post| post.user_id.clone())),
+            self.load_media(&context, &feed.posts),
+        );
+
+        let mut unavailable = Vec::new();
+        degraded("GetHydratedFeed", "user", missing_authors, &mut unavailable);
+        degraded("GetHydratedFeed", "media", missing_media, &mut unavailable);
+
+        let posts = feed
+            .posts
+            .into_iter()
+            .map(|post| {
+                let author = authors.get(&post.user_id).map(author_summary);
+                hydrate(post, author, &media)
+            })
+            .collect();
+
+        Ok(Response::new(GetHydratedFeedResponse {
+            posts,
+            pagination: feed.pagination,
+            unavailable,
+        }))
+    }
+
+    async fn get_profile_page(
+        &self,
+        mut request: Request<GetProfilePageRequest>
+    ) -> Result<Response<GetProfilePageResponse>, Status> {
+        bind_caller(&mut request)?;
+        let context = CallContext::of(&request);
+        let GetProfilePageRequest { user_id, viewer_id, pagination } = request.into_inner();
+
+        let (profile, posts) = tokio::join!(
+            self.get_profile(context.request(GetProfileRequest {
+                user_id: user_id.clone(),
+                viewer_id: viewer_id.clone(),
+            })),
+            self.list_user_posts(context.request(ListUserPostsRequest {
+                user_id,
+                viewer_id,
+                pagination,
+            })),
+        );
+
+        // The profile is what the page is about; its posts are not
+        let profile = profile?.into_inner().profile;
+        let mut unavailable = Vec::new();
+        let (posts, pagination) = match posts {
+            Ok(response) => {
+                let response = response.into_inner();
+                (response.posts, response.pagination)
+            }
+            Err(status) => {
+                debug!("Profile page posts unavailable: {}", status.message());
+                degraded("GetProfilePage", "posts", vec![String::new()], &mut unavailable);
+                (Vec::new(), None)
+            }
+        };
+
+        let (media, missing_media) = self.load_media(&context, &posts).await;
+        degraded("GetProfilePage", "media", missing_media, &mut unavailable);
+
+        // Every post is by the profile's user, so the author comes with the profile
+        let author = profile.as_ref().and_then(|profile| profile.user.as_ref()).map(author_summary);
+        let posts = posts
+            .into_iter()
+            .map(|post| hydrate(post, author.clone(), &media))
+            .collect();
+
+        Ok(Response::new(GetProfilePageResponse {
+            profile,
+            posts,
+            pagination,
+            unavailable,
+        }))
+    }
+}
+
+impl GatewayServer {
+    async fn load_authors(
+        &self,
+        context: &CallContext,
+        user_ids: impl Iterator<Item = String>,
+    ) -> (HashMap<String, User>, Vec<String>) {
+        fetch_all(user_ids, |user_id| async move {
+            self.get_user(context.request(GetUserRequest { user_id }))
+                .await
+                .map(|response| response.into_inner().user)
+        })
+        .await
+    }
+
+    async fn load_media(&self, context: &CallContext, posts: &[Post]) -> (HashMap<String, Media>, Vec<String>) {
+        let media_ids = posts.iter().flat_map(|post| post.media_ids.iter().cloned());
+        fetch_all(media_ids, |media_id| async move {
+            self.get_media(context.request(GetMediaRequest { media_id }))
+                .await
+                .map(|response| response.into_inner().media)
+        })
+        .await
+    }
+}
+
+// Records resources left out of a page as `<kind>:<id>`, or just `<kind>` for a whole section
+fn degraded(rpc: &'static str, kind: &'static str, ids: Vec<String>, unavailable: &mut Vec<String>) {
+    if ids.is_empty() {
+        return;
+    }
+    counter!("gateway_page_degraded_total", ids.len() as u64, "rpc" => rpc, "resource" => kind);
+
+    let mut entries: Vec<String> = ids
+        .into_iter()
+        .map(|id| if id.is_empty() { kind.to_string() } else { format!("{}:{}", kind, id) })
+        .collect();
+    entries.sort();
+    unavailable.extend(entries);
+}
+
+fn hydrate(post: Post, author: Option<AuthorSummary>, media: &HashMap<String, Media>) -> HydratedPost {
+    HydratedPost {
+        author,
+        media: post.media_ids.iter().filter_map(|id| media.get(id).cloned()).collect(),
+        post: Some(post),
+    }
+}
+
+fn author_summary(user: &User) -> AuthorSummary {
+    AuthorSummary {
+        id: user.id.clone(),
+        username: user.username.clone(),
+        display_name: user.display_name.clone(),
+        avatar_url: user.avatar_url.clone(),
+        verified: user.verified,
+    }
+}
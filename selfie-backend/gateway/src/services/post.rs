@@ -1,159 +1,194 @@
 use tonic::{Request, Response, Status};
-use tracing::{info, error};
 
-use crate::proxy::ServiceProxies;
-use super::{GatewayServer, map_error, get_auth_token};
+use crate::proxy::Upstream;
+use super::GatewayServer;
+use super::identity::{bind_caller, caller_bound};
 
 // Generated protobuf code
 tonic::include_proto!("selfie.post.v1");
 
+// Identity fields bound to the authenticated caller
+caller_bound! {
+    CreatePostRequest { user_id: enforce }
+    GetPostRequest { viewer_id: overwrite }
+    UpdatePostRequest { user_id: enforce }
+    DeletePostRequest { user_id: enforce }
+    ListUserPostsRequest { viewer_id: overwrite }
+    GetFeedRequest { user_id: enforce }
+    LikePostRequest { user_id: enforce }
+    UnlikePostRequest { user_id: enforce }
+    AddCommentRequest { user_id: enforce }
+    DeleteCommentRequest { user_id: enforce }
+}
+
 #[tonic::async_trait]
 impl post_service_server::PostService for GatewayServer {
     async fn create_post(
         &self,
-        request: Request<CreatePostRequest>
+        mut request: Request<CreatePostRequest>
     ) -> Result<Response<CreatePostResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.post.write().await;
-        client
-            .create_post(request)
-            .await
-            .map_err(map_error)
+        self.call_upstream(Upstream::Post, "CreatePost", &self.proxies.post, request, |mut client, request| async move {
+            client.create_post(request).await
+        })
+        .await
     }
 
     async fn get_post(
         &self,
-        request: Request<GetPostRequest>
+        mut request: Request<GetPostRequest>
     ) -> Result<Response<GetPostResponse>, Status> {
-        let mut client = self.proxies.post.write().await;
-        client
-            .get_post(request)
+        bind_caller(&mut request)?;
+
+        let tags = vec![format!("post:{}", request.get_ref().post_id)];
+        self.cache
+            .get_or_fetch("GetPost", tags, request, |request| {
+                self.call_upstream(Upstream::Post, "GetPost", &self.proxies.post, request, |mut client, request| async move {
+                    client.get_post(request).await
+                })
+            })
             .await
-            .map_err(map_error)
     }
 
     async fn update_post(
         &self,
-        request: Request<UpdatePostRequest>
+        mut request: Request<UpdatePostRequest>
     ) -> Result<Response<UpdatePostResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.post.write().await;
-        client
-            .update_post(request)
-            .await
-            .map_err(map_error)
+        let tags = vec![format!("post:{}", request.get_ref().post_id)];
+        let response = self.call_upstream(Upstream::Post, "UpdatePost", &self.proxies.post, request, |mut client, request| async move {
+            client.update_post(request).await
+        })
+        .await?;
+
+        self.cache.invalidate(&tags);
+        Ok(response)
     }
 
     async fn delete_post(
         &self,
-        request: Request<DeletePostRequest>
+        mut request: Request<DeletePostRequest>
     ) -> Result<Response<DeletePostResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.post.write().await;
-        client
-            .delete_post(request)
-            .await
-            .map_err(map_error)
+        let tags = vec![format!("post:{}", request.get_ref().post_id)];
+        let response = self.call_upstream(Upstream::Post, "DeletePost", &self.proxies.post, request, |mut client, request| async move {
+            client.delete_post(request).await
+        })
+        .await?;
+
+        self.cache.invalidate(&tags);
+        Ok(response)
     }
 
     async fn list_user_posts(
         &self,
-        request: Request<ListUserPostsRequest>
+        mut request: Request<ListUserPostsRequest>
     ) -> Result<Response<ListUserPostsResponse>, Status> {
-        let mut client = self.proxies.post.write().await;
-        client
-            .list_user_posts(request)
-            .await
-            .map_err(map_error)
+        bind_caller(&mut request)?;
+
+        self.call_upstream(Upstream::Post, "ListUserPosts", &self.proxies.post, request, |mut client, request| async move {
+            client.list_user_posts(request).await
+        })
+        .await
     }
 
     async fn get_feed(
         &self,
-        request: Request<GetFeedRequest>
+        mut request: Request<GetFeedRequest>
     ) -> Result<Response<GetFeedResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.post.write().await;
-        client
-            .get_feed(request)
-            .await
-            .map_err(map_error)
+        self.call_upstream(Upstream::Post, "GetFeed", &self.proxies.post, request, |mut client, request| async move {
+            client.get_feed(request).await
+        })
+        .await
     }
 
     async fn like_post(
         &self,
-        request: Request<LikePostRequest>
+        mut request: Request<LikePostRequest>
     ) -> Result<Response<LikePostResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.post.write().await;
-        client
-            .like_post(request)
-            .await
-            .map_err(map_error)
+        let tags = vec![format!("post:{}", request.get_ref().post_id)];
+        let response = self.call_upstream(Upstream::Post, "LikePost", &self.proxies.post, request, |mut client, request| async move {
+            client.like_post(request).await
+        })
+        .await?;
+
+        self.cache.invalidate(&tags);
+        Ok(response)
     }
 
     async fn unlike_post(
         &self,
-        request: Request<UnlikePostRequest>
+        mut request: Request<UnlikePostRequest>
     ) -> Result<Response<UnlikePostResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.post.write().await;
-        client
-            .unlike_post(request)
-            .await
-            .map_err(map_error)
+        let tags = vec![format!("post:{}", request.get_ref().post_id)];
+        let response = self.call_upstream(Upstream::Post, "UnlikePost", &self.proxies.post, request, |mut client, request| async move {
+            client.unlike_post(request).await
+        })
+        .await?;
+
+        self.cache.invalidate(&tags);
+        Ok(response)
     }
 
     async fn get_likes(
         &self,
         request: Request<GetLikesRequest>
     ) -> Result<Response<GetLikesResponse>, Status> {
-        let mut client = self.proxies.post.write().await;
-        client
-            .get_likes(request)
-            .await
-            .map_err(map_error)
+        self.call_upstream(Upstream::Post, "GetLikes", &self.proxies.post, request, |mut client, request| async move {
+            client.get_likes(request).await
+        })
+        .await
     }
 
     async fn add_comment(
         &self,
-        request: Request<AddCommentRequest>
+        mut request: Request<AddCommentRequest>
     ) -> Result<Response<AddCommentResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.post.write().await;
-        client
-            .add_comment(request)
-            .await
-            .map_err(map_error)
+        let tags = vec![format!("post:{}", request.get_ref().post_id)];
+        let response = self.call_upstream(Upstream::Post, "AddComment", &self.proxies.post, request, |mut client, request| async move {
+            client.add_comment(request).await
+        })
+        .await?;
+
+        self.cache.invalidate(&tags);
+        Ok(response)
     }
 
     async fn delete_comment(
         &self,
-        request: Request<DeleteCommentRequest>
+        mut request: Request<DeleteCommentRequest>
     ) -> Result<Response<DeleteCommentResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.post.write().await;
-        client
-            .delete_comment(request)
-            .await
-            .map_err(map_error)
+        // The request only names the comment, so the post to invalidate comes from the response
+        let response = self.call_upstream(Upstream::Post, "DeleteComment", &self.proxies.post, request, |mut client, request| async move {
+            client.delete_comment(request).await
+        })
+        .await?;
+
+        let tags = vec![format!("post:{}", response.get_ref().post_id)];
+        self.cache.invalidate(&tags);
+        Ok(response)
     }
 
     async fn get_comments(
         &self,
         request: Request<GetCommentsRequest>
     ) -> Result<Response<GetCommentsResponse>, Status> {
-        let mut client = self.proxies.post.write().await;
-        client
-            .get_comments(request)
-            .await
-            .map_err(map_error)
+        self.call_upstream(Upstream::Post, "GetComments", &self.proxies.post, request, |mut client, request| async move {
+            client.get_comments(request).await
+        })
+        .await
     }
 }
\ No newline at end of file
@@ -1,123 +1,157 @@
 use tonic::{Request, Response, Status};
-use tracing::{info, error};
 
-use crate::proxy::ServiceProxies;
-use super::{GatewayServer, map_error, get_auth_token};
+use crate::proxy::Upstream;
+use super::GatewayServer;
+use super::identity::{bind_caller, caller_bound};
 
 // Generated protobuf code
 tonic::include_proto!("selfie.user.v1");
 
+// Identity fields bound to the authenticated caller
+caller_bound! {
+    UpdateUserRequest { user_id: enforce }
+    UpdateAvatarRequest { user_id: enforce }
+    GetProfileRequest { viewer_id: overwrite }
+    UpdateProfileRequest { user_id: enforce }
+    SearchUsersRequest { viewer_id: overwrite }
+    BlockUserRequest { user_id: enforce }
+    UnblockUserRequest { user_id: enforce }
+    GetBlockedUsersRequest { user_id: enforce }
+}
+
 #[tonic::async_trait]
 impl user_service_server::UserService for GatewayServer {
     async fn get_user(
         &self,
         request: Request<GetUserRequest>
     ) -> Result<Response<GetUserResponse>, Status> {
-        let mut client = self.proxies.user.write().await;
-        client
-            .get_user(request)
+        let tags = vec![format!("user:{}", request.get_ref().user_id)];
+        self.cache
+            .get_or_fetch("GetUser", tags, request, |request| {
+                self.call_upstream(Upstream::User, "GetUser", &self.proxies.user, request, |mut client, request| async move {
+                    client.get_user(request).await
+                })
+            })
             .await
-            .map_err(map_error)
     }
 
     async fn update_user(
         &self,
-        request: Request<UpdateUserRequest>
+        mut request: Request<UpdateUserRequest>
     ) -> Result<Response<UpdateUserResponse>, Status> {
-        // Verify auth token
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.user.write().await;
-        client
-            .update_user(request)
-            .await
-            .map_err(map_error)
+        let tags = vec![format!("user:{}", request.get_ref().user_id)];
+        let response = self.call_upstream(Upstream::User, "UpdateUser", &self.proxies.user, request, |mut client, request| async move {
+            client.update_user(request).await
+        })
+        .await?;
+
+        self.cache.invalidate(&tags);
+        Ok(response)
     }
 
     async fn update_avatar(
         &self,
-        request: Request<UpdateAvatarRequest>
+        mut request: Request<UpdateAvatarRequest>
     ) -> Result<Response<UpdateAvatarResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.user.write().await;
-        client
-            .update_avatar(request)
-            .await
-            .map_err(map_error)
+        let tags = vec![format!("user:{}", request.get_ref().user_id)];
+        let response = self.call_upstream(Upstream::User, "UpdateAvatar", &self.proxies.user, request, |mut client, request| async move {
+            client.update_avatar(request).await
+        })
+        .await?;
+
+        self.cache.invalidate(&tags);
+        Ok(response)
     }
 
     async fn get_profile(
         &self,
-        request: Request<GetProfileRequest>
+        mut request: Request<GetProfileRequest>
     ) -> Result<Response<GetProfileResponse>, Status> {
-        let mut client = self.proxies.user.write().await;
-        client
-            .get_profile(request)
+        bind_caller(&mut request)?;
+
+        let tags = vec![format!("user:{}", request.get_ref().user_id)];
+        self.cache
+            .get_or_fetch("GetProfile", tags, request, |request| {
+                self.call_upstream(Upstream::User, "GetProfile", &self.proxies.user, request, |mut client, request| async move {
+                    client.get_profile(request).await
+                })
+            })
             .await
-            .map_err(map_error)
     }
 
     async fn update_profile(
         &self,
-        request: Request<UpdateProfileRequest>
+        mut request: Request<UpdateProfileRequest>
     ) -> Result<Response<UpdateProfileResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.user.write().await;
-        client
-            .update_profile(request)
-            .await
-            .map_err(map_error)
+        let tags = vec![format!("user:{}", request.get_ref().user_id)];
+        let response = self.call_upstream(Upstream::User, "UpdateProfile", &self.proxies.user, request, |mut client, request| async move {
+            client.update_profile(request).await
+        })
+        .await?;
+
+        self.cache.invalidate(&tags);
+        Ok(response)
     }
 
     async fn search_users(
         &self,
-        request: Request<SearchUsersRequest>
+        mut request: Request<SearchUsersRequest>
     ) -> Result<Response<SearchUsersResponse>, Status> {
-        let mut client = self.proxies.user.write().await;
-        client
-            .search_users(request)
-            .await
-            .map_err(map_error)
+        bind_caller(&mut request)?;
+
+        self.call_upstream(Upstream::User, "SearchUsers", &self.proxies.user, request, |mut client, request| async move {
+            client.search_users(request).await
+        })
+        .await
     }
 
     async fn block_user(
         &self,
-        request: Request<BlockUserRequest>
+        mut request: Request<BlockUserRequest>
     ) -> Result<Response<BlockUserResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.user.write().await;
-        client
-            .block_user(request)
-            .await
-            .map_err(map_error)
+        let tags = vec![format!("user:{}", request.get_ref().blocked_user_id)];
+        let response = self.call_upstream(Upstream::User, "BlockUser", &self.proxies.user, request, |mut client, request| async move {
+            client.block_user(request).await
+        })
+        .await?;
+
+        self.cache.invalidate(&tags);
+        Ok(response)
     }
 
     async fn unblock_user(
         &self,
-        request: Request<UnblockUserRequest>
+        mut request: Request<UnblockUserRequest>
     ) -> Result<Response<UnblockUserResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.user.write().await;
-        client
-            .unblock_user(request)
-            .await
-            .map_err(map_error)
+        let tags = vec![format!("user:{}", request.get_ref().blocked_user_id)];
+        let response = self.call_upstream(Upstream::User, "UnblockUser", &self.proxies.user, request, |mut client, request| async move {
+            client.unblock_user(request).await
+        })
+        .await?;
+
+        self.cache.invalidate(&tags);
+        Ok(response)
     }
 
     async fn get_blocked_users(
         &self,
-        request: Request<GetBlockedUsersRequest>
+        mut request: Request<GetBlockedUsersRequest>
     ) -> Result<Response<GetBlockedUsersResponse>, Status> {
-        let _token = get_auth_token(&request)?;
+        bind_caller(&mut request)?;
         
-        let mut client = self.proxies.user.write().await;
-        client
-            .get_blocked_users(request)
-            .await
-            .map_err(map_error)
+        self.call_upstream(Upstream::User, "GetBlockedUsers", &self.proxies.user, request, |mut client, request| async move {
+            client.get_blocked_users(request).await
+        })
+        .await
     }
 }
\ No newline at end of file
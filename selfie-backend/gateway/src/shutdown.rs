@@ -0,0 +1,71 @@
+use std::future::Future;
+use std::sync::Arc;
+use anyhow::Result;
+use futures::{Stream, StreamExt};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tonic::Status;
+use tracing::info;
+
+use crate::error::GatewayError;
+
+/// Tells listeners and long-lived streams that the gateway is going away. Cloning is cheap;
+/// every clone sees the same state.
+#[derive(Clone)]
+pub struct Shutdown {
+    started: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            started: Arc::new(watch::Sender::new(false)),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Closes the listeners and ends open streams.
+    pub fn start(&self) {
+        self.started.send_replace(true);
+    }
+
+    /// Resolves once shutdown has started, immediately if it already has.
+    pub fn started(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut started = self.started.subscribe();
+        async move {
+            let _ = started.wait_for(|started| *started).await;
+        }
+    }
+}
+
+/// Waits for SIGTERM, or Ctrl-C when running in a terminal.
+pub async fn signal_received() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            info!("Received Ctrl-C, shutting down");
+        }
+    }
+    Ok(())
+}
+
+/// Forwards `stream` until shutdown starts, then ends it with `UNAVAILABLE` so the client
+/// reconnects, through the load balancer, to an instance that is staying up.
+pub fn until_shutdown<S, T>(stream: S, shutdown: &Shutdown) -> impl Stream<Item = Result<T, Status>> + Send + 'static
+where
+    S: Stream<Item = Result<T, Status>> + Send + 'static,
+    T: Send + 'static,
+{
+    let state = Some((Box::pin(stream), Box::pin(shutdown.started())));
+    futures::stream::unfold(state, |state| async move {
+        let (mut stream, mut started) = state?;
+        tokio::select! {
+            biased;
+            _ = &mut started => Some((Err(GatewayError::ShuttingDown.into()), None)),
+            item = stream.next() => item.map(|item| (item, Some((stream, started)))),
+        }
+    })
+}
@@ -0,0 +1,110 @@
+use anyhow::Result;
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self as sdktrace, Sampler, TracerProvider};
+use opentelemetry_sdk::Resource;
+use tonic::codegen::http::HeaderMap;
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::TelemetryConfig;
+
+/// Installs the log subscriber and the OpenTelemetry tracer. Spans are exported over OTLP when
+/// a collector is configured; without one they are still created so trace ids reach the logs
+/// and the upstream services.
+pub fn init(config: &TelemetryConfig) -> Result<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let trace_config = sdktrace::config()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio))))
+        .with_resource(Resource::new(vec![KeyValue::new("service.name", config.service_name.clone())]));
+
+    let tracer = match &config.otlp_endpoint {
+        Some(endpoint) => opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+            .with_trace_config(trace_config)
+            .install_batch(opentelemetry_sdk::runtime::Tokio)?,
+        None => {
+            let provider = TracerProvider::builder().with_config(trace_config).build();
+            let tracer = provider.tracer("selfie-gateway");
+            global::set_tracer_provider(provider);
+            tracer
+        }
+    };
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    // JSON lines carry the fields of the enclosing request span, trace_id and request_id included
+    let (json, text) = if config.json_logs {
+        let layer = tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false);
+        (Some(layer), None)
+    } else {
+        (None, Some(tracing_subscriber::fmt::layer().with_target(false)))
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(json)
+        .with(text)
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()?;
+
+    Ok(())
+}
+
+/// Flushes spans still queued for export.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Writes the current span's context into outgoing metadata as `traceparent`/`tracestate`.
+pub fn inject(metadata: &mut MetadataMap) {
+    let context = Span::current().context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut MetadataInjector(metadata)));
+}
+
+/// Makes the span a child of the remote trace described by the request headers, if any.
+pub fn set_remote_parent(span: &Span, headers: &HeaderMap) {
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(parent);
+}
+
+/// Hex trace id of the span, as it appears in `traceparent`.
+pub fn trace_id(span: &Span) -> String {
+    use opentelemetry::trace::TraceContextExt;
+
+    span.context().span().span_context().trace_id().to_string()
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (MetadataKey::from_bytes(key.as_bytes()), MetadataValue::try_from(value)) {
+            self.0.insert(key, value);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use anyhow::{anyhow, bail, Context as _, Result};
+use futures::Stream;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tonic::transport::server::{Connected, TcpConnectInfo};
+use tracing::{debug, info, warn};
+
+use crate::config::TlsConfig;
+use crate::shutdown::Shutdown;
+
+// Connections accepted but not yet picked up by the server
+const ACCEPT_BACKLOG: usize = 128;
+
+/// ALPN protocols offered on a listener.
+#[derive(Clone, Copy)]
+pub enum Alpn {
+    /// gRPC needs HTTP/2; HTTP/1.1 stays available for gRPC-Web, REST and GraphQL
+    Grpc,
+    /// WebSocket upgrades only work over HTTP/1.1
+    WebSocket,
+}
+
+impl Alpn {
+    fn protocols(self) -> Vec<Vec<u8>> {
+        match self {
+            Alpn::Grpc => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            Alpn::WebSocket => vec![b"http/1.1".to_vec()],
+        }
+    }
+}
+
+/// TLS termination for one listener. The certificate, key and client CA are re-read on
+/// reload; connections already established keep the certificate they were accepted with.
+pub struct ServerTls {
+    alpn: Alpn,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+impl ServerTls {
+    pub fn from_config(config: &TlsConfig, alpn: Alpn) -> Result<Self> {
+        Ok(Self {
+            alpn,
+            acceptor: RwLock::new(acceptor(config, alpn)?),
+        })
+    }
+
+    /// Builds an acceptor for `replace_acceptor` from the certificate and client CA currently
+    /// on disk.
+    pub fn load_acceptor(&self, config: &TlsConfig) -> Result<TlsAcceptor> {
+        acceptor(config, self.alpn)
+    }
+
+    /// Switches new connections to `acceptor`.
+    pub fn replace_acceptor(&self, acceptor: TlsAcceptor) {
+        *self.acceptor.write().expect("TLS acceptor poisoned") = acceptor;
+    }
+
+    async fn accept(&self, stream: TcpStream) -> io::Result<ServerStream> {
+        let acceptor = self.acceptor.read().expect("TLS acceptor poisoned").clone();
+        let stream = acceptor.accept(stream).await?;
+        Ok(ServerStream::Tls(Box::new(stream)))
+    }
+}
+
+fn acceptor(config: &TlsConfig, alpn: Alpn) -> Result<TlsAcceptor> {
+    let certs = load_certs(&config.cert_path)?;
+    let key = load_key(&config.key_path)?;
+
+    let builder = match &config.client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert).with_context(|| format!("invalid client CA certificate in {}", path))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = if config.require_client_cert {
+                verifier.build()
+            } else {
+                verifier.allow_unauthenticated().build()
+            };
+            ServerConfig::builder().with_client_cert_verifier(verifier.map_err(|err| anyhow!("{}", err))?)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .with_context(|| format!("invalid TLS certificate or key ({})", config.cert_path))?;
+    server_config.alpn_protocols = alpn.protocols();
+
+    info!(
+        "Loaded TLS certificate {} (client certificates {})",
+        config.cert_path,
+        match (&config.client_ca_path, config.require_client_cert) {
+            (None, _) => "not requested",
+            (Some(_), false) => "verified when presented",
+            (Some(_), true) => "required",
+        }
+    );
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("failed to read certificate {}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid PEM in {}", path))?;
+    if certs.is_empty() {
+        bail!("no certificates found in {}", path);
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("failed to read private key {}", path))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("invalid PEM in {}", path))?
+        .ok_or_else(|| anyhow!("no private key found in {}", path))
+}
+
+/// Accepts connections on `listener`, completing TLS handshakes off the accept loop so a
+/// slow client doesn't hold up the others. Failed handshakes are logged and dropped. The
+/// listener is closed when shutdown starts.
+pub fn incoming(
+    listener: TcpListener,
+    tls: Option<Arc<ServerTls>>,
+    shutdown: &Shutdown,
+) -> impl Stream<Item = io::Result<ServerStream>> {
+    let (accepted, mut ready) = mpsc::channel(ACCEPT_BACKLOG);
+    let started = shutdown.started();
+
+    tokio::spawn(async move {
+        tokio::pin!(started);
+        loop {
+            let (stream, remote) = tokio::select! {
+                _ = &mut started => break,
+                connection = listener.accept() => match connection {
+                    Ok(connection) => connection,
+                    Err(err) => {
+                        warn!("Failed to accept connection: {}", err);
+                        continue;
+                    }
+                },
+            };
+
+            let tls = tls.clone();
+            let accepted = accepted.clone();
+            tokio::spawn(async move {
+                match accept(tls.as_deref(), stream).await {
+                    Ok(stream) => {
+                        let _ = accepted.send(Ok(stream)).await;
+                    }
+                    Err(err) => debug!("TLS handshake with {} failed: {}", remote, err),
+                }
+            });
+        }
+    });
+
+    // Never ends: the server would take the end of its connections for a finished shutdown
+    // and stop without draining the ones already open
+    futures::stream::poll_fn(move |cx| match ready.poll_recv(cx) {
+        Poll::Ready(None) => Poll::Pending,
+        poll => poll,
+    })
+}
+
+/// Completes the TLS handshake when the listener terminates TLS.
+pub async fn accept(tls: Option<&ServerTls>, stream: TcpStream) -> io::Result<ServerStream> {
+    let _ = stream.set_nodelay(true);
+    match tls {
+        Some(tls) => tls.accept(stream).await,
+        None => Ok(ServerStream::Plain(stream)),
+    }
+}
+
+/// An accepted connection, with or without TLS.
+pub enum ServerStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connected for ServerStream {
+    // Reported like a plain TCP connection so the client address is found the same way
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        match self {
+            ServerStream::Plain(stream) => stream.connect_info(),
+            ServerStream::Tls(stream) => stream.get_ref().0.connect_info(),
+        }
+    }
+}
+
+impl AsyncRead for ServerStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ServerStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            ServerStream::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            ServerStream::Plain(stream) => stream.is_write_vectored(),
+            ServerStream::Tls(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use prost::Message as _;
+use prost_reflect::{DynamicMessage, MessageDescriptor, SerializeOptions};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::Message;
+use tonic::Status;
+
+use crate::rest::error_body;
+use crate::services::chat;
+
+/// Frames sent by the client, as JSON text messages tagged by `type`. `request_id` is optional
+/// and echoed on the matching `ack` or `error` frame.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    SendMessage {
+        request_id: Option<String>,
+        chat_id: String,
+        content: String,
+        #[serde(default)]
+        media_ids: Vec<String>,
+    },
+    MarkAsRead {
+        request_id: Option<String>,
+        chat_id: String,
+        last_message_id: String,
+    },
+}
+
+impl ClientFrame {
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            ClientFrame::SendMessage { request_id, .. } | ClientFrame::MarkAsRead { request_id, .. } => {
+                request_id.as_deref()
+            }
+        }
+    }
+}
+
+/// Sent once the message subscription is established.
+pub fn ready() -> Message {
+    text(json!({ "type": "ready" }))
+}
+
+/// A chat message delivered on the subscription, in proto3 JSON like the REST API.
+pub fn message(descriptor: &MessageDescriptor, message: &chat::Message) -> Message {
+    text(json!({ "type": "message", "message": to_json(descriptor, message) }))
+}
+
+/// Successful completion of a client frame, with the message it created if any.
+pub fn ack(request_id: Option<&str>, message: Option<Value>) -> Message {
+    let mut frame = json!({ "type": "ack", "request_id": request_id });
+    if let Some(message) = message {
+        frame["message"] = message;
+    }
+    text(frame)
+}
+
+/// A failed client frame, or the subscription failing when `request_id` is `None`. The error
+/// object has the same shape as REST error bodies.
+pub fn error(request_id: Option<&str>, status: &Status) -> Message {
+    let body = error_body(status);
+    text(json!({ "type": "error", "request_id": request_id, "error": body["error"] }))
+}
+
+pub fn to_json(descriptor: &MessageDescriptor, message: &chat::Message) -> Value {
+    DynamicMessage::decode(descriptor.clone(), message.encode_to_vec().as_slice())
+        .ok()
+        .and_then(|dynamic| {
+            dynamic
+                .serialize_with_options(serde_json::value::Serializer, &SerializeOptions::new())
+                .ok()
+        })
+        .unwrap_or(Value::Null)
+}
+
+fn text(frame: Value) -> Message {
+    Message::Text(frame.to_string())
+}
@@ -0,0 +1,298 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use anyhow::{Context as _, Result};
+use async_graphql::http::WebSocketProtocols as Protocols;
+use prost_reflect::{DescriptorPool, MessageDescriptor};
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig as ProtocolConfig;
+use tonic::Status;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::config::WebSocketConfig;
+use crate::error::GatewayError;
+use crate::graphql::{self, GatewaySchema};
+use crate::middleware::trace::{MAX_REQUEST_ID_LEN, REQUEST_ID_HEADER};
+use crate::middleware::{AuthenticatedUser, TokenVerifier};
+use crate::ratelimit::{Decision, RateLimiter};
+use crate::services::context::CallContext;
+use crate::services::GatewayServer;
+use crate::shutdown::Shutdown;
+use crate::tls::{self, ServerStream, ServerTls};
+
+mod frame;
+mod session;
+
+// Path clients connect to; anything else is rejected during the handshake
+pub const CHAT_PATH: &str = "/v1/chat/ws";
+
+const CHAT_MESSAGE: &str = "selfie.chat.v1.Message";
+
+/// Bridges WebSocket clients to chat streaming. Each connection subscribes to
+/// `StreamMessages` for the caller and may send `SendMessage` and `MarkAsRead` frames; calls
+/// go through the same handlers, identity binding and rate limits as gRPC and REST. GraphQL
+/// subscriptions are served from the same listener.
+pub struct WebSocketBridge {
+    gateway: GatewayServer,
+    graphql: Option<GatewaySchema>,
+    verifier: Arc<TokenVerifier>,
+    rate_limiter: Arc<RateLimiter>,
+    message_descriptor: MessageDescriptor,
+    heartbeat_interval: Duration,
+    idle_timeout: Duration,
+    max_frame_bytes: usize,
+    shutdown: Shutdown,
+}
+
+/// What the client asked for in the upgrade request, once its token checks out.
+struct Handshake {
+    caller: Caller,
+    route: Route,
+}
+
+/// The user a connection authenticated as, and the request id of its upgrade request.
+struct Caller {
+    user_id: String,
+    request_id: String,
+    // Passed on to upstreams like a gRPC caller's `authorization` header
+    access_token: String,
+    // The connection is closed once the token it authenticated with runs out
+    expires_at: Instant,
+}
+
+enum Route {
+    Chat(ChatSubscription),
+    GraphQL(Protocols),
+}
+
+struct ChatSubscription {
+    chat_ids: Vec<String>,
+    after_message_id: String,
+}
+
+impl WebSocketBridge {
+    pub fn new(
+        gateway: GatewayServer,
+        graphql: Option<GatewaySchema>,
+        verifier: Arc<TokenVerifier>,
+        rate_limiter: Arc<RateLimiter>,
+        descriptor_set: &[u8],
+        config: &WebSocketConfig,
+        shutdown: Shutdown,
+    ) -> Result<Self> {
+        let pool = DescriptorPool::decode(descriptor_set).context("invalid file descriptor set")?;
+        let message_descriptor = pool
+            .get_message_by_name(CHAT_MESSAGE)
+            .with_context(|| format!("{} missing from the descriptor set", CHAT_MESSAGE))?;
+
+        Ok(Self {
+            gateway,
+            graphql,
+            verifier,
+            rate_limiter,
+            message_descriptor,
+            heartbeat_interval: Duration::from_secs(config.heartbeat_interval_secs),
+            idle_timeout: Duration::from_secs(config.idle_timeout_secs),
+            max_frame_bytes: config.max_frame_bytes,
+            shutdown,
+        })
+    }
+
+    /// Accepts connections on `addr`, over TLS when `tls` is set. Once shutdown starts the
+    /// listener closes, open connections are told to reconnect, and this returns when they
+    /// have all closed.
+    pub async fn serve(self: Arc<Self>, addr: SocketAddr, tls: Option<Arc<ServerTls>>) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("WebSocket bridge listening on {}{}", addr, if tls.is_some() { " (TLS)" } else { "" });
+
+        let started = self.shutdown.started();
+        tokio::pin!(started);
+        let mut connections = JoinSet::new();
+
+        loop {
+            let (stream, remote) = tokio::select! {
+                _ = &mut started => break,
+                // Reaps finished connections so the set doesn't grow for the life of the process
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!("Failed to accept WebSocket connection: {}", err);
+                        continue;
+                    }
+                },
+            };
+
+            let bridge = self.clone();
+            let tls = tls.clone();
+            connections.spawn(async move {
+                let accepted = match tls::accept(tls.as_deref(), stream).await {
+                    Ok(stream) => bridge.accept(stream).await,
+                    Err(err) => Err(err.into()),
+                };
+                if let Err(err) = accepted {
+                    debug!("WebSocket connection from {} ended: {}", remote, err);
+                }
+            });
+        }
+
+        drop(listener);
+        info!("WebSocket bridge draining {} connections", connections.len());
+        while connections.join_next().await.is_some() {}
+        Ok(())
+    }
+
+    // The handshake callback has to return tungstenite's `ErrorResponse` as is
+    #[allow(clippy::result_large_err)]
+    async fn accept(self: Arc<Self>, stream: ServerStream) -> Result<()> {
+        let mut handshake = None;
+        let config = ProtocolConfig {
+            max_message_size: Some(self.max_frame_bytes),
+            max_frame_size: Some(self.max_frame_bytes),
+            ..Default::default()
+        };
+
+        let ws = tokio_tungstenite::accept_hdr_async_with_config(
+            stream,
+            |request: &Request, mut response: Response| {
+                let accepted = self.authenticate(request)?;
+                if let Route::GraphQL(protocol) = &accepted.route {
+                    response.headers_mut().insert(
+                        "sec-websocket-protocol",
+                        HeaderValue::from_static(protocol.sec_websocket_protocol()),
+                    );
+                }
+                handshake = Some(accepted);
+                Ok(response)
+            },
+            Some(config),
+        )
+        .await?;
+        let handshake = handshake.context("handshake completed without authentication")?;
+
+        let Handshake { caller, route } = handshake;
+        if self.limit("StreamMessages", &caller.user_id).await.is_err() {
+            session::reject(ws, "rate limited").await;
+            return Ok(());
+        }
+
+        let span = info_span!(
+            "websocket",
+            user_id = %caller.user_id,
+            request_id = %caller.request_id,
+        );
+        match route {
+            Route::Chat(subscription) => {
+                session::run(self, ws, caller, subscription).instrument(span).await;
+            }
+            Route::GraphQL(protocol) => {
+                let schema = self.graphql.clone().context("GraphQL is disabled")?;
+                let context = CallContext::new(
+                    session::caller_metadata(&caller),
+                    Some(AuthenticatedUser { user_id: caller.user_id }),
+                );
+                let data = graphql::request_data(&self.gateway, context);
+                graphql::serve_subscriptions(schema, ws, protocol, data, self.idle_timeout, caller.expires_at, &self.shutdown)
+                    .instrument(span)
+                    .await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the caller's limit for a chat RPC, exactly as if it had arrived over gRPC.
+    async fn limit(&self, rpc: &str, user_id: &str) -> Result<(), Status> {
+        let path = format!("/selfie.chat.v1.ChatService/{}", rpc);
+        match self.rate_limiter.check(&path, &format!("user:{}", user_id)).await {
+            Decision::Allowed => Ok(()),
+            Decision::Limited { retry_after } => Err(GatewayError::RateLimited(retry_after).into()),
+        }
+    }
+
+    // Browsers can't set headers on a WebSocket upgrade, so the token may also come as the
+    // `access_token` query parameter
+    #[allow(clippy::result_large_err)]
+    fn authenticate(&self, request: &Request) -> Result<Handshake, ErrorResponse> {
+        let graphql = match request.uri().path() {
+            CHAT_PATH => false,
+            graphql::SUBSCRIPTIONS_PATH if self.graphql.is_some() => true,
+            _ => return Err(rejection(StatusCode::NOT_FOUND, "not found")),
+        };
+
+        let mut token = request
+            .headers()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string);
+        let mut chat_ids = Vec::new();
+        let mut after_message_id = String::new();
+
+        let query = request.uri().query().unwrap_or_default();
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "access_token" if token.is_none() => token = Some(value.into_owned()),
+                "chat_id" => chat_ids.push(value.into_owned()),
+                "last_message_id" => after_message_id = value.into_owned(),
+                _ => {}
+            }
+        }
+
+        let token = token.ok_or_else(|| rejection(StatusCode::UNAUTHORIZED, "missing access token"))?;
+        let (user_id, expires_at) = self
+            .verifier
+            .verify_until(&token)
+            .map_err(|err| rejection(StatusCode::UNAUTHORIZED, &err.to_string()))?;
+        let expires_at = Instant::now() + expires_at.duration_since(SystemTime::now()).unwrap_or_default();
+
+        let request_id = request
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty() && value.len() <= MAX_REQUEST_ID_LEN)
+            .map(str::to_string)
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+        let route = if graphql {
+            Route::GraphQL(subprotocol(request)?)
+        } else {
+            Route::Chat(ChatSubscription {
+                chat_ids,
+                after_message_id,
+            })
+        };
+
+        Ok(Handshake {
+            caller: Caller {
+                user_id,
+                request_id,
+                access_token: token,
+                expires_at,
+            },
+            route,
+        })
+    }
+}
+
+// First GraphQL over WebSocket protocol offered in Sec-WebSocket-Protocol that we speak
+#[allow(clippy::result_large_err)]
+fn subprotocol(request: &Request) -> Result<Protocols, ErrorResponse> {
+    request
+        .headers()
+        .get_all("sec-websocket-protocol")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|protocol| protocol.trim().parse::<Protocols>().ok())
+        .ok_or_else(|| rejection(StatusCode::BAD_REQUEST, "unsupported GraphQL WebSocket protocol"))
+}
+
+fn rejection(status: StatusCode, reason: &str) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(reason.to_string()));
+    *response.status_mut() = status;
+    response
+}
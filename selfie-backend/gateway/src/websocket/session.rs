@@ -0,0 +1,240 @@
+use std::sync::Arc;
+use std::time::Instant;
+use futures::stream::SplitSink;
+use futures::{SinkExt, StreamExt};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Request, Status};
+use tracing::{debug, info, Instrument, Span};
+
+use crate::error::GatewayError;
+use crate::middleware::trace::REQUEST_ID_HEADER;
+use crate::middleware::AuthenticatedUser;
+use crate::services::chat::chat_service_server::ChatService;
+use crate::services::chat::{MarkAsReadRequest, SendMessageRequest, StreamMessagesRequest};
+use crate::tls::ServerStream;
+use super::frame::{self, ClientFrame};
+use super::{Caller, ChatSubscription, WebSocketBridge};
+
+// Frames queued for a slow client before delivery waits on it
+const OUTBOUND_BUFFER: usize = 64;
+
+type Socket = WebSocketStream<ServerStream>;
+
+/// Closes a connection that was accepted but may not subscribe.
+pub(super) async fn reject(mut ws: Socket, reason: &str) {
+    let _ = ws.close(Some(close_frame(CloseCode::Again, reason))).await;
+}
+
+/// Serves one connection until either side closes it. Messages from the subscription and
+/// replies to client frames share a single writer; a client that stops answering pings is
+/// dropped after the idle timeout, and every client when its access token expires.
+pub(super) async fn run(
+    bridge: Arc<WebSocketBridge>,
+    ws: Socket,
+    caller: Caller,
+    subscription: ChatSubscription,
+) {
+    let caller = Arc::new(caller);
+    let (sink, mut source) = ws.split();
+    let (outbound, outbound_rx) = mpsc::channel(OUTBOUND_BUFFER);
+    let writer = tokio::spawn(write_frames(sink, outbound_rx));
+
+    let after_message_id = subscription.after_message_id.clone();
+    let request_message = StreamMessagesRequest {
+        user_id: caller.user_id.clone(),
+        chat_ids: subscription.chat_ids,
+        after_message_id: subscription.after_message_id,
+    };
+    let mut messages = match bridge.gateway.stream_messages(request(&caller, request_message)).await {
+        Ok(response) => response.into_inner(),
+        Err(status) => {
+            let _ = outbound.send(frame::error(None, &status)).await;
+            let _ = outbound.send(Message::Close(Some(close_frame(close_code(&status), "subscription failed")))).await;
+            drop(outbound);
+            let _ = writer.await;
+            return;
+        }
+    };
+
+    info!(after_message_id = %after_message_id, "WebSocket connected");
+    let _ = outbound.send(frame::ready()).await;
+
+    // Clients resume from the last message they saw; this one is only for the logs
+    let mut last_message_id = after_message_id;
+    let mut last_seen = Instant::now();
+    let mut heartbeat = tokio::time::interval(bridge.heartbeat_interval);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let shutdown = bridge.shutdown.started();
+    tokio::pin!(shutdown);
+    let expired = tokio::time::sleep_until(caller.expires_at);
+    tokio::pin!(expired);
+
+    let close = loop {
+        tokio::select! {
+            // Ahead of the message stream, which ends with an error at the same moment
+            biased;
+            _ = &mut shutdown => break Some(close_frame(CloseCode::Restart, "gateway restarting, reconnect")),
+            // Reconnecting with a refreshed token picks up where this left off
+            _ = &mut expired => break Some(close_frame(CloseCode::Policy, "access token expired")),
+            delivered = messages.next() => match delivered {
+                Some(Ok(response)) => {
+                    if let Some(message) = response.message {
+                        last_message_id = message.id.clone();
+                        if outbound.send(frame::message(&bridge.message_descriptor, &message)).await.is_err() {
+                            break None;
+                        }
+                    }
+                }
+                // The client reconnects with its last message id and misses nothing
+                Some(Err(status)) => {
+                    let _ = outbound.send(frame::error(None, &status)).await;
+                    break Some(close_frame(CloseCode::Again, "message stream interrupted"));
+                }
+                None => break Some(close_frame(CloseCode::Again, "message stream ended")),
+            },
+            received = source.next() => {
+                last_seen = Instant::now();
+                match received {
+                    Some(Ok(Message::Text(text))) => handle_frame(&bridge, &caller, &text, &outbound).await,
+                    Some(Ok(Message::Binary(_))) => {
+                        let status: Status = GatewayError::InvalidRequest("frames must be JSON text".to_string()).into();
+                        let _ = outbound.send(frame::error(None, &status)).await;
+                    }
+                    Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                    Some(Ok(Message::Close(_))) | None => break None,
+                    Some(Err(err)) => {
+                        debug!("WebSocket read failed: {}", err);
+                        break None;
+                    }
+                }
+            },
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() >= bridge.idle_timeout {
+                    break Some(close_frame(CloseCode::Away, "idle timeout"));
+                }
+                if outbound.send(Message::Ping(Vec::new())).await.is_err() {
+                    break None;
+                }
+            },
+        }
+    };
+
+    if let Some(close) = close {
+        let _ = outbound.send(Message::Close(Some(close))).await;
+    }
+    drop(outbound);
+    let _ = writer.await;
+    info!(last_message_id = %last_message_id, "WebSocket disconnected");
+}
+
+// Each frame runs on its own task so a slow upstream call doesn't hold up delivery
+async fn handle_frame(
+    bridge: &Arc<WebSocketBridge>,
+    caller: &Arc<Caller>,
+    text: &str,
+    outbound: &mpsc::Sender<Message>,
+) {
+    let client_frame: ClientFrame = match serde_json::from_str(text) {
+        Ok(client_frame) => client_frame,
+        Err(err) => {
+            let status: Status = GatewayError::InvalidRequest(format!("invalid frame: {}", err)).into();
+            let _ = outbound.send(frame::error(None, &status)).await;
+            return;
+        }
+    };
+
+    let bridge = bridge.clone();
+    let caller = caller.clone();
+    let outbound = outbound.clone();
+    tokio::spawn(
+        async move {
+            let request_id = client_frame.request_id().map(str::to_string);
+            let reply = match dispatch(&bridge, &caller, client_frame).await {
+                Ok(message) => frame::ack(request_id.as_deref(), message),
+                Err(status) => frame::error(request_id.as_deref(), &status),
+            };
+            let _ = outbound.send(reply).await;
+        }
+        .instrument(Span::current()),
+    );
+}
+
+async fn dispatch(bridge: &WebSocketBridge, caller: &Caller, client_frame: ClientFrame) -> Result<Option<Value>, Status> {
+    match client_frame {
+        ClientFrame::SendMessage { chat_id, content, media_ids, .. } => {
+            bridge.limit("SendMessage", &caller.user_id).await?;
+            let message = SendMessageRequest {
+                chat_id,
+                sender_id: caller.user_id.clone(),
+                content,
+                media_ids,
+            };
+            let response = bridge.gateway.send_message(request(caller, message)).await?;
+            Ok(response
+                .into_inner()
+                .message
+                .map(|message| frame::to_json(&bridge.message_descriptor, &message)))
+        }
+        ClientFrame::MarkAsRead { chat_id, last_message_id, .. } => {
+            bridge.limit("MarkAsRead", &caller.user_id).await?;
+            let message = MarkAsReadRequest {
+                chat_id,
+                user_id: caller.user_id.clone(),
+                last_message_id,
+            };
+            bridge.gateway.mark_as_read(request(caller, message)).await?;
+            Ok(None)
+        }
+    }
+}
+
+async fn write_frames(mut sink: SplitSink<Socket, Message>, mut outbound: mpsc::Receiver<Message>) {
+    while let Some(message) = outbound.recv().await {
+        let closing = matches!(message, Message::Close(_));
+        if sink.send(message).await.is_err() || closing {
+            break;
+        }
+    }
+}
+
+// Calls made for the connection carry the caller and its request id like any gateway request
+fn request<T>(caller: &Caller, message: T) -> Request<T> {
+    let mut request = Request::from_parts(caller_metadata(caller), Default::default(), message);
+    request.extensions_mut().insert(AuthenticatedUser {
+        user_id: caller.user_id.clone(),
+    });
+    request
+}
+
+pub(super) fn caller_metadata(caller: &Caller) -> MetadataMap {
+    let mut metadata = MetadataMap::new();
+    if let Ok(request_id) = caller.request_id.parse() {
+        metadata.insert(REQUEST_ID_HEADER, request_id);
+    }
+    if let Ok(authorization) = format!("Bearer {}", caller.access_token).parse() {
+        metadata.insert("authorization", authorization);
+    }
+    metadata
+}
+
+// Errors the client can't fix by reconnecting are reported as policy violations
+fn close_code(status: &Status) -> CloseCode {
+    match status.code() {
+        Code::Unauthenticated | Code::PermissionDenied | Code::InvalidArgument => CloseCode::Policy,
+        _ => CloseCode::Again,
+    }
+}
+
+fn close_frame(code: CloseCode, reason: &str) -> CloseFrame<'static> {
+    CloseFrame {
+        code,
+        reason: reason.to_string().into(),
+    }
+}
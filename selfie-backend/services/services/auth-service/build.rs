@@ -0,0 +1,9 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // The gateway's protos are the contract; serve exactly the AuthService it proxies
+    tonic_build::configure()
+        .build_client(false)
+        .build_server(true)
+        .compile(&["../../../gateway/proto/auth.proto"], &["../../../gateway/proto"])?;
+
+    Ok(())
+}
@@ -7,6 +7,7 @@ pub struct RegisterRequest {
     pub email: String,
     #[validate(length(min = 12, message = "Passphrase must be at least 12 characters"))]
     pub passphrase: String,
+    pub username: String,
 }
 
 #[derive(Debug, Deserialize, Validate)]
@@ -14,7 +15,8 @@ pub struct LoginRequest {
     #[validate(email)]
     pub email: String,
     pub passphrase: String,
-    pub totp_code: Option<String>,
+    #[validate(length(max = 100, message = "Device name must be at most 100 characters"))]
+    pub device_name: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -25,6 +27,29 @@ pub struct AuthResponse {
     pub expires_in: i64,
 }
 
+/// Tokens when the login is complete, or the 2FA challenge when it isn't.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Authenticated(AuthResponse),
+    TotpRequired(TotpChallengeResponse),
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpChallengeResponse {
+    pub requires_2fa: bool,
+    /// Exchanged, with the TOTP code, at `/2fa/verify`
+    pub challenge_token: String,
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct VerifyTotpRequest {
+    pub challenge_token: String,
+    #[validate(length(equal = 6, message = "TOTP code must be 6 digits"))]
+    pub code: String,
+}
+
 #[derive(Debug, Serialize)]
 pub struct UserResponse {
     pub id: String,
@@ -57,4 +82,36 @@ pub struct ConfirmResetRequest {
     pub token: String,
     #[validate(length(min = 12, message = "Passphrase must be at least 12 characters"))]
     pub new_passphrase: String,
-}
\ No newline at end of file
+}
+
+/// A login on one device, as listed at `/sessions`.
+#[derive(Debug, Serialize)]
+pub struct SessionResponse {
+    pub id: String,
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: String,
+    pub last_seen: String,
+    /// Whether this is the session the request was made from
+    pub current: bool,
+}
+
+/// JSON Web Key Set served at `/.well-known/jwks.json`.
+#[derive(Debug, Serialize)]
+pub struct JwksResponse {
+    pub keys: Vec<Jwk>,
+}
+
+/// Public half of an Ed25519 signing key (RFC 8037).
+#[derive(Debug, Serialize)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub key_use: String,
+    pub kid: String,
+    /// Base64url-encoded public key
+    pub x: String,
+}
@@ -5,6 +5,7 @@ use axum::{
 };
 use serde_json::json;
 use thiserror::Error;
+use tonic::{Code, Status};
 
 #[derive(Error, Debug)]
 pub enum AuthError {
@@ -25,6 +26,12 @@ pub enum AuthError {
     
     #[error("User already exists")]
     UserExists,
+
+    #[error("Username is taken")]
+    UsernameTaken,
+
+    #[error("Invalid username: {0}")]
+    InvalidUsername(String),
     
     #[error("Database error: {0}")]
     DatabaseError(#[from] foundationdb::Error),
@@ -46,6 +53,12 @@ pub enum AuthError {
 
     #[error("Invalid TOTP code")]
     InvalidTotpCode,
+
+    #[error("Signing key error: {0}")]
+    SigningKeyError(String),
+
+    #[error("Session not found")]
+    SessionNotFound,
 }
 
 impl IntoResponse for AuthError {
@@ -57,10 +70,14 @@ impl IntoResponse for AuthError {
             AuthError::InvalidToken => (StatusCode::UNAUTHORIZED, self.to_string()),
             AuthError::UserNotFound => (StatusCode::NOT_FOUND, self.to_string()),
             AuthError::UserExists => (StatusCode::CONFLICT, self.to_string()),
+            AuthError::UsernameTaken => (StatusCode::CONFLICT, self.to_string()),
+            AuthError::InvalidUsername(_) => (StatusCode::BAD_REQUEST, self.to_string()),
             AuthError::DatabaseError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Database error occurred".to_string()),
             AuthError::InternalError => (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error".to_string()),
             AuthError::WeakPassphrase(msg) => (StatusCode::BAD_REQUEST, msg),
             AuthError::RateLimitExceeded => (StatusCode::TOO_MANY_REQUESTS, self.to_string()),
+            AuthError::SigningKeyError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error".to_string()),
+            AuthError::SessionNotFound => (StatusCode::NOT_FOUND, self.to_string()),
         };
 
         let body = Json(json!({
@@ -70,4 +87,27 @@ impl IntoResponse for AuthError {
 
         (status, body).into_response()
     }
-}
\ No newline at end of file
+}
+
+// Messages follow the REST responses; internal details stay in the logs
+impl From<AuthError> for Status {
+    fn from(err: AuthError) -> Self {
+        let code = match &err {
+            AuthError::AuthenticationError
+            | AuthError::InvalidCredentials
+            | AuthError::TokenExpired
+            | AuthError::InvalidToken
+            | AuthError::InvalidTotpCode => Code::Unauthenticated,
+            AuthError::UserNotFound | AuthError::SessionNotFound => Code::NotFound,
+            AuthError::UserExists | AuthError::UsernameTaken => Code::AlreadyExists,
+            AuthError::WeakPassphrase(_) | AuthError::InvalidUsername(_) => Code::InvalidArgument,
+            AuthError::RateLimitExceeded => Code::ResourceExhausted,
+            AuthError::TotpAlreadyEnabled | AuthError::TotpNotEnabled => Code::FailedPrecondition,
+            AuthError::DatabaseError(_) | AuthError::InternalError | AuthError::SigningKeyError(_) => {
+                return Status::internal("Internal server error");
+            }
+        };
+
+        Status::new(code, err.to_string())
+    }
+}
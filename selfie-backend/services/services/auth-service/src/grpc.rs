@@ -0,0 +1,228 @@
+use std::sync::Arc;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+use tonic::{Request, Response, Status};
+use validator::Validate;
+
+use crate::{
+    api::models,
+    error::AuthError,
+    service::{
+        auth::{AuthService, LoginOutcome},
+        jwt::AccessClaims,
+        models::{ClientInfo, TrustedProxies},
+    },
+};
+
+pub mod pb {
+    tonic::include_proto!("selfie.auth.v1");
+}
+
+pub use pb::auth_service_server::AuthServiceServer;
+
+/// `selfie.auth.v1.AuthService` as the gateway proxies it, on the same `AuthService` as the
+/// REST routes.
+pub struct AuthGrpcService {
+    auth_service: Arc<AuthService>,
+    trusted_proxies: TrustedProxies,
+}
+
+impl AuthGrpcService {
+    pub fn new(auth_service: Arc<AuthService>, trusted_proxies: TrustedProxies) -> Self {
+        Self { auth_service, trusted_proxies }
+    }
+
+    // The gateway forwards the caller's `authorization` metadata untouched
+    async fn caller<T>(&self, request: &Request<T>) -> Result<AccessClaims, Status> {
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|auth| auth.strip_prefix("Bearer "))
+            .ok_or(AuthError::AuthenticationError)?;
+
+        Ok(self.auth_service.validate_access_token(token).await?)
+    }
+
+    // As in the REST handlers, x-forwarded-for is only read as far as `trusted_proxies`
+    // vouch for it; otherwise the peer is taken for the client
+    fn client_info<T>(&self, request: &Request<T>) -> ClientInfo {
+        let forwarded_for: Vec<&str> = request
+            .metadata()
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        let user_agent = request.metadata().get("user-agent").and_then(|value| value.to_str().ok());
+
+        self.trusted_proxies
+            .client_info(user_agent, &forwarded_for, request.remote_addr().map(|addr| addr.ip()))
+    }
+}
+
+/// TLS for the gRPC listener from `GRPC_TLS_CERT_PATH` and `GRPC_TLS_KEY_PATH`, requiring a
+/// client certificate issued by `GRPC_TLS_CLIENT_CA_PATH` when that is set too. Without a
+/// certificate the listener is plaintext.
+pub fn tls_config_from_env() -> std::io::Result<Option<ServerTlsConfig>> {
+    let (Ok(cert_path), Ok(key_path)) = (
+        std::env::var("GRPC_TLS_CERT_PATH"),
+        std::env::var("GRPC_TLS_KEY_PATH"),
+    ) else {
+        return Ok(None);
+    };
+
+    let identity = Identity::from_pem(std::fs::read(cert_path)?, std::fs::read(key_path)?);
+    let mut tls = ServerTlsConfig::new().identity(identity);
+    if let Ok(ca_path) = std::env::var("GRPC_TLS_CLIENT_CA_PATH") {
+        tls = tls.client_ca_root(Certificate::from_pem(std::fs::read(ca_path)?));
+    }
+    Ok(Some(tls))
+}
+
+#[tonic::async_trait]
+impl pb::auth_service_server::AuthService for AuthGrpcService {
+    async fn register(
+        &self,
+        request: Request<pb::RegisterRequest>,
+    ) -> Result<Response<pb::RegisterResponse>, Status> {
+        let request = request.into_inner();
+        let req = models::RegisterRequest {
+            email: request.email,
+            passphrase: request.passphrase,
+            username: request.username,
+        };
+        req.validate()
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+
+        let user = self.auth_service.register(req).await?;
+
+        Ok(Response::new(pb::RegisterResponse {
+            user_id: user.id.to_string(),
+            // Only ever sent by email; handing it back would let anyone verify an address
+            // they don't own
+            verification_token: String::new(),
+        }))
+    }
+
+    async fn login(
+        &self,
+        request: Request<pb::LoginRequest>,
+    ) -> Result<Response<pb::LoginResponse>, Status> {
+        let client = self.client_info(&request);
+        let request = request.into_inner();
+        let req = models::LoginRequest {
+            email: request.email,
+            passphrase: request.passphrase,
+            device_name: None,
+        };
+        req.validate()
+            .map_err(|_| AuthError::InvalidCredentials)?;
+
+        let response = match self.auth_service.login(req, client).await? {
+            LoginOutcome::Authenticated(tokens) => pb::LoginResponse {
+                access_token: tokens.access_token,
+                refresh_token: tokens.refresh_token,
+                requires_2fa: false,
+                challenge_token: String::new(),
+            },
+            // The client follows up with Verify2FA
+            LoginOutcome::TotpRequired { challenge_token, .. } => pb::LoginResponse {
+                requires_2fa: true,
+                challenge_token,
+                ..Default::default()
+            },
+        };
+
+        Ok(Response::new(response))
+    }
+
+    async fn refresh(
+        &self,
+        request: Request<pb::RefreshRequest>,
+    ) -> Result<Response<pb::RefreshResponse>, Status> {
+        let client = self.client_info(&request);
+        let tokens = self
+            .auth_service
+            .refresh_token(&request.get_ref().refresh_token, client)
+            .await?;
+
+        Ok(Response::new(pb::RefreshResponse {
+            access_token: tokens.access_token,
+            refresh_token: tokens.refresh_token,
+        }))
+    }
+
+    async fn verify2_fa(
+        &self,
+        request: Request<pb::Verify2FaRequest>,
+    ) -> Result<Response<pb::Verify2FaResponse>, Status> {
+        let client = self.client_info(&request);
+        let request = request.into_inner();
+        let tokens = self
+            .auth_service
+            .verify_totp_login(&request.challenge_token, &request.code, client)
+            .await?;
+
+        Ok(Response::new(pb::Verify2FaResponse {
+            access_token: tokens.access_token,
+            refresh_token: tokens.refresh_token,
+        }))
+    }
+
+    async fn setup2_fa(
+        &self,
+        request: Request<pb::Setup2FaRequest>,
+    ) -> Result<Response<pb::Setup2FaResponse>, Status> {
+        let caller = self.caller(&request).await?;
+        let user = self.auth_service.get_user(caller.user_id).await?;
+        if !request.get_ref().email.is_empty() && !request.get_ref().email.eq_ignore_ascii_case(&user.email) {
+            return Err(Status::permission_denied("email does not match the authenticated user"));
+        }
+
+        // Enabled through the REST `/2fa/enable` route once the user confirms a code
+        let setup = self.auth_service.setup_totp(caller.user_id).await?;
+
+        Ok(Response::new(pb::Setup2FaResponse {
+            secret: setup.secret,
+            qr_code: setup.qr_code_url,
+        }))
+    }
+
+    async fn validate_token(
+        &self,
+        request: Request<pb::ValidateTokenRequest>,
+    ) -> Result<Response<pb::ValidateTokenResponse>, Status> {
+        let response = match self.auth_service.validate_access_token(&request.get_ref().token).await {
+            Ok(claims) => pb::ValidateTokenResponse {
+                valid: true,
+                user_id: claims.user_id.to_string(),
+            },
+            Err(AuthError::InvalidToken) => pb::ValidateTokenResponse::default(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Response::new(response))
+    }
+
+    async fn reset_password(
+        &self,
+        request: Request<pb::ResetPasswordRequest>,
+    ) -> Result<Response<pb::ResetPasswordResponse>, Status> {
+        // Unknown and unverified addresses look like any other, so the RPC can't be used to
+        // find out who has an account
+        match self.auth_service.initiate_password_reset(&request.get_ref().email).await {
+            Ok(()) | Err(AuthError::UserNotFound) | Err(AuthError::AuthenticationError) => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(Response::new(pb::ResetPasswordResponse { success: true }))
+    }
+
+    async fn verify_email(
+        &self,
+        request: Request<pb::VerifyEmailRequest>,
+    ) -> Result<Response<pb::VerifyEmailResponse>, Status> {
+        self.auth_service.verify_email(&request.get_ref().token).await?;
+
+        Ok(Response::new(pb::VerifyEmailResponse { success: true }))
+    }
+}
@@ -1,32 +1,40 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
 use axum::{
-    routing::{get, post},
-    extract::Query,
+    routing::{delete, get, post},
+    extract::{ConnectInfo, Path, Query},
+    http::{header, HeaderMap},
+    response::IntoResponse,
     Extension, Json, Router,
 };
 use validator::Validate;
 
 use crate::{
-    api::models::{AuthResponse, LoginRequest, RegisterRequest},
+    api::models::{
+        AuthResponse, LoginRequest, LoginResponse, RegisterRequest, SessionResponse,
+        TotpChallengeResponse, VerifyTotpRequest,
+    },
     error::AuthError,
     middleware::{auth::auth_middleware, auth::refresh_token_middleware},
-    repository::fdb::FdbUserRepository,
-    service::{auth::AuthService, jwt::JwtService, models::User},
+    service::{auth::{AuthService, LoginOutcome}, jwt::JwtService, models::{ClientInfo, TrustedProxies, User}},
 };
+use uuid::Uuid;
 
-pub fn auth_routes(jwt_service: Arc<JwtService>) -> Router {
-    let repository = Arc::new(FdbUserRepository::new(
-        foundationdb::Database::new(None).expect("Failed to create FDB database"),
-    ));
-    let auth_service = Arc::new(AuthService::new(repository, jwt_service.clone()));
-
+pub fn auth_routes(
+    auth_service: Arc<AuthService>,
+    jwt_service: Arc<JwtService>,
+    trusted_proxies: TrustedProxies,
+) -> Router {
     Router::new()
         .route("/register", post(register))
         .route("/login", post(login))
+        .route("/2fa/verify", post(verify_totp_login))
         .route("/refresh", post(refresh_token))
+        .route("/logout", post(logout))
         .route("/verify-email", get(verify_email))
         .route("/request-password-reset", post(request_password_reset))
         .route("/reset-password", post(reset_password))
+        .route("/.well-known/jwks.json", get(jwks))
         .route(
             "/me",
             get(get_current_user).route_layer(axum::middleware::from_fn_with_state(
@@ -34,6 +42,22 @@ pub fn auth_routes(jwt_service: Arc<JwtService>) -> Router {
                 auth_middleware,
             )),
         )
+        .route(
+            "/sessions",
+            get(list_sessions)
+                .delete(revoke_other_sessions)
+                .route_layer(axum::middleware::from_fn_with_state(
+                    jwt_service.clone(),
+                    auth_middleware,
+                )),
+        )
+        .route(
+            "/sessions/:id",
+            delete(revoke_session).route_layer(axum::middleware::from_fn_with_state(
+                jwt_service.clone(),
+                auth_middleware,
+            )),
+        )
         .route(
             "/2fa/setup",
             post(setup_totp).route_layer(axum::middleware::from_fn_with_state(
@@ -51,11 +75,13 @@ pub fn auth_routes(jwt_service: Arc<JwtService>) -> Router {
         .route(
             "/2fa/disable",
             post(disable_totp).route_layer(axum::middleware::from_fn_with_state(
-                jwt_service,
+                jwt_service.clone(),
                 auth_middleware,
             )),
         )
         .layer(Extension(auth_service))
+        .layer(Extension(jwt_service))
+        .layer(Extension(trusted_proxies))
 }
 
 async fn register(
@@ -71,17 +97,47 @@ async fn register(
 
 async fn login(
     Extension(auth_service): Extension<Arc<AuthService>>,
+    Extension(trusted_proxies): Extension<TrustedProxies>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(req): Json<LoginRequest>,
+) -> Result<Json<LoginResponse>, AuthError> {
+    req.validate()
+        .map_err(|_| AuthError::InvalidCredentials)?;
+
+    let response = match auth_service.login(req, client_info(&headers, trusted_proxies, peer)).await? {
+        LoginOutcome::Authenticated(token) => LoginResponse::Authenticated(token),
+        LoginOutcome::TotpRequired { challenge_token, expires_in } => {
+            LoginResponse::TotpRequired(TotpChallengeResponse {
+                requires_2fa: true,
+                challenge_token,
+                expires_in,
+            })
+        }
+    };
+    Ok(Json(response))
+}
+
+async fn verify_totp_login(
+    Extension(auth_service): Extension<Arc<AuthService>>,
+    Extension(trusted_proxies): Extension<TrustedProxies>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<VerifyTotpRequest>,
 ) -> Result<Json<AuthResponse>, AuthError> {
     req.validate()
         .map_err(|_| AuthError::InvalidCredentials)?;
 
-    let token = auth_service.login(req).await?;
+    let token = auth_service
+        .verify_totp_login(&req.challenge_token, &req.code, client_info(&headers, trusted_proxies, peer))
+        .await?;
     Ok(Json(token))
 }
 
 async fn refresh_token(
     Extension(auth_service): Extension<Arc<AuthService>>,
+    Extension(trusted_proxies): Extension<TrustedProxies>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     headers: axum::http::header::HeaderMap,
 ) -> Result<Json<AuthResponse>, AuthError> {
     let refresh_token = headers
@@ -90,10 +146,56 @@ async fn refresh_token(
         .and_then(|auth| auth.strip_prefix("Bearer "))
         .ok_or(AuthError::AuthenticationError)?;
 
-    let new_tokens = auth_service.refresh_token(refresh_token).await?;
+    let new_tokens = auth_service.refresh_token(refresh_token, client_info(&headers, trusted_proxies, peer)).await?;
     Ok(Json(new_tokens))
 }
 
+async fn logout(
+    Extension(auth_service): Extension<Arc<AuthService>>,
+    headers: axum::http::header::HeaderMap,
+) -> Result<Json<()>, AuthError> {
+    let refresh_token = headers
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|auth| auth.strip_prefix("Bearer "))
+        .ok_or(AuthError::AuthenticationError)?;
+
+    auth_service.logout(refresh_token).await?;
+    Ok(Json(()))
+}
+
+async fn list_sessions(
+    Extension(auth_service): Extension<Arc<AuthService>>,
+    Extension(auth_context): Extension<crate::middleware::auth::AuthContext>,
+) -> Result<Json<Vec<SessionResponse>>, AuthError> {
+    let sessions = auth_service
+        .list_sessions(auth_context.user_id, auth_context.session_id)
+        .await?;
+    Ok(Json(sessions))
+}
+
+async fn revoke_session(
+    Extension(auth_service): Extension<Arc<AuthService>>,
+    Extension(auth_context): Extension<crate::middleware::auth::AuthContext>,
+    Path(session_id): Path<Uuid>,
+) -> Result<Json<()>, AuthError> {
+    auth_service
+        .revoke_session(auth_context.user_id, auth_context.session_id, session_id)
+        .await?;
+    Ok(Json(()))
+}
+
+// `DELETE /sessions` signs out everywhere except the calling device
+async fn revoke_other_sessions(
+    Extension(auth_service): Extension<Arc<AuthService>>,
+    Extension(auth_context): Extension<crate::middleware::auth::AuthContext>,
+) -> Result<Json<()>, AuthError> {
+    auth_service
+        .revoke_other_sessions(auth_context.user_id, auth_context.session_id)
+        .await?;
+    Ok(Json(()))
+}
+
 async fn get_current_user(
     Extension(auth_service): Extension<Arc<AuthService>>,
     Extension(auth_context): Extension<crate::middleware::auth::AuthContext>,
@@ -156,4 +258,26 @@ async fn reset_password(
 ) -> Result<Json<()>, AuthError> {
     auth_service.reset_password(&req.token, &req.new_passphrase).await?;
     Ok(Json(()))
-}
\ No newline at end of file
+}
+
+// Verifiers may cache the key set for a few minutes; new keys are published well before
+// they sign anything
+async fn jwks(Extension(jwt_service): Extension<Arc<JwtService>>) -> impl IntoResponse {
+    (
+        [(header::CACHE_CONTROL, "public, max-age=300")],
+        Json(jwt_service.jwks()),
+    )
+}
+
+// X-Forwarded-For is only read as far as `trusted_proxies` vouch for it; otherwise the peer
+// is taken for the client
+fn client_info(headers: &HeaderMap, trusted_proxies: TrustedProxies, peer: SocketAddr) -> ClientInfo {
+    let forwarded_for: Vec<&str> = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    let user_agent = headers.get("User-Agent").and_then(|value| value.to_str().ok());
+
+    trusted_proxies.client_info(user_agent, &forwarded_for, Some(peer.ip()))
+}
@@ -1,6 +1,7 @@
 mod auth;
 mod db;
 mod error;
+mod grpc;
 mod handlers;
 mod middleware;
 mod models;
@@ -11,7 +12,7 @@ use axum::{
     Extension, Router,
 };
 use foundationdb::Database;
-use std::{net::SocketAddr, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tower::ServiceBuilder;
 use tower_http::{
     compression::CompressionLayer,
@@ -21,7 +22,17 @@ use tower_http::{
 };
 use tracing::{info, Level};
 
-use crate::{db::FdbPool, handlers::auth_routes};
+use crate::{
+    grpc::{AuthGrpcService, AuthServiceServer},
+    handlers::auth_routes,
+    repository::fdb::FdbUserRepository,
+    service::{
+        auth::AuthService,
+        jwt::JwtService,
+        keys::{KeyStore, KeyStoreConfig},
+        models::TrustedProxies,
+    },
+};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -36,7 +47,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize FoundationDB
     foundationdb::init().expect("Failed to initialize FoundationDB");
     let db = Database::new(None)?;
-    let db_pool = FdbPool::new(db);
+    let repository = Arc::new(FdbUserRepository::new(db));
 
     // Configure CORS
     let cors = CorsLayer::new()
@@ -59,8 +70,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .timeout(Duration::from_secs(30))
         .into_inner();
 
-    // Initialize JWT service
-    let jwt_service = Arc::new(JwtService::new()?);
+    // Load the JWT signing keys and keep rotating them
+    let key_store = Arc::new(KeyStore::load(KeyStoreConfig::from_env())?);
+    tokio::spawn(key_store.clone().run());
+    let jwt_service = Arc::new(JwtService::new(key_store));
 
     // Initialize email service
     let email_service = Arc::new(EmailService::new(
@@ -72,18 +85,43 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         std::env::var("APP_URL").unwrap_or_else(|_| "https://selfie.app".to_string()),
     ).await?;
 
+    // REST and gRPC run on the same core
+    let auth_service = Arc::new(AuthService::new(repository, jwt_service.clone(), email_service));
+
+    // Proxies whose X-Forwarded-For entries are believed, for the addresses sessions record
+    let trusted_proxies = TrustedProxies::from_env();
+
     // Build our application with routes
     let app = Router::new()
-        .merge(auth_routes(jwt_service))
+        .merge(auth_routes(auth_service.clone(), jwt_service, trusted_proxies))
         .layer(middleware);
 
+    // gRPC for the gateway, with grpc.health.v1 for its probes
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter
+        .set_serving::<AuthServiceServer<AuthGrpcService>>()
+        .await;
+
+    let mut grpc = tonic::transport::Server::builder();
+    if let Some(tls) = grpc::tls_config_from_env()? {
+        grpc = grpc.tls_config(tls)?;
+    }
+
     // Run our service
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-    info!("Auth service listening on {}", addr);
-    
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await?;
+    let grpc_addr = SocketAddr::from(([0, 0, 0, 0], 50051));
+    info!("Auth service listening on {} (REST) and {} (gRPC)", addr, grpc_addr);
+
+    let rest_server = axum::Server::bind(&addr).serve(app.into_make_service_with_connect_info::<SocketAddr>());
+    let grpc_server = grpc
+        .add_service(health_service)
+        .add_service(AuthServiceServer::new(AuthGrpcService::new(auth_service, trusted_proxies)))
+        .serve(grpc_addr);
+
+    tokio::try_join!(
+        async { rest_server.await.map_err(Box::<dyn std::error::Error>::from) },
+        async { grpc_server.await.map_err(Box::<dyn std::error::Error>::from) },
+    )?;
 
     Ok(())
     println!("Server running on {{}}", addr);
@@ -12,6 +12,7 @@ use crate::{error::AuthError, service::jwt::JwtService};
 #[derive(Clone)]
 pub struct AuthContext {
     pub user_id: Uuid,
+    pub session_id: Option<Uuid>,
 }
 
 pub async fn auth_middleware<B>(
@@ -30,9 +31,12 @@ pub async fn auth_middleware<B>(
     }
 
     let token = &auth_header[7..];
-    let user_id = jwt_service.verify_token(token, "access")?;
+    let claims = jwt_service.verify_access_token(token)?;
 
-    request.extensions_mut().insert(AuthContext { user_id });
+    request.extensions_mut().insert(AuthContext {
+        user_id: claims.user_id,
+        session_id: claims.session_id,
+    });
 
     Ok(next.run(request).await)
 }
@@ -1,18 +1,22 @@
 use std::sync::Arc;
 use async_trait::async_trait;
-use foundationdb::{Database, RangeOption};
+use foundationdb::{options::StreamingMode, Database, RangeOption};
+use time::OffsetDateTime;
 use uuid::Uuid;
 
 use crate::{
     error::AuthError,
-    repository::UserRepository,
-    service::models::User,
+    repository::{RefreshRotation, UserRepository},
+    service::models::{ClientInfo, TokenFamily, User},
 };
 
 const USER_PREFIX: &[u8] = b"user";
 const EMAIL_INDEX_PREFIX: &[u8] = b"email_idx";
+const USERNAME_INDEX_PREFIX: &[u8] = b"uname_idx";
 const VERIFICATION_TOKEN_PREFIX: &[u8] = b"verify";
 const RESET_TOKEN_PREFIX: &[u8] = b"reset";
+const TOKEN_FAMILY_PREFIX: &[u8] = b"token_family";
+const FAMILY_INDEX_PREFIX: &[u8] = b"family_idx";
 
 pub struct FdbUserRepository {
     db: Arc<Database>,
@@ -39,6 +43,13 @@ impl FdbUserRepository {
         key
     }
 
+    fn make_username_key(username: &str) -> Vec<u8> {
+        let mut key = Vec::with_capacity(USERNAME_INDEX_PREFIX.len() + username.len());
+        key.extend_from_slice(USERNAME_INDEX_PREFIX);
+        key.extend_from_slice(username.as_bytes());
+        key
+    }
+
     fn make_verification_key(token: &str) -> Vec<u8> {
         let mut key = Vec::with_capacity(VERIFICATION_TOKEN_PREFIX.len() + token.len());
         key.extend_from_slice(VERIFICATION_TOKEN_PREFIX);
@@ -52,6 +63,37 @@ impl FdbUserRepository {
         key.extend_from_slice(token.as_bytes());
         key
     }
+
+    fn make_family_key(id: &Uuid) -> Vec<u8> {
+        let mut key = Vec::with_capacity(TOKEN_FAMILY_PREFIX.len() + 16);
+        key.extend_from_slice(TOKEN_FAMILY_PREFIX);
+        key.extend_from_slice(id.as_bytes());
+        key
+    }
+
+    fn make_family_index_key(user_id: &Uuid, family_id: &Uuid) -> Vec<u8> {
+        let mut key = Self::make_family_index_prefix(user_id);
+        key.extend_from_slice(family_id.as_bytes());
+        key
+    }
+
+    fn make_family_index_prefix(user_id: &Uuid) -> Vec<u8> {
+        let mut key = Vec::with_capacity(FAMILY_INDEX_PREFIX.len() + 32);
+        key.extend_from_slice(FAMILY_INDEX_PREFIX);
+        key.extend_from_slice(user_id.as_bytes());
+        key
+    }
+
+    // Every index entry of the user's token families
+    fn family_index_range(user_id: &Uuid) -> RangeOption<'static> {
+        let begin = Self::make_family_index_prefix(user_id);
+        let mut end = begin.clone();
+        end.push(0xff);
+        RangeOption {
+            mode: StreamingMode::WantAll,
+            ..RangeOption::from((begin, end))
+        }
+    }
 }
 
 #[async_trait]
@@ -66,6 +108,11 @@ impl UserRepository for FdbUserRepository {
                 return Err(AuthError::UserExists);
             }
 
+            let username_key = Self::make_username_key(&user.username);
+            if tr.get(&username_key).await?.is_some() {
+                return Err(AuthError::UsernameTaken);
+            }
+
             // Store user data
             let user_key = Self::make_user_key(&user.id);
             let user_bytes = serde_json::to_vec(&user)
@@ -73,6 +120,7 @@ impl UserRepository for FdbUserRepository {
             
             tr.set(&user_key, &user_bytes);
             tr.set(&email_key, &user.id.as_bytes());
+            tr.set(&username_key, &user.id.as_bytes());
 
             // Set verification token index if exists
             if let Some(token) = &user.email_verification_token {
@@ -198,4 +246,218 @@ impl UserRepository for FdbUserRepository {
             }
         }).await
     }
+
+    async fn claim_totp_attempt(
+        &self,
+        user_id: &Uuid,
+        challenge_id: &Uuid,
+        max_attempts: u32,
+    ) -> Result<Option<User>, AuthError> {
+        let db = self.db.clone();
+
+        // Read and counted in one transaction, so parallel guesses can't share an attempt
+        db.run(|tr| async move {
+            let user_key = Self::make_user_key(user_id);
+            let Some(bytes) = tr.get(&user_key).await? else {
+                return Ok(None);
+            };
+
+            let mut user: User = serde_json::from_slice(&bytes)
+                .map_err(|_| AuthError::InternalError)?;
+
+            let now = OffsetDateTime::now_utc();
+            let Some(challenge) = user.totp_challenge.as_mut() else {
+                return Ok(None);
+            };
+            if challenge.id != *challenge_id || challenge.attempts >= max_attempts || challenge.expires_at <= now {
+                return Ok(None);
+            }
+
+            challenge.attempts += 1;
+            user.failed_login_attempts += 1;
+            user.updated_at = now;
+
+            let user_bytes = serde_json::to_vec(&user)
+                .map_err(|_| AuthError::InternalError)?;
+            tr.set(&user_key, &user_bytes);
+
+            Ok(Some(user))
+        }).await
+    }
+
+    async fn record_login(
+        &self,
+        user_id: &Uuid,
+        challenge_id: Option<&Uuid>,
+        at: OffsetDateTime,
+    ) -> Result<bool, AuthError> {
+        let db = self.db.clone();
+
+        // Checked and cleared in one transaction, so parallel requests with the same valid
+        // code can't both complete the challenge
+        db.run(|tr| async move {
+            let user_key = Self::make_user_key(user_id);
+            let Some(bytes) = tr.get(&user_key).await? else {
+                return Ok(false);
+            };
+
+            let mut user: User = serde_json::from_slice(&bytes)
+                .map_err(|_| AuthError::InternalError)?;
+
+            if let Some(challenge_id) = challenge_id {
+                if user.totp_challenge.as_ref().map(|challenge| challenge.id) != Some(*challenge_id) {
+                    return Ok(false);
+                }
+            }
+
+            user.totp_challenge = None;
+            user.failed_login_attempts = 0;
+            user.last_login = Some(at);
+            user.updated_at = at;
+
+            let user_bytes = serde_json::to_vec(&user)
+                .map_err(|_| AuthError::InternalError)?;
+            tr.set(&user_key, &user_bytes);
+
+            Ok(true)
+        }).await
+    }
+
+    async fn create_token_family(&self, family: &TokenFamily) -> Result<(), AuthError> {
+        let db = self.db.clone();
+
+        db.run(|tr| async move {
+            // Families are only deleted when revoked; clear out the user's expired ones
+            // before adding another
+            let now = OffsetDateTime::now_utc();
+            let entries = tr.get_range(&Self::family_index_range(&family.user_id), 1, false).await?;
+            for entry in entries.iter() {
+                let family_id = Uuid::from_slice(&entry.key()[entry.key().len() - 16..])
+                    .map_err(|_| AuthError::InternalError)?;
+                let family_key = Self::make_family_key(&family_id);
+
+                let expired = match tr.get(&family_key).await? {
+                    Some(bytes) => {
+                        let existing: TokenFamily = serde_json::from_slice(&bytes)
+                            .map_err(|_| AuthError::InternalError)?;
+                        existing.expires_at <= now
+                    }
+                    None => true,
+                };
+                if expired {
+                    tr.clear(&family_key);
+                    tr.clear(entry.key());
+                }
+            }
+
+            let family_bytes = serde_json::to_vec(&family)
+                .map_err(|_| AuthError::InternalError)?;
+
+            tr.set(&Self::make_family_key(&family.id), &family_bytes);
+            tr.set(&Self::make_family_index_key(&family.user_id, &family.id), &[]);
+
+            Ok(())
+        }).await
+    }
+
+    async fn rotate_refresh_token(
+        &self,
+        family_id: &Uuid,
+        jti: &str,
+        next_jti: &str,
+        expires_at: OffsetDateTime,
+        client: &ClientInfo,
+    ) -> Result<RefreshRotation, AuthError> {
+        let db = self.db.clone();
+
+        // One transaction, so of two requests racing with the same token only one rotates
+        // and the other counts as reuse
+        db.run(|tr| async move {
+            let family_key = Self::make_family_key(family_id);
+            let Some(bytes) = tr.get(&family_key).await? else {
+                return Ok(RefreshRotation::Revoked);
+            };
+
+            let mut family: TokenFamily = serde_json::from_slice(&bytes)
+                .map_err(|_| AuthError::InternalError)?;
+
+            if family.current_jti != jti {
+                tr.clear(&family_key);
+                tr.clear(&Self::make_family_index_key(&family.user_id, &family.id));
+                return Ok(RefreshRotation::Reused(family));
+            }
+
+            family.current_jti = next_jti.to_string();
+            family.client = client.clone();
+            family.last_refreshed = OffsetDateTime::now_utc();
+            family.expires_at = expires_at;
+
+            let family_bytes = serde_json::to_vec(&family)
+                .map_err(|_| AuthError::InternalError)?;
+            tr.set(&family_key, &family_bytes);
+
+            Ok(RefreshRotation::Rotated)
+        }).await
+    }
+
+    async fn list_token_families(&self, user_id: &Uuid) -> Result<Vec<TokenFamily>, AuthError> {
+        let db = self.db.clone();
+
+        db.run(|tr| async move {
+            let now = OffsetDateTime::now_utc();
+            let entries = tr.get_range(&Self::family_index_range(user_id), 1, false).await?;
+
+            let mut families = Vec::new();
+            for entry in entries.iter() {
+                let family_id = Uuid::from_slice(&entry.key()[entry.key().len() - 16..])
+                    .map_err(|_| AuthError::InternalError)?;
+
+                if let Some(bytes) = tr.get(&Self::make_family_key(&family_id)).await? {
+                    let family: TokenFamily = serde_json::from_slice(&bytes)
+                        .map_err(|_| AuthError::InternalError)?;
+                    if family.expires_at > now {
+                        families.push(family);
+                    }
+                }
+            }
+
+            Ok(families)
+        }).await
+    }
+
+    async fn revoke_token_family(&self, user_id: &Uuid, family_id: &Uuid) -> Result<bool, AuthError> {
+        let db = self.db.clone();
+
+        db.run(|tr| async move {
+            // Found through the user's index, so one user can't revoke another's family
+            let index_key = Self::make_family_index_key(user_id, family_id);
+            if tr.get(&index_key).await?.is_none() {
+                return Ok(false);
+            }
+
+            tr.clear(&Self::make_family_key(family_id));
+            tr.clear(&index_key);
+            Ok(true)
+        }).await
+    }
+
+    async fn revoke_token_families(&self, user_id: &Uuid, except: Option<&Uuid>) -> Result<(), AuthError> {
+        let db = self.db.clone();
+
+        db.run(|tr| async move {
+            let entries = tr.get_range(&Self::family_index_range(user_id), 1, false).await?;
+            for entry in entries.iter() {
+                let family_id = Uuid::from_slice(&entry.key()[entry.key().len() - 16..])
+                    .map_err(|_| AuthError::InternalError)?;
+                if Some(&family_id) == except {
+                    continue;
+                }
+
+                tr.clear(&Self::make_family_key(&family_id));
+                tr.clear(entry.key());
+            }
+
+            Ok(())
+        }).await
+    }
 }
\ No newline at end of file
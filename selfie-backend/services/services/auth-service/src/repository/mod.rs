@@ -1,8 +1,19 @@
 use async_trait::async_trait;
+use time::OffsetDateTime;
 use uuid::Uuid;
-use crate::service::models::User;
+use crate::service::models::{ClientInfo, TokenFamily, User};
 use crate::error::AuthError;
 
+/// What became of a refresh token presented for rotation.
+pub enum RefreshRotation {
+    /// It was its family's current token and has been replaced by the new one
+    Rotated,
+    /// It had been exchanged before; the family has been revoked
+    Reused(TokenFamily),
+    /// Its family is gone: logged out, revoked or expired
+    Revoked,
+}
+
 #[async_trait]
 pub trait UserRepository: Send + Sync + 'static {
     async fn create_user(&self, user: &User) -> Result<(), AuthError>;
@@ -11,4 +22,37 @@ pub trait UserRepository: Send + Sync + 'static {
     async fn update_user(&self, user: &User) -> Result<(), AuthError>;
     async fn get_user_by_verification_token(&self, token: &str) -> Result<Option<User>, AuthError>;
     async fn get_user_by_reset_token(&self, token: &str) -> Result<Option<User>, AuthError>;
-}
\ No newline at end of file
+    /// Counts an attempt at the user's pending TOTP challenge `challenge_id`, as a failed
+    /// login until it succeeds. Returns the updated user, or `None` if that challenge is no
+    /// longer pending: completed, replaced by a newer login, expired or out of attempts.
+    async fn claim_totp_attempt(
+        &self,
+        user_id: &Uuid,
+        challenge_id: &Uuid,
+        max_attempts: u32,
+    ) -> Result<Option<User>, AuthError>;
+    /// Completes a login: clears the pending TOTP challenge, resets failed attempts and sets
+    /// `last_login`. Given a `challenge_id`, only while that challenge is still pending, so
+    /// each challenge completes one login; returns false if it no longer is.
+    async fn record_login(
+        &self,
+        user_id: &Uuid,
+        challenge_id: Option<&Uuid>,
+        at: OffsetDateTime,
+    ) -> Result<bool, AuthError>;
+    async fn create_token_family(&self, family: &TokenFamily) -> Result<(), AuthError>;
+    async fn rotate_refresh_token(
+        &self,
+        family_id: &Uuid,
+        jti: &str,
+        next_jti: &str,
+        expires_at: OffsetDateTime,
+        client: &ClientInfo,
+    ) -> Result<RefreshRotation, AuthError>;
+    /// The user's token families that have not expired.
+    async fn list_token_families(&self, user_id: &Uuid) -> Result<Vec<TokenFamily>, AuthError>;
+    /// Returns false if the user has no such family.
+    async fn revoke_token_family(&self, user_id: &Uuid, family_id: &Uuid) -> Result<bool, AuthError>;
+    /// Revokes all the user's token families but `except`.
+    async fn revoke_token_families(&self, user_id: &Uuid, except: Option<&Uuid>) -> Result<(), AuthError>;
+}
@@ -3,18 +3,32 @@ use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
-use time::OffsetDateTime;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 use zxcvbn::zxcvbn;
 
 use crate::{
-    api::models::{AuthResponse, LoginRequest, RegisterRequest, TotpSecretResponse, EnableTotpRequest},
+    api::models::{AuthResponse, LoginRequest, RegisterRequest, SessionResponse, TotpSecretResponse, EnableTotpRequest},
     error::AuthError,
-    repository::UserRepository,
-    service::{jwt::JwtService, totp::TotpService, models::{User, UserStatus}},
+    repository::{RefreshRotation, UserRepository},
+    service::{
+        jwt::{AccessClaims, JwtService, TOTP_CHALLENGE_DURATION},
+        totp::TotpService,
+        models::{ClientInfo, TokenFamily, TotpChallenge, User, UserStatus},
+    },
 };
 
 const MIN_ENTROPY_BITS: f64 = 50.0; // Requires a strong passphrase
+const MAX_TOTP_ATTEMPTS: u32 = 5; // Codes tried per 2FA challenge
+
+/// How far a login got.
+pub enum LoginOutcome {
+    Authenticated(AuthResponse),
+    /// The passphrase was right and the account has 2FA; `verify_totp_login` exchanges the
+    /// challenge token and a code for the pair
+    TotpRequired { challenge_token: String, expires_in: i64 },
+}
 
 pub struct AuthService {
     repository: Arc<dyn UserRepository>,
@@ -42,6 +56,8 @@ impl AuthService {
     }
 
     pub async fn register(&self, req: RegisterRequest) -> Result<User, AuthError> {
+        let username = normalize_username(&req.username)?;
+
         // Validate passphrase strength
         let entropy = zxcvbn(&req.passphrase, &[&req.email])
             .map_err(|_| AuthError::InternalError)?;
@@ -60,13 +76,18 @@ impl AuthService {
             .map_err(|_| AuthError::InternalError)?
             .to_string();
 
-        let user = User::new(req.email, passphrase_hash);
+        let user = User::new(req.email, username, passphrase_hash);
         self.repository.create_user(&user).await?;
 
+        // The account exists either way; a lost email only delays verification
+        if let Err(err) = self.send_verification_email(&user).await {
+            error!("Failed to send verification email to user {}: {}", user.id, err);
+        }
+
         Ok(user)
     }
 
-    pub async fn login(&self, req: LoginRequest) -> Result<AuthResponse, AuthError> {
+    pub async fn login(&self, req: LoginRequest, client: ClientInfo) -> Result<LoginOutcome, AuthError> {
         let user = self
             .repository
             .get_user_by_email(&req.email)
@@ -86,31 +107,92 @@ impl AuthService {
             .verify_password(req.passphrase.as_bytes(), &parsed_hash)
             .is_err()
         {
-            // Update failed login attempts
+            self.record_failed_login(user).await?;
+            return Err(AuthError::InvalidCredentials);
+        }
+
+        // With 2FA the passphrase only earns a challenge; the code comes in a second request
+        if user.totp_enabled {
+            let challenge_id = Uuid::new_v4();
+            let (challenge_token, expires_at) = self.jwt_service.generate_totp_challenge_token(user.id, challenge_id)?;
+
             let mut user = user;
-            user.failed_login_attempts += 1;
+            user.totp_challenge = Some(TotpChallenge {
+                id: challenge_id,
+                attempts: 0,
+                device_name: req.device_name,
+                expires_at,
+            });
             user.updated_at = OffsetDateTime::now_utc();
             self.repository.update_user(&user).await?;
 
-            return Err(AuthError::InvalidCredentials);
+            return Ok(LoginOutcome::TotpRequired {
+                challenge_token,
+                expires_in: TOTP_CHALLENGE_DURATION,
+            });
         }
 
-        // Verify TOTP if enabled
-        if user.totp_enabled {
-            let totp_code = req.totp_code.ok_or(AuthError::AuthenticationError)?;
-            self.verify_totp(&user, &totp_code).await?;
+        let tokens = self.start_session(user.id, None, req.device_name, client).await?;
+        Ok(LoginOutcome::Authenticated(tokens))
+    }
+
+    /// Second step of a login that returned `TotpRequired`. Each challenge takes a few codes;
+    /// wrong ones also count towards the lockout that wrong passphrases do.
+    pub async fn verify_totp_login(&self, challenge_token: &str, code: &str, client: ClientInfo) -> Result<AuthResponse, AuthError> {
+        let claims = self.jwt_service.verify_totp_challenge_token(challenge_token)?;
+
+        let user = self.get_user(claims.user_id).await?;
+        if user.is_locked() {
+            return Err(AuthError::RateLimitExceeded);
         }
 
-        // Generate tokens
-        let access_token = self.jwt_service.generate_access_token(user.id)?;
-        let refresh_token = self.jwt_service.generate_refresh_token(user.id)?;
+        // Counted before the code is checked, so guesses in parallel still run out
+        let user = self
+            .repository
+            .claim_totp_attempt(&claims.user_id, &claims.challenge_id, MAX_TOTP_ATTEMPTS)
+            .await?
+            .ok_or(AuthError::InvalidToken)?;
 
-        // Reset failed attempts and update last login
-        let mut user = user;
-        user.failed_login_attempts = 0;
-        user.last_login = Some(OffsetDateTime::now_utc());
+        self.verify_totp(&user, code).await?;
+
+        let device_name = user.totp_challenge.as_ref().and_then(|challenge| challenge.device_name.clone());
+        self.start_session(user.id, Some(&claims.challenge_id), device_name, client).await
+    }
+
+    async fn record_failed_login(&self, mut user: User) -> Result<(), AuthError> {
+        user.failed_login_attempts += 1;
         user.updated_at = OffsetDateTime::now_utc();
-        self.repository.update_user(&user).await?;
+        self.repository.update_user(&user).await
+    }
+
+    // Issues the token pair for a fully authenticated login, starting a new session and with
+    // it a refresh token family. A login completing TOTP challenge `challenge_id` consumes it
+    // first, so a challenge yields at most one session.
+    async fn start_session(
+        &self,
+        user_id: Uuid,
+        challenge_id: Option<&Uuid>,
+        device_name: Option<String>,
+        client: ClientInfo,
+    ) -> Result<AuthResponse, AuthError> {
+        let now = OffsetDateTime::now_utc();
+        if !self.repository.record_login(&user_id, challenge_id, now).await? {
+            return Err(AuthError::InvalidToken);
+        }
+
+        let session_id = Uuid::new_v4();
+        let access_token = self.jwt_service.generate_access_token(user_id, session_id)?;
+        let (refresh_token, refresh_claims) = self.jwt_service.generate_refresh_token(user_id, session_id)?;
+        self.repository.create_token_family(&TokenFamily {
+            id: session_id,
+            user_id,
+            current_jti: refresh_claims.jti,
+            device_name,
+            client,
+            created_at: now,
+            last_refreshed: now,
+            expires_at: refresh_claims.expires_at,
+        }).await?;
 
         Ok(AuthResponse {
             access_token,
@@ -120,12 +202,50 @@ impl AuthService {
         })
     }
 
-    pub async fn refresh_token(&self, refresh_token: &str) -> Result<AuthResponse, AuthError> {
-        let user_id = self.jwt_service.verify_token(refresh_token, "refresh")?;
-        
-        // Generate new tokens
-        let access_token = self.jwt_service.generate_access_token(user_id)?;
-        let new_refresh_token = self.jwt_service.generate_refresh_token(user_id)?;
+    pub async fn get_user(&self, user_id: Uuid) -> Result<User, AuthError> {
+        self.repository
+            .get_user_by_id(&user_id)
+            .await?
+            .ok_or(AuthError::UserNotFound)
+    }
+
+    /// Checks an access token and that its session hasn't been revoked since it was issued.
+    pub async fn validate_access_token(&self, token: &str) -> Result<AccessClaims, AuthError> {
+        let claims = self.jwt_service.verify_access_token(token)?;
+        if let Some(session_id) = claims.session_id {
+            let families = self.repository.list_token_families(&claims.user_id).await?;
+            if !families.iter().any(|family| family.id == session_id) {
+                return Err(AuthError::InvalidToken);
+            }
+        }
+        Ok(claims)
+    }
+
+    /// Exchanges a refresh token for a new pair. Each refresh token is good for one exchange;
+    /// presenting one a second time means someone else holds a copy, so the whole family is
+    /// revoked and both parties have to log in again.
+    pub async fn refresh_token(&self, refresh_token: &str, client: ClientInfo) -> Result<AuthResponse, AuthError> {
+        let claims = self.jwt_service.verify_refresh_token(refresh_token)?;
+        let (new_refresh_token, next) = self.jwt_service.generate_refresh_token(claims.user_id, claims.family)?;
+
+        let rotation = self.repository
+            .rotate_refresh_token(&claims.family, &claims.jti, &next.jti, next.expires_at, &client)
+            .await?;
+
+        match rotation {
+            RefreshRotation::Rotated => {}
+            RefreshRotation::Reused(family) => {
+                warn!(
+                    target: "security",
+                    "Refresh token {} reused for user {}; revoked token family {} (issued {}, last refreshed {})",
+                    claims.jti, family.user_id, family.id, family.created_at, family.last_refreshed
+                );
+                return Err(AuthError::InvalidToken);
+            }
+            RefreshRotation::Revoked => return Err(AuthError::InvalidToken),
+        }
+
+        let access_token = self.jwt_service.generate_access_token(claims.user_id, claims.family)?;
 
         Ok(AuthResponse {
             access_token,
@@ -135,6 +255,68 @@ impl AuthService {
         })
     }
 
+    /// Revokes the refresh token family `refresh_token` belongs to. Access tokens already
+    /// issued from it run out on their own.
+    pub async fn logout(&self, refresh_token: &str) -> Result<(), AuthError> {
+        let claims = self.jwt_service.verify_refresh_token(refresh_token)?;
+        self.repository.revoke_token_family(&claims.user_id, &claims.family).await?;
+        Ok(())
+    }
+
+    /// The user's signed-in devices, most recently used first.
+    pub async fn list_sessions(&self, user_id: Uuid, current: Option<Uuid>) -> Result<Vec<SessionResponse>, AuthError> {
+        let mut families = self.repository.list_token_families(&user_id).await?;
+        self.check_session(current, &families)?;
+        families.sort_by(|a, b| b.last_refreshed.cmp(&a.last_refreshed));
+
+        Ok(families
+            .into_iter()
+            .map(|family| SessionResponse {
+                id: family.id.to_string(),
+                device_name: family.device_name,
+                user_agent: family.client.user_agent,
+                ip: family.client.ip,
+                created_at: family.created_at.format(&Rfc3339).unwrap_or_default(),
+                last_seen: family.last_refreshed.format(&Rfc3339).unwrap_or_default(),
+                current: Some(family.id) == current,
+            })
+            .collect())
+    }
+
+    /// Signs a device out. Its refresh token stops working at once; access tokens already
+    /// issued to it run out within their 15 minutes.
+    pub async fn revoke_session(&self, user_id: Uuid, current: Option<Uuid>, session_id: Uuid) -> Result<(), AuthError> {
+        let families = self.repository.list_token_families(&user_id).await?;
+        self.check_session(current, &families)?;
+
+        if !self.repository.revoke_token_family(&user_id, &session_id).await? {
+            return Err(AuthError::SessionNotFound);
+        }
+        info!("User {} revoked session {}", user_id, session_id);
+        Ok(())
+    }
+
+    /// Signs out every device but the one making the request.
+    pub async fn revoke_other_sessions(&self, user_id: Uuid, current: Option<Uuid>) -> Result<(), AuthError> {
+        let families = self.repository.list_token_families(&user_id).await?;
+        self.check_session(current, &families)?;
+
+        // Without a session of its own the caller would be signed out too
+        let current = current.ok_or(AuthError::InvalidToken)?;
+        self.repository.revoke_token_families(&user_id, Some(&current)).await?;
+        info!("User {} revoked all sessions but {}", user_id, current);
+        Ok(())
+    }
+
+    // An access token outlives its session by up to 15 minutes; it must not be able to manage
+    // the sessions that are left, or a revoked device could sign the others out
+    fn check_session(&self, current: Option<Uuid>, families: &[TokenFamily]) -> Result<(), AuthError> {
+        match current {
+            Some(current) if !families.iter().any(|family| family.id == current) => Err(AuthError::InvalidToken),
+            _ => Ok(()),
+        }
+    }
+
     pub async fn setup_totp(&self, user_id: Uuid) -> Result<TotpSecretResponse, AuthError> {
         let mut user = self.repository
             .get_user_by_id(&user_id)
@@ -308,4 +490,18 @@ impl AuthService {
         
         self.repository.update_user(&user).await
     }
-}
\ No newline at end of file
+}
+
+// Usernames are compared and stored lowercase: 3 to 30 letters, digits, `_` or `.`
+fn normalize_username(username: &str) -> Result<String, AuthError> {
+    let username = username.trim().to_ascii_lowercase();
+    if !(3..=30).contains(&username.len()) {
+        return Err(AuthError::InvalidUsername("must be 3 to 30 characters".to_string()));
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+        return Err(AuthError::InvalidUsername(
+            "may only contain letters, digits, underscores and periods".to_string(),
+        ));
+    }
+    Ok(username)
+}
@@ -1,14 +1,17 @@
 use std::sync::Arc;
-use ed25519_dalek::{Keypair, SecretKey, Signer};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, TokenData, Validation};
+use ed25519_dalek::Signer;
+use jsonwebtoken::{decode, decode_header, encode, Header, TokenData, Validation};
 use serde::{Deserialize, Serialize};
 use time::{Duration, OffsetDateTime};
 use uuid::Uuid;
 
+use crate::api::models::JwksResponse;
 use crate::error::AuthError;
+use crate::service::keys::KeyStore;
 
 const ACCESS_TOKEN_DURATION: i64 = 900; // 15 minutes in seconds
-const REFRESH_TOKEN_DURATION: i64 = 2592000; // 30 days in seconds
+pub const REFRESH_TOKEN_DURATION: i64 = 2592000; // 30 days in seconds
+pub const TOTP_CHALLENGE_DURATION: i64 = 300; // 5 minutes to enter the TOTP code
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Claims {
@@ -18,32 +21,46 @@ struct Claims {
     jti: String,        // JWT ID
     #[serde(rename = "type")]
     token_type: String, // Token type (access or refresh)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fam: Option<String>, // Token family, refresh tokens only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sid: Option<String>, // Session (token family) issued from, access tokens only
+}
+
+/// Who an access token was issued to, and from which session.
+#[derive(Debug, Clone, Copy)]
+pub struct AccessClaims {
+    pub user_id: Uuid,
+    // Absent from tokens issued before sessions were tracked
+    pub session_id: Option<Uuid>,
+}
+
+/// The login a 2FA challenge token stands for.
+#[derive(Debug, Clone, Copy)]
+pub struct TotpChallengeClaims {
+    pub user_id: Uuid,
+    pub challenge_id: Uuid,
+}
+
+/// What a refresh token's rotation is tracked by.
+#[derive(Debug)]
+pub struct RefreshClaims {
+    pub user_id: Uuid,
+    pub jti: String,
+    pub family: Uuid,
+    pub expires_at: OffsetDateTime,
 }
 
 pub struct JwtService {
-    encoding_key: EncodingKey,
-    decoding_key: DecodingKey,
-    signing_keypair: Keypair,
+    keys: Arc<KeyStore>,
 }
 
 impl JwtService {
-    pub fn new() -> Result<Self, AuthError> {
-        // In production, load these from secure storage/HSM
-        let secret = ed25519_dalek::SigningKey::generate(&mut rand::thread_rng());
-        let public = secret.verifying_key();
-        let keypair = Keypair {
-            secret,
-            public,
-        };
-
-        Ok(Self {
-            encoding_key: EncodingKey::from_ed_der(&keypair.secret.to_bytes()),
-            decoding_key: DecodingKey::from_ed_der(&keypair.public.to_bytes()),
-            signing_keypair: keypair,
-        })
+    pub fn new(keys: Arc<KeyStore>) -> Self {
+        Self { keys }
     }
 
-    pub fn generate_access_token(&self, user_id: Uuid) -> Result<String, AuthError> {
+    pub fn generate_access_token(&self, user_id: Uuid, session_id: Uuid) -> Result<String, AuthError> {
         let now = OffsetDateTime::now_utc();
         let claims = Claims {
             sub: user_id.to_string(),
@@ -51,41 +68,118 @@ impl JwtService {
             iat: now.unix_timestamp(),
             jti: Uuid::new_v4().to_string(),
             token_type: "access".to_string(),
+            fam: None,
+            sid: Some(session_id.to_string()),
         };
 
-        encode(
-            &Header::new(jsonwebtoken::Algorithm::EdDSA),
-            &claims,
-            &self.encoding_key,
-        )
-        .map_err(|_| AuthError::InternalError)
+        self.sign(&claims)
     }
 
-    pub fn generate_refresh_token(&self, user_id: Uuid) -> Result<String, AuthError> {
+    /// Issues the next refresh token in `family`, returning it with its claims.
+    pub fn generate_refresh_token(&self, user_id: Uuid, family: Uuid) -> Result<(String, RefreshClaims), AuthError> {
         let now = OffsetDateTime::now_utc();
+        let expires_at = now + Duration::seconds(REFRESH_TOKEN_DURATION);
         let claims = Claims {
             sub: user_id.to_string(),
-            exp: (now + Duration::seconds(REFRESH_TOKEN_DURATION)).unix_timestamp(),
+            exp: expires_at.unix_timestamp(),
             iat: now.unix_timestamp(),
             jti: Uuid::new_v4().to_string(),
             token_type: "refresh".to_string(),
+            fam: Some(family.to_string()),
+            sid: None,
         };
 
-        encode(
-            &Header::new(jsonwebtoken::Algorithm::EdDSA),
-            &claims,
-            &self.encoding_key,
-        )
-        .map_err(|_| AuthError::InternalError)
+        let token = self.sign(&claims)?;
+        Ok((token, RefreshClaims {
+            user_id,
+            jti: claims.jti,
+            family,
+            expires_at,
+        }))
+    }
+
+    /// Issues the token a client exchanges, with a TOTP code, for the pair of a login that
+    /// needs one. Its type makes it good for nothing else.
+    pub fn generate_totp_challenge_token(&self, user_id: Uuid, challenge_id: Uuid) -> Result<(String, OffsetDateTime), AuthError> {
+        let now = OffsetDateTime::now_utc();
+        let expires_at = now + Duration::seconds(TOTP_CHALLENGE_DURATION);
+        let claims = Claims {
+            sub: user_id.to_string(),
+            exp: expires_at.unix_timestamp(),
+            iat: now.unix_timestamp(),
+            jti: challenge_id.to_string(),
+            token_type: "totp_challenge".to_string(),
+            fam: None,
+            sid: None,
+        };
+
+        Ok((self.sign(&claims)?, expires_at))
+    }
+
+    // Signed with the current key and stamped with its kid so verifiers pick the right one
+    fn sign(&self, claims: &Claims) -> Result<String, AuthError> {
+        let key = self.keys.signing_key();
+        let mut header = Header::new(jsonwebtoken::Algorithm::EdDSA);
+        header.kid = Some(key.kid.clone());
+
+        encode(&header, claims, &key.encoding_key).map_err(|_| AuthError::InternalError)
     }
 
     pub fn verify_token(&self, token: &str, expected_type: &str) -> Result<Uuid, AuthError> {
+        let claims = self.decode_claims(token, expected_type)?;
+        Uuid::parse_str(&claims.sub).map_err(|_| AuthError::InvalidToken)
+    }
+
+    pub fn verify_access_token(&self, token: &str) -> Result<AccessClaims, AuthError> {
+        let claims = self.decode_claims(token, "access")?;
+
+        Ok(AccessClaims {
+            user_id: Uuid::parse_str(&claims.sub).map_err(|_| AuthError::InvalidToken)?,
+            session_id: claims
+                .sid
+                .map(|sid| Uuid::parse_str(&sid).map_err(|_| AuthError::InvalidToken))
+                .transpose()?,
+        })
+    }
+
+    pub fn verify_totp_challenge_token(&self, token: &str) -> Result<TotpChallengeClaims, AuthError> {
+        let claims = self.decode_claims(token, "totp_challenge")?;
+
+        Ok(TotpChallengeClaims {
+            user_id: Uuid::parse_str(&claims.sub).map_err(|_| AuthError::InvalidToken)?,
+            challenge_id: Uuid::parse_str(&claims.jti).map_err(|_| AuthError::InvalidToken)?,
+        })
+    }
+
+    /// Checks a refresh token's signature and expiry; whether it is still the current token
+    /// of its family is for the caller to check.
+    pub fn verify_refresh_token(&self, token: &str) -> Result<RefreshClaims, AuthError> {
+        let claims = self.decode_claims(token, "refresh")?;
+        // Refresh tokens issued before families were tracked carry none and must log in again
+        let family = claims.fam.as_deref().ok_or(AuthError::InvalidToken)?;
+
+        Ok(RefreshClaims {
+            user_id: Uuid::parse_str(&claims.sub).map_err(|_| AuthError::InvalidToken)?,
+            family: Uuid::parse_str(family).map_err(|_| AuthError::InvalidToken)?,
+            expires_at: OffsetDateTime::from_unix_timestamp(claims.exp).map_err(|_| AuthError::InvalidToken)?,
+            jti: claims.jti,
+        })
+    }
+
+    fn decode_claims(&self, token: &str, expected_type: &str) -> Result<Claims, AuthError> {
         let mut validation = Validation::new(jsonwebtoken::Algorithm::EdDSA);
         validation.set_required_spec_claims(&["sub", "exp", "iat", "jti", "type"]);
 
+        // Tokens name their key; one that is unknown or past its grace period fails
+        let kid = decode_header(token)
+            .map_err(|_| AuthError::InvalidToken)?
+            .kid
+            .ok_or(AuthError::InvalidToken)?;
+        let key = self.keys.verifying_key(&kid).ok_or(AuthError::InvalidToken)?;
+
         let token_data: TokenData<Claims> = decode(
             token,
-            &self.decoding_key,
+            &key.decoding_key,
             &validation,
         ).map_err(|_| AuthError::InvalidToken)?;
 
@@ -93,23 +187,28 @@ impl JwtService {
             return Err(AuthError::InvalidToken);
         }
 
-        let user_id = Uuid::parse_str(&token_data.claims.sub)
-            .map_err(|_| AuthError::InvalidToken)?;
-
-        Ok(user_id)
+        Ok(token_data.claims)
     }
 
-    // Sign arbitrary data using the service's keypair
+    // Sign arbitrary data using the current signing key
     pub fn sign_data(&self, data: &[u8]) -> Vec<u8> {
-        self.signing_keypair.sign(data).to_bytes().to_vec()
+        self.keys.signing_key().signing_key.sign(data).to_bytes().to_vec()
     }
 
-    // Verify signed data
+    // Verify signed data against every key that still verifies
     pub fn verify_signature(&self, data: &[u8], signature: &[u8]) -> bool {
-        if let Ok(sig) = ed25519_dalek::Signature::from_bytes(signature) {
-            self.signing_keypair.public.verify_strict(data, &sig).is_ok()
+        if let Ok(sig) = ed25519_dalek::Signature::from_slice(signature) {
+            self.keys
+                .verifying_keys()
+                .iter()
+                .any(|key| key.signing_key.verifying_key().verify_strict(data, &sig).is_ok())
         } else {
             false
         }
     }
+
+    /// Public keys for verifying tokens offline.
+    pub fn jwks(&self) -> JwksResponse {
+        self.keys.jwks()
+    }
 }
\ No newline at end of file
@@ -0,0 +1,495 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::pkcs8::spki::der::pem::LineEnding;
+use ed25519_dalek::pkcs8::{DecodePrivateKey, EncodePrivateKey};
+use ed25519_dalek::SigningKey;
+use jsonwebtoken::{DecodingKey, EncodingKey};
+use rand::rngs::OsRng;
+use time::OffsetDateTime;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::api::models::{Jwk, JwksResponse};
+use crate::error::AuthError;
+use crate::service::jwt::REFRESH_TOKEN_DURATION;
+
+// How often the key directory is re-read, picking up keys added by other replicas
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct KeyStoreConfig {
+    /// A PEM file, or a directory of `<kid>.pem` files, holding Ed25519 private keys (PKCS#8)
+    pub key_path: PathBuf,
+    /// Age at which a new signing key is generated; 0 leaves rotation to whoever manages the keys
+    pub rotation_interval: Duration,
+    /// How long a new key is published before tokens are signed with it, so verifiers that
+    /// cache the JWKS know it by the time they see it
+    pub publish_delay: Duration,
+    /// How long a retired key still verifies tokens; at least the refresh token lifetime
+    pub grace_period: Duration,
+}
+
+impl KeyStoreConfig {
+    pub fn from_env() -> Self {
+        let secs = |name: &str, default: u64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(Duration::from_secs(default))
+        };
+
+        Self {
+            key_path: std::env::var("JWT_KEY_PATH")
+                .unwrap_or_else(|_| "/etc/selfie/jwt-keys".to_string())
+                .into(),
+            rotation_interval: secs("JWT_KEY_ROTATION_SECS", 30 * 24 * 3600),
+            publish_delay: secs("JWT_KEY_PUBLISH_DELAY_SECS", 600),
+            grace_period: secs("JWT_KEY_GRACE_SECS", REFRESH_TOKEN_DURATION as u64),
+        }
+    }
+}
+
+/// One Ed25519 key, identified in token headers by its `kid`.
+pub struct JwtKey {
+    pub kid: String,
+    pub signing_key: SigningKey,
+    pub encoding_key: EncodingKey,
+    pub decoding_key: DecodingKey,
+    // When the key was generated; its place in the rotation follows from it
+    created: SystemTime,
+}
+
+/// The signing keys in `key_path`. The newest key that has been published for
+/// `publish_delay` signs; older keys are retired and keep verifying tokens for
+/// `grace_period` after their successor took over, then drop out of the JWKS (and, when this
+/// service rotates the keys, off the disk).
+pub struct KeyStore {
+    config: KeyStoreConfig,
+    keys: RwLock<KeySet>,
+}
+
+struct KeySet {
+    signing: Arc<JwtKey>,
+    // Everything that verifies, the signing key and keys waiting to sign included
+    verifying: HashMap<String, Arc<JwtKey>>,
+    // Retired keys past their grace period
+    expired: Vec<String>,
+}
+
+impl KeyStore {
+    pub fn load(config: KeyStoreConfig) -> Result<Self, AuthError> {
+        // A service that rotates its own keys also creates the first one
+        let rotating = config.key_path.is_dir() && !config.rotation_interval.is_zero();
+        if rotating && read_keys(&config.key_path)?.is_empty() {
+            let kid = generate_key(&config.key_path)?;
+            info!("Generated JWT signing key {}", kid);
+        }
+
+        let keys = KeySet::load(&config)?;
+        info!(
+            "Signing tokens with key {} ({} verification keys)",
+            keys.signing.kid,
+            keys.verifying.len()
+        );
+
+        Ok(Self {
+            config,
+            keys: RwLock::new(keys),
+        })
+    }
+
+    /// Rotates the keys on schedule and re-reads the directory so every replica follows the
+    /// same rotation. Replicas that rotate at the same moment each add a key; the newer one
+    /// wins everywhere once published and the other retires with the rest.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.refresh() {
+                error!("Failed to refresh JWT signing keys: {}", err);
+            }
+        }
+    }
+
+    fn refresh(&self) -> Result<(), AuthError> {
+        let rotating = self.config.key_path.is_dir() && !self.config.rotation_interval.is_zero();
+        if rotating {
+            let newest = read_keys(&self.config.key_path)?.into_iter().map(|key| key.created).max();
+            if newest.map_or(true, |created| age(created) >= self.config.rotation_interval) {
+                let kid = generate_key(&self.config.key_path)?;
+                info!("Generated JWT signing key {}, signing with it in {:?}", kid, self.config.publish_delay);
+            }
+        }
+
+        let keys = KeySet::load(&self.config)?;
+        if rotating {
+            self.remove_expired(&keys)?;
+        }
+
+        let mut current = self.keys.write().expect("JWT key set poisoned");
+        if current.signing.kid != keys.signing.kid {
+            info!("Signing tokens with key {}, retiring {}", keys.signing.kid, current.signing.kid);
+        }
+        *current = keys;
+        Ok(())
+    }
+
+    // Keys past their grace period can no longer verify anything. Another replica may have
+    // removed them first.
+    fn remove_expired(&self, keys: &KeySet) -> Result<(), AuthError> {
+        for kid in &keys.expired {
+            let path = self.config.key_path.join(format!("{}.pem", kid));
+            match fs::remove_file(&path) {
+                Ok(()) => info!("Removed expired JWT signing key {}", kid),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(key_error(&path, err)),
+            }
+        }
+        Ok(())
+    }
+
+    /// The key new tokens are signed with.
+    pub fn signing_key(&self) -> Arc<JwtKey> {
+        self.keys.read().expect("JWT key set poisoned").signing.clone()
+    }
+
+    /// The key a token names in its `kid` header, if it still verifies.
+    pub fn verifying_key(&self, kid: &str) -> Option<Arc<JwtKey>> {
+        self.keys.read().expect("JWT key set poisoned").verifying.get(kid).cloned()
+    }
+
+    pub fn verifying_keys(&self) -> Vec<Arc<JwtKey>> {
+        self.keys.read().expect("JWT key set poisoned").verifying.values().cloned().collect()
+    }
+
+    /// Public halves of every key that verifies, as served at `/.well-known/jwks.json`.
+    pub fn jwks(&self) -> JwksResponse {
+        let keys = self.keys.read().expect("JWT key set poisoned");
+        let mut jwks: Vec<Jwk> = keys.verifying.values().map(|key| key.jwk()).collect();
+        jwks.sort_by(|a, b| a.kid.cmp(&b.kid));
+        JwksResponse { keys: jwks }
+    }
+}
+
+impl KeySet {
+    fn load(config: &KeyStoreConfig) -> Result<Self, AuthError> {
+        let mut keys = if config.key_path.is_dir() {
+            read_keys(&config.key_path)?
+        } else {
+            vec![read_key(&config.key_path)?]
+        };
+        if keys.is_empty() {
+            return Err(AuthError::SigningKeyError(format!(
+                "no signing keys in {}",
+                config.key_path.display()
+            )));
+        }
+        keys.sort_by_key(|key| key.created);
+
+        // Newest published key, or the newest of all when none has been out long enough
+        let signing = keys
+            .iter()
+            .rposition(|key| age(key.created) >= config.publish_delay)
+            .unwrap_or(keys.len() - 1);
+
+        let keys: Vec<Arc<JwtKey>> = keys.into_iter().map(Arc::new).collect();
+        let mut verifying = HashMap::new();
+        let mut expired = Vec::new();
+        for (index, key) in keys.iter().enumerate() {
+            // A key retires when the one after it starts signing
+            let retired_at = keys
+                .get(index + 1)
+                .filter(|_| index < signing)
+                .map(|successor| successor.created + config.publish_delay);
+            if retired_at.map_or(false, |retired_at| age(retired_at) >= config.grace_period) {
+                expired.push(key.kid.clone());
+            } else {
+                verifying.insert(key.kid.clone(), key.clone());
+            }
+        }
+
+        Ok(Self {
+            signing: keys[signing].clone(),
+            verifying,
+            expired,
+        })
+    }
+}
+
+impl JwtKey {
+    fn jwk(&self) -> Jwk {
+        Jwk {
+            kty: "OKP".to_string(),
+            crv: "Ed25519".to_string(),
+            alg: "EdDSA".to_string(),
+            key_use: "sig".to_string(),
+            kid: self.kid.clone(),
+            x: URL_SAFE_NO_PAD.encode(self.signing_key.verifying_key().to_bytes()),
+        }
+    }
+}
+
+fn read_keys(dir: &Path) -> Result<Vec<JwtKey>, AuthError> {
+    let mut keys = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|err| key_error(dir, err))? {
+        let path = entry.map_err(|err| key_error(dir, err))?.path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some("pem") {
+            keys.push(read_key(&path)?);
+        }
+    }
+    Ok(keys)
+}
+
+fn read_key(path: &Path) -> Result<JwtKey, AuthError> {
+    let pem = fs::read_to_string(path).map_err(|err| key_error(path, err))?;
+    let kid = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default()
+        .to_string();
+    // Generated keys carry their creation time in the kid, which survives copies and restores
+    // that reset the mtime; keys named some other way fall back to the file's mtime
+    let created = match kid_created(&kid) {
+        Some(created) => created,
+        None => fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|err| key_error(path, err))?,
+    };
+
+    let signing_key = SigningKey::from_pkcs8_pem(&pem)
+        .map_err(|err| AuthError::SigningKeyError(format!("invalid Ed25519 key {}: {}", path.display(), err)))?;
+    let encoding_key = EncodingKey::from_ed_pem(pem.as_bytes())
+        .map_err(|err| AuthError::SigningKeyError(format!("invalid Ed25519 key {}: {}", path.display(), err)))?;
+    let decoding_key = DecodingKey::from_ed_der(signing_key.verifying_key().as_bytes());
+
+    Ok(JwtKey {
+        kid,
+        signing_key,
+        encoding_key,
+        decoding_key,
+        created,
+    })
+}
+
+// The leading unix timestamp of a `{unix_ts}-{8 hex}` kid as `generate_key` writes them
+fn kid_created(kid: &str) -> Option<SystemTime> {
+    let (timestamp, suffix) = kid.split_once('-')?;
+    if suffix.len() != 8 || !suffix.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    let secs: u64 = timestamp.parse().ok()?;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+// Written under a temporary name and renamed, so other replicas never read half a key
+fn generate_key(dir: &Path) -> Result<String, AuthError> {
+    let kid = format!(
+        "{}-{}",
+        OffsetDateTime::now_utc().unix_timestamp(),
+        &Uuid::new_v4().simple().to_string()[..8]
+    );
+    let pem = SigningKey::generate(&mut OsRng)
+        .to_pkcs8_pem(LineEnding::LF)
+        .map_err(|err| AuthError::SigningKeyError(format!("failed to encode new key: {}", err)))?;
+
+    let partial = dir.join(format!(".{}.pem.partial", kid));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&partial)
+        .map_err(|err| key_error(&partial, err))?;
+    file.write_all(pem.as_bytes())
+        .and_then(|_| file.sync_all())
+        .map_err(|err| key_error(&partial, err))?;
+
+    let path = dir.join(format!("{}.pem", kid));
+    fs::rename(&partial, &path).map_err(|err| key_error(&path, err))?;
+    Ok(kid)
+}
+
+fn age(created: SystemTime) -> Duration {
+    created.elapsed().unwrap_or_default()
+}
+
+fn key_error(path: &Path, err: std::io::Error) -> AuthError {
+    AuthError::SigningKeyError(format!("{}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60;
+    const DAY: u64 = 24 * 3600;
+
+    // A scratch key directory, removed when dropped
+    struct KeyDir(PathBuf);
+
+    impl KeyDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("jwt-keys-{}", Uuid::new_v4().simple()));
+            fs::create_dir(&dir).unwrap();
+            Self(dir)
+        }
+
+        // Writes a key named as `generate_key` would have `age_secs` ago
+        fn add(&self, age_secs: u64) -> String {
+            let created = OffsetDateTime::now_utc().unix_timestamp() - age_secs as i64;
+            let kid = format!("{}-{}", created, &Uuid::new_v4().simple().to_string()[..8]);
+            self.write(&kid);
+            kid
+        }
+
+        fn write(&self, kid: &str) {
+            let pem = SigningKey::generate(&mut OsRng).to_pkcs8_pem(LineEnding::LF).unwrap();
+            fs::write(self.0.join(format!("{}.pem", kid)), pem.as_bytes()).unwrap();
+        }
+
+        fn config(&self, rotation_days: u64) -> KeyStoreConfig {
+            KeyStoreConfig {
+                key_path: self.0.clone(),
+                rotation_interval: Duration::from_secs(rotation_days * DAY),
+                publish_delay: Duration::from_secs(10 * MINUTE),
+                grace_period: Duration::from_secs(7 * DAY),
+            }
+        }
+    }
+
+    impl Drop for KeyDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn verifying(keys: &KeySet) -> Vec<&str> {
+        let mut kids: Vec<&str> = keys.verifying.keys().map(String::as_str).collect();
+        kids.sort();
+        kids
+    }
+
+    #[test]
+    fn new_key_verifies_before_it_signs() {
+        let dir = KeyDir::new();
+        let old = dir.add(20 * DAY);
+        let new = dir.add(5 * MINUTE);
+
+        let keys = KeySet::load(&dir.config(0)).unwrap();
+
+        assert_eq!(keys.signing.kid, old);
+        assert_eq!(verifying(&keys), vec![old.as_str(), new.as_str()]);
+        assert!(keys.expired.is_empty());
+    }
+
+    #[test]
+    fn published_key_signs_and_its_predecessor_keeps_verifying() {
+        let dir = KeyDir::new();
+        let old = dir.add(40 * DAY);
+        let new = dir.add(20 * MINUTE);
+
+        let keys = KeySet::load(&dir.config(0)).unwrap();
+
+        assert_eq!(keys.signing.kid, new);
+        assert_eq!(verifying(&keys), vec![old.as_str(), new.as_str()]);
+        assert!(keys.expired.is_empty());
+    }
+
+    #[test]
+    fn retired_keys_expire_after_the_grace_period() {
+        let dir = KeyDir::new();
+        let oldest = dir.add(40 * DAY);
+        let previous = dir.add(20 * DAY);
+        let current = dir.add(DAY);
+
+        let keys = KeySet::load(&dir.config(0)).unwrap();
+
+        // `previous` retired a day ago, `oldest` twenty days ago
+        assert_eq!(keys.signing.kid, current);
+        assert_eq!(verifying(&keys), vec![previous.as_str(), current.as_str()]);
+        assert_eq!(keys.expired, vec![oldest]);
+    }
+
+    #[test]
+    fn newest_key_signs_when_none_is_published_yet() {
+        let dir = KeyDir::new();
+        dir.add(2 * MINUTE);
+        let newest = dir.add(MINUTE);
+
+        let keys = KeySet::load(&dir.config(0)).unwrap();
+
+        assert_eq!(keys.signing.kid, newest);
+        assert_eq!(keys.verifying.len(), 2);
+    }
+
+    #[test]
+    fn created_falls_back_to_the_file_time_for_other_names() {
+        let dir = KeyDir::new();
+        let generated = dir.add(40 * DAY);
+        dir.write("primary");
+
+        let keys = KeySet::load(&dir.config(0)).unwrap();
+
+        // Just written, so `primary` is the newest but not yet published
+        assert_eq!(keys.signing.kid, generated);
+        assert!(age(keys.verifying["primary"].created) < Duration::from_secs(MINUTE));
+        assert_eq!(kid_created("primary"), None);
+    }
+
+    #[test]
+    fn empty_directory_is_an_error_without_rotation() {
+        let dir = KeyDir::new();
+
+        assert!(KeySet::load(&dir.config(0)).is_err());
+        assert!(KeyStore::load(dir.config(0)).is_err());
+    }
+
+    #[test]
+    fn rotating_store_creates_its_first_key() {
+        let dir = KeyDir::new();
+
+        let store = KeyStore::load(dir.config(30)).unwrap();
+
+        assert_eq!(store.verifying_keys().len(), 1);
+        assert!(kid_created(&store.signing_key().kid).is_some());
+    }
+
+    #[test]
+    fn refresh_rotates_once_the_newest_key_is_due() {
+        let dir = KeyDir::new();
+        let old = dir.add(40 * DAY);
+        let store = KeyStore::load(dir.config(30)).unwrap();
+
+        store.refresh().unwrap();
+
+        // The new key waits out the publish delay before it signs
+        assert_eq!(store.signing_key().kid, old);
+        assert_eq!(store.verifying_keys().len(), 2);
+
+        // Not due again until the new key is 30 days old
+        store.refresh().unwrap();
+        assert_eq!(store.verifying_keys().len(), 2);
+    }
+
+    #[test]
+    fn refresh_removes_expired_keys_from_disk() {
+        let dir = KeyDir::new();
+        let oldest = dir.add(40 * DAY);
+        dir.add(20 * DAY);
+        dir.add(DAY);
+        let store = KeyStore::load(dir.config(30)).unwrap();
+
+        store.refresh().unwrap();
+
+        assert!(!dir.0.join(format!("{}.pem", oldest)).exists());
+        assert!(store.verifying_key(&oldest).is_none());
+        assert_eq!(store.verifying_keys().len(), 2);
+    }
+}
@@ -1,3 +1,4 @@
+use std::net::IpAddr;
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
 use uuid::Uuid;
@@ -6,9 +7,15 @@ use uuid::Uuid;
 pub struct User {
     pub id: Uuid,
     pub email: String,
+    // Lowercase, unique; empty for accounts created before usernames
+    #[serde(default)]
+    pub username: String,
     pub passphrase_hash: String,
     pub totp_secret: Option<String>,
     pub totp_enabled: bool,
+    // The login that passed the passphrase check and waits for a TOTP code
+    #[serde(default)]
+    pub totp_challenge: Option<TotpChallenge>,
     pub failed_login_attempts: u32,
     pub last_login: Option<OffsetDateTime>,
     pub email_verified: bool,
@@ -28,6 +35,85 @@ pub enum UserStatus {
     PendingVerification,
 }
 
+/// The chain of refresh tokens handed out since one login, which is also the user's session
+/// on that device. Only the newest token in the chain refreshes; presenting any earlier one
+/// means it was copied, and ends the family.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TokenFamily {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    // `jti` of the one token that may still be exchanged
+    pub current_jti: String,
+    // Name the client gave the device at login
+    #[serde(default)]
+    pub device_name: Option<String>,
+    // Updated on every refresh, so as fresh as `last_refreshed`
+    #[serde(default)]
+    pub client: ClientInfo,
+    pub created_at: OffsetDateTime,
+    pub last_refreshed: OffsetDateTime,
+    // When the current token expires; the family is dead after that
+    pub expires_at: OffsetDateTime,
+}
+
+/// A login waiting for its TOTP code, named by the challenge token the client holds. Only the
+/// newest login's challenge is kept.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TotpChallenge {
+    // `jti` of the challenge token
+    pub id: Uuid,
+    pub attempts: u32,
+    pub device_name: Option<String>,
+    pub expires_at: OffsetDateTime,
+}
+
+/// Where a request came from, as far as the service can tell behind the gateway.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// Proxies in front of the service that append to X-Forwarded-For, from `TRUSTED_PROXY_HOPS`.
+/// The client address is the entry this many from the right; 0 ignores the header and takes
+/// the peer address for the client's.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrustedProxies {
+    pub hops: usize,
+}
+
+impl TrustedProxies {
+    pub fn from_env() -> Self {
+        Self {
+            hops: std::env::var("TRUSTED_PROXY_HOPS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0),
+        }
+    }
+
+    /// Where a request from `peer` came from, given its User-Agent and X-Forwarded-For values.
+    pub fn client_info(&self, user_agent: Option<&str>, forwarded_for: &[&str], peer: Option<IpAddr>) -> ClientInfo {
+        // Anything left of the outermost trusted proxy's entry came from the client
+        let forwarded = self.hops.checked_sub(1).and_then(|hop| {
+            forwarded_for
+                .iter()
+                .flat_map(|value| value.split(','))
+                .rev()
+                .nth(hop)
+                .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+        });
+
+        ClientInfo {
+            user_agent: user_agent
+                .map(str::trim)
+                .filter(|agent| !agent.is_empty())
+                .map(|agent| agent.chars().take(256).collect()),
+            ip: forwarded.or(peer).map(|ip| ip.to_string()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct AuthToken {
     pub user_id: Uuid,
@@ -50,14 +136,16 @@ pub struct TotpSetup {
 }
 
 impl User {
-    pub fn new(email: String, passphrase_hash: String) -> Self {
+    pub fn new(email: String, username: String, passphrase_hash: String) -> Self {
         let now = OffsetDateTime::now_utc();
         Self {
             id: Uuid::new_v4(),
             email,
+            username,
             passphrase_hash,
             totp_secret: None,
             totp_enabled: false,
+            totp_challenge: None,
             failed_login_attempts: 0,
             last_login: None,
             email_verified: false,
@@ -81,4 +169,37 @@ impl User {
         }
         false
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "10.0.0.7";
+
+    fn client_ip(hops: usize, forwarded_for: &[&str]) -> Option<String> {
+        TrustedProxies { hops }
+            .client_info(None, forwarded_for, Some(PEER.parse().unwrap()))
+            .ip
+    }
+
+    #[test]
+    fn ignores_forwarded_for_without_trusted_proxies() {
+        assert_eq!(client_ip(0, &["203.0.113.9"]).as_deref(), Some(PEER));
+    }
+
+    #[test]
+    fn takes_the_entry_appended_by_the_outermost_trusted_proxy() {
+        // The client made up the first entry; the two proxies appended the others
+        let forwarded_for = ["1.2.3.4, 203.0.113.9", "10.0.0.2"];
+
+        assert_eq!(client_ip(1, &forwarded_for).as_deref(), Some("10.0.0.2"));
+        assert_eq!(client_ip(2, &forwarded_for).as_deref(), Some("203.0.113.9"));
+    }
+
+    #[test]
+    fn falls_back_to_the_peer_when_the_header_is_short_or_malformed() {
+        assert_eq!(client_ip(2, &["203.0.113.9"]).as_deref(), Some(PEER));
+        assert_eq!(client_ip(1, &["not-an-ip"]).as_deref(), Some(PEER));
+        assert_eq!(client_ip(1, &[]).as_deref(), Some(PEER));
+    }
+}